@@ -135,7 +135,7 @@ ASSET_RESULT=$(dfx canister call $BACKEND add_asset "(
       amount = 400_000_000_000 
     } 
   },
-  vec {})")
+  vec { record { heir_principal = principal \"$MINTER\"; percentage = 100 } })")
 echo -e "${GREEN}[OK] Asset added: $ASSET_RESULT${NC}"
 echo "Add ICRC-2 Token Asset to Vault: PASS"
 
@@ -0,0 +1,369 @@
+#![allow(non_snake_case)]
+
+// Archive for audit events the backend no longer keeps in its own memory, in the spirit of
+// the ICRC-3 ledger archives. Events are stored as the backend's candid-encoded
+// `AuditEvent`, so this canister never has to follow changes to that type.
+
+use std::{borrow::Cow, cell::RefCell, ops::Bound};
+
+use candid::{CandidType, Principal};
+use ic_cdk_macros::{init, query, update};
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+    storable::Bound as StorableBound,
+    DefaultMemoryImpl, StableBTreeMap, StableCell, Storable,
+};
+use serde::{Deserialize, Serialize};
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+const DEFAULT_PAGE_SIZE: u32 = 100;
+const MAX_PAGE_SIZE: u32 = 500;
+// A page can come back short when the time window skips a lot of events
+const MAX_SCAN: usize = 5_000;
+
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct ArchiveInit {
+    pub backend: Principal,
+}
+
+#[derive(Clone, CandidType, Serialize, Deserialize, PartialEq, Debug)]
+pub struct ArchivedEvent {
+    pub id: u64,
+    pub owner: Principal,
+    pub timestamp: u64,
+    pub event: Vec<u8>,
+}
+
+impl Storable for ArchivedEvent {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode ArchivedEvent"))
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        candid::encode_one(&self).expect("Failed to encode ArchivedEvent")
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes)
+            .expect("Failed to decode ArchivedEvent - storage corruption detected")
+    }
+
+    const BOUND: StorableBound = StorableBound::Unbounded;
+}
+
+// Index entry so one owner's events can be read without scanning the whole archive
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct OwnerEventKey {
+    owner: Principal,
+    id: u64,
+}
+
+impl Storable for OwnerEventKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned((*self).into_bytes())
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        let owner = self.owner.as_slice();
+        let mut bytes = Vec::with_capacity(1 + owner.len() + 8);
+        bytes.push(owner.len() as u8);
+        bytes.extend_from_slice(owner);
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let len = bytes[0] as usize;
+        let mut arr = [0u8; 8];
+        arr.copy_from_slice(&bytes[1 + len..1 + len + 8]);
+        OwnerEventKey {
+            owner: Principal::from_slice(&bytes[1..1 + len]),
+            id: u64::from_be_bytes(arr),
+        }
+    }
+
+    const BOUND: StorableBound = StorableBound::Bounded {
+        max_size: 38,
+        is_fixed_size: false,
+    };
+}
+
+#[derive(Clone, CandidType, Serialize, Deserialize, PartialEq, Debug)]
+pub struct GetArchivedEventsArgs {
+    // None reads every owner, only allowed for the backend and controllers
+    pub owner: Option<Principal>,
+    // Exclusive: the page starts after this id (before it when `newest_first`)
+    pub cursor: Option<u64>,
+    pub limit: Option<u32>,
+    pub newest_first: Option<bool>,
+    pub from_time: Option<u64>,
+    pub to_time: Option<u64>,
+}
+
+#[derive(Clone, CandidType, Serialize, Deserialize, PartialEq, Debug)]
+pub struct ArchivedEventsPage {
+    pub events: Vec<ArchivedEvent>,
+    pub next_cursor: Option<u64>,
+}
+
+#[derive(Clone, CandidType, Serialize, Deserialize, PartialEq, Debug)]
+pub enum ArchiveError {
+    Unauthorized,
+}
+
+// Events filed under `previous` belong to `owner` since the backend handed their stream
+// over. None withdraws an earlier alias.
+#[derive(Clone, CandidType, Serialize, Deserialize, PartialEq, Debug)]
+pub struct OwnerAlias {
+    pub previous: Principal,
+    pub owner: Option<Principal>,
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+    RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    static BACKEND: RefCell<StableCell<Principal, Memory>> =
+    RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))),
+            Principal::anonymous(),
+        )
+    );
+
+    static EVENTS: RefCell<StableBTreeMap<u64, ArchivedEvent, Memory>> =
+    RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))))
+    );
+
+    static EVENTS_BY_OWNER: RefCell<StableBTreeMap<OwnerEventKey, (), Memory>> =
+    RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))))
+    );
+
+    static OWNER_ALIASES: RefCell<StableBTreeMap<Principal, Principal, Memory>> =
+    RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))))
+    );
+}
+
+fn backend() -> Principal {
+    BACKEND.with(|b| *b.borrow().get())
+}
+
+fn is_privileged(caller: &Principal) -> bool {
+    *caller == backend() || ic_cdk::api::is_controller(caller)
+}
+
+// Whether `owner`'s events are `caller`'s: the stream ends up with whoever took it over
+// last, and a previous owner loses access along with the vault. The backend never sends a
+// cycle, the bound only keeps a bad alias from looping.
+fn owned_by(owner: &Principal, caller: &Principal) -> bool {
+    let mut current = *owner;
+    for _ in 0..=OWNER_ALIASES.with(|aliases| aliases.borrow().len()) {
+        match OWNER_ALIASES.with(|aliases| aliases.borrow().get(&current)) {
+            Some(next) => current = next,
+            None => return current == *caller,
+        }
+    }
+    false
+}
+
+fn apply_aliases(aliases: Vec<OwnerAlias>) {
+    OWNER_ALIASES.with(|store| {
+        let mut store = store.borrow_mut();
+        for alias in aliases {
+            match alias.owner {
+                Some(owner) => store.insert(alias.previous, owner),
+                None => store.remove(&alias.previous),
+            };
+        }
+    });
+}
+
+#[init]
+fn init(args: ArchiveInit) {
+    BACKEND.with(|b| b.borrow_mut().set(args.backend));
+}
+
+// Re-appending an id overwrites it, so the backend can safely retry a batch whose reply
+// it never saw
+#[update]
+fn append_events(events: Vec<ArchivedEvent>) -> Result<u64, ArchiveError> {
+    if ic_cdk::api::msg_caller() != backend() {
+        return Err(ArchiveError::Unauthorized);
+    }
+
+    EVENTS.with(|store| {
+        let mut store = store.borrow_mut();
+        EVENTS_BY_OWNER.with(|index| {
+            let mut index = index.borrow_mut();
+            for event in events {
+                index.insert(
+                    OwnerEventKey {
+                        owner: event.owner,
+                        id: event.id,
+                    },
+                    (),
+                );
+                store.insert(event.id, event);
+            }
+        });
+        Ok(store.len())
+    })
+}
+
+// The backend sends every handoff in order, each exactly once
+#[update]
+fn alias_owners(aliases: Vec<OwnerAlias>) -> Result<(), ArchiveError> {
+    if ic_cdk::api::msg_caller() != backend() {
+        return Err(ArchiveError::Unauthorized);
+    }
+    apply_aliases(aliases);
+    Ok(())
+}
+
+// Owners can only read their own events, including those of owners whose stream they took
+// over
+#[query]
+fn get_events(args: GetArchivedEventsArgs) -> Result<ArchivedEventsPage, ArchiveError> {
+    let caller = ic_cdk::api::msg_caller();
+
+    let owner = match args.owner {
+        Some(owner) if owned_by(&owner, &caller) || is_privileged(&caller) => Some(owner),
+        None if is_privileged(&caller) => None,
+        None => Some(caller),
+        Some(_) => return Err(ArchiveError::Unauthorized),
+    };
+
+    let limit = args
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE) as usize;
+    let newest_first = args.newest_first.unwrap_or(false);
+
+    let start = match (args.cursor, newest_first) {
+        (Some(id), false) => Bound::Excluded(id),
+        _ => Bound::Included(0),
+    };
+    let end = match (args.cursor, newest_first) {
+        (Some(id), true) => Bound::Excluded(id),
+        _ => Bound::Included(u64::MAX),
+    };
+
+    let mut events = Vec::new();
+    let mut scanned = 0;
+    let mut last_seen = None;
+    let mut exhausted = true;
+    let mut visit = |id: u64| -> bool {
+        if events.len() == limit || scanned == MAX_SCAN {
+            exhausted = false;
+            return false;
+        }
+        scanned += 1;
+        last_seen = Some(id);
+
+        if let Some(event) = EVENTS.with(|store| store.borrow().get(&id)) {
+            let in_window = !(args.from_time.is_some_and(|t| event.timestamp < t)
+                || args.to_time.is_some_and(|t| event.timestamp > t));
+            if in_window {
+                events.push(event);
+            }
+        }
+        true
+    };
+
+    match owner {
+        Some(owner) => EVENTS_BY_OWNER.with(|index| {
+            let key = |b: Bound<u64>| b.map(|id| OwnerEventKey { owner, id });
+            let index = index.borrow();
+            let mut keys = index.keys_range((key(start), key(end))).map(|k| k.id);
+            while let Some(id) = if newest_first {
+                keys.next_back()
+            } else {
+                keys.next()
+            } {
+                if !visit(id) {
+                    break;
+                }
+            }
+        }),
+        None => EVENTS.with(|store| {
+            let store = store.borrow();
+            let mut keys = store.keys_range((start, end));
+            while let Some(id) = if newest_first {
+                keys.next_back()
+            } else {
+                keys.next()
+            } {
+                if !visit(id) {
+                    break;
+                }
+            }
+        }),
+    }
+
+    let next_cursor = if exhausted { None } else { last_seen };
+    Ok(ArchivedEventsPage {
+        events,
+        next_cursor,
+    })
+}
+
+#[query]
+fn event_count() -> u64 {
+    EVENTS.with(|store| store.borrow().len())
+}
+
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(n: u8) -> Principal {
+        Principal::from_slice(&[n; 29])
+    }
+
+    fn alias(previous: u8, owner: Option<u8>) -> OwnerAlias {
+        OwnerAlias {
+            previous: principal(previous),
+            owner: owner.map(principal),
+        }
+    }
+
+    #[test]
+    fn owners_read_only_their_own_events_without_aliases() {
+        assert!(owned_by(&principal(1), &principal(1)));
+        assert!(!owned_by(&principal(1), &principal(2)));
+    }
+
+    #[test]
+    fn aliases_follow_every_recovery() {
+        apply_aliases(vec![alias(1, Some(2)), alias(2, Some(3))]);
+        assert!(owned_by(&principal(1), &principal(3)));
+        assert!(owned_by(&principal(2), &principal(3)));
+        assert!(owned_by(&principal(3), &principal(3)));
+        // Owners before the current one lost their vault, and their read access with it
+        assert!(!owned_by(&principal(1), &principal(1)));
+        assert!(!owned_by(&principal(1), &principal(2)));
+        assert!(!owned_by(&principal(3), &principal(1)));
+    }
+
+    #[test]
+    fn withdrawn_aliases_give_events_back() {
+        apply_aliases(vec![alias(1, Some(2)), alias(1, None), alias(2, Some(1))]);
+        assert!(owned_by(&principal(1), &principal(1)));
+        assert!(owned_by(&principal(2), &principal(1)));
+        assert!(!owned_by(&principal(1), &principal(2)));
+    }
+
+    #[test]
+    fn a_cycle_does_not_loop() {
+        apply_aliases(vec![alias(1, Some(2)), alias(2, Some(1))]);
+        assert!(!owned_by(&principal(1), &principal(3)));
+    }
+}
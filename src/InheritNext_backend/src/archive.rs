@@ -0,0 +1,406 @@
+use std::{cell::Cell, time::Duration};
+
+use candid::Principal;
+use ic_cdk::{
+    call::Call,
+    management_canister::{
+        create_canister_with_extra_cycles, install_code, CanisterInstallMode, CanisterSettings,
+        CreateCanisterArgs, InstallCodeArgs,
+    },
+};
+
+use crate::{
+    helpers::{
+        now, ARCHIVE_BATCH_SIZE, ARCHIVE_CREATION_CYCLES, ARCHIVE_INTERVAL_SECS,
+        MAX_EVENTS_PER_ARCHIVE,
+    },
+    storage,
+    types::{
+        ArchiveError, ArchiveInfo, ArchiveInit, ArchivedEvent, ArchivedEventsCallback, AuditQuery,
+        GetArchivedEventsArgs, GetArchivedEventsFn,
+    },
+};
+
+thread_local! {
+    static SHIPPING: Cell<bool> = const { Cell::new(false) };
+}
+
+// Clears the flag however the shipping future ends, including a trap after an await
+struct ShippingGuard;
+
+impl ShippingGuard {
+    fn acquire() -> Result<Self, String> {
+        if SHIPPING.with(|s| s.replace(true)) {
+            return Err("Archiving already in progress".to_string());
+        }
+        Ok(ShippingGuard)
+    }
+}
+
+impl Drop for ShippingGuard {
+    fn drop(&mut self) {
+        SHIPPING.with(|s| s.set(false));
+    }
+}
+
+// Timers do not survive upgrades, so this has to be called from both init and post_upgrade
+pub fn start_archive_timer() {
+    ic_cdk_timers::set_timer_interval_serial(
+        Duration::from_secs(ARCHIVE_INTERVAL_SECS),
+        async || {
+            // Anything left in the queue is retried on the next tick
+            let _ = ship_archive_queue().await;
+        },
+    );
+}
+
+// Ships one batch of evicted events and drops them locally once the archive has them.
+// Returns how many were shipped.
+pub async fn ship_archive_queue() -> Result<u64, String> {
+    let _guard = ShippingGuard::acquire()?;
+
+    // An archive that can't be reached is sent its aliases again on the next tick
+    push_owner_aliases().await;
+
+    let batch = storage::list_archive_queue(ARCHIVE_BATCH_SIZE);
+    if batch.is_empty() {
+        return Ok(0);
+    }
+
+    let (index, archive) = ensure_archive(batch.len() as u64).await?;
+
+    let ids: Vec<_> = batch.iter().map(|(id, _)| *id).collect();
+    let events: Vec<ArchivedEvent> = batch
+        .into_iter()
+        .map(|(id, event)| ArchivedEvent {
+            id: id.0,
+            owner: storage::audit_stream(&event.blame),
+            timestamp: event.timestamp,
+            event: candid::encode_one(&event).expect("Failed to encode AuditEvent"),
+        })
+        .collect();
+
+    // The archive overwrites ids it already has, so a batch whose reply got lost is
+    // simply sent again
+    let appended: Result<u64, ArchiveError> =
+        Call::unbounded_wait(archive.canister_id, "append_events")
+            .with_arg(events)
+            .await
+            .map_err(|e| format!("append_events call failed: {:?}", e))?
+            .candid()
+            .map_err(|e| format!("Failed to decode append_events: {:?}", e))?;
+    appended.map_err(|e| format!("Archive rejected the batch: {:?}", e))?;
+
+    storage::complete_archive_batch(index, &ids);
+    Ok(ids.len() as u64)
+}
+
+// Sends every archive the stream handoffs it hasn't applied yet, so a new owner can read
+// events archived under a previous owner's principal. Runs under the shipping guard, so
+// no archive is sent the same aliases twice at once.
+async fn push_owner_aliases() {
+    let Some((latest, _)) = storage::latest_archive() else {
+        return;
+    };
+    for index in 0..=latest {
+        let Some(info) = storage::get_archive(index).filter(|info| info.installed) else {
+            continue;
+        };
+        let pushed = info.aliases_pushed.unwrap_or(0);
+        let aliases = storage::list_owner_aliases(pushed);
+        if aliases.is_empty() {
+            continue;
+        }
+
+        let count = aliases.len() as u64;
+        let applied = Call::unbounded_wait(info.canister_id, "alias_owners")
+            .with_arg(aliases)
+            .await
+            .ok()
+            .and_then(|response| response.candid::<Result<(), ArchiveError>>().ok());
+        if applied != Some(Ok(())) {
+            continue;
+        }
+        // Re-read, the archive may have been updated while we waited
+        if let Some(mut info) = storage::get_archive(index) {
+            info.aliases_pushed = Some(pushed + count);
+            storage::insert_archive(index, info);
+        }
+    }
+}
+
+// The archive the next batch goes to, spawning a new one when the current one is full
+async fn ensure_archive(batch_len: u64) -> Result<(u64, ArchiveInfo), String> {
+    match storage::latest_archive() {
+        Some((index, info)) if !info.installed => {
+            // Created earlier but the install never confirmed, start it over
+            let info = install_archive(index, info, CanisterInstallMode::Reinstall).await?;
+            Ok((index, info))
+        }
+        Some((index, info)) if info.events + batch_len <= MAX_EVENTS_PER_ARCHIVE => {
+            Ok((index, info))
+        }
+        latest => spawn_archive(latest.map_or(0, |(index, _)| index + 1)).await,
+    }
+}
+
+async fn spawn_archive(index: u64) -> Result<(u64, ArchiveInfo), String> {
+    let config = storage::get_archive_config();
+    if config.wasm.is_empty() {
+        return Err("No archive wasm has been uploaded".to_string());
+    }
+
+    let mut controllers = vec![ic_cdk::api::canister_self()];
+    controllers.extend(config.controllers);
+
+    let created = create_canister_with_extra_cycles(
+        &CreateCanisterArgs {
+            settings: Some(CanisterSettings {
+                controllers: Some(controllers),
+                ..Default::default()
+            }),
+        },
+        ARCHIVE_CREATION_CYCLES,
+    )
+    .await
+    .map_err(|e| format!("create_canister failed: {:?}", e))?;
+
+    // Recorded before installing so a failed install doesn't leak the canister
+    let info = ArchiveInfo {
+        canister_id: created.canister_id,
+        installed: false,
+        first_event_id: None,
+        last_event_id: None,
+        events: 0,
+        created_at: now(),
+        aliases_pushed: None,
+    };
+    storage::insert_archive(index, info.clone());
+
+    let info = install_archive(index, info, CanisterInstallMode::Install).await?;
+    Ok((index, info))
+}
+
+async fn install_archive(
+    index: u64,
+    mut info: ArchiveInfo,
+    mode: CanisterInstallMode,
+) -> Result<ArchiveInfo, String> {
+    let init = ArchiveInit {
+        backend: ic_cdk::api::canister_self(),
+    };
+
+    install_code(&InstallCodeArgs {
+        mode,
+        canister_id: info.canister_id,
+        wasm_module: storage::get_archive_config().wasm,
+        arg: candid::encode_one(init).expect("Failed to encode ArchiveInit"),
+    })
+    .await
+    .map_err(|e| format!("install_code failed: {:?}", e))?;
+
+    info.installed = true;
+    storage::insert_archive(index, info.clone());
+    Ok(info)
+}
+
+// ICRC-3 style: where to look for the part of a query no longer held locally. Archives
+// only hold events older than whatever the stream still has here, and each archive
+// applies the owner check itself, following the aliases `push_owner_aliases` sent it.
+pub fn archive_callbacks(
+    query: &AuditQuery,
+    blame: Option<&Principal>,
+) -> Vec<ArchivedEventsCallback> {
+    if blame.is_some_and(|owner| storage::get_audit_stream_stats(owner).archived_events == 0) {
+        return Vec::new();
+    }
+    let newest_first = query.newest_first.unwrap_or(false);
+
+    // Events shipped before a recovery are filed under the previous owner
+    let owners: Vec<Option<Principal>> = match blame {
+        None => vec![None],
+        Some(stream) => std::iter::once(*stream)
+            .chain(storage::previous_stream_owners(stream))
+            .map(Some)
+            .collect(),
+    };
+
+    storage::list_archives()
+        .into_iter()
+        .filter(|archive| archive.installed && archive.events > 0)
+        .filter(|archive| match query.cursor {
+            None => true,
+            Some(cursor) if newest_first => archive.first_event_id.is_some_and(|id| id < cursor),
+            Some(cursor) => archive.last_event_id.is_some_and(|id| id > cursor),
+        })
+        .flat_map(|archive| {
+            owners.iter().map(move |owner| ArchivedEventsCallback {
+                callback: GetArchivedEventsFn::new(archive.canister_id, "get_events".to_string()),
+                args: GetArchivedEventsArgs {
+                    owner: *owner,
+                    cursor: query.cursor,
+                    limit: query.limit,
+                    newest_first: query.newest_first,
+                    from_time: query.from_time,
+                    to_time: query.to_time,
+                },
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        helpers::{MAX_CRITICAL_EVENTS_PER_VAULT, MAX_HEARTBEATS_PER_VAULT},
+        types::{AuditEvent, DeadManSwitch, EventType, OwnerAlias, Vault, VaultStatus},
+    };
+
+    fn vault(owner: Principal) -> Vault {
+        Vault {
+            owner,
+            created_at: 0,
+            status: VaultStatus::Active,
+            dms: DeadManSwitch {
+                last_heartbeat: 0,
+                heartbeat_interval: 1,
+                grace_period: 1,
+                pending_since: None,
+            },
+            recovery_config: None,
+            next_asset_id: 0,
+            recovery_request: None,
+            heir_visibility: None,
+            remainder_policy: None,
+            escrow_subaccount: None,
+        }
+    }
+
+    fn query() -> AuditQuery {
+        AuditQuery {
+            cursor: None,
+            limit: None,
+            event_types: None,
+            from_time: None,
+            to_time: None,
+            newest_first: None,
+        }
+    }
+
+    #[test]
+    fn full_archive_queue_drops_only_heartbeats() {
+        let owner = Principal::from_slice(&[1; 29]);
+        let log = |event_type| {
+            storage::log_event(AuditEvent {
+                timestamp: 0,
+                event_type,
+                blame: owner,
+                prev_hash: None,
+            })
+        };
+
+        // Overflow both retention windows so some of each kind is queued
+        for asset_id in 0..MAX_CRITICAL_EVENTS_PER_VAULT + 3 {
+            log(EventType::PayoutClaimed {
+                asset_id,
+                heir: owner,
+            });
+        }
+        for _ in 0..MAX_HEARTBEATS_PER_VAULT + 5 {
+            log(EventType::Heartbeat);
+        }
+        assert_eq!(storage::archive_queue_len(), 8);
+        assert_eq!(storage::get_audit_stream_stats(&owner).queued_events, 8);
+
+        storage::trim_archive_queue(2);
+
+        // The queue stays over the cap rather than lose a critical event
+        let queued = storage::list_archive_queue(10);
+        assert_eq!(queued.len(), 3);
+        assert!(queued
+            .iter()
+            .all(|(_, event)| matches!(event.event_type, EventType::PayoutClaimed { .. })));
+        let stats = storage::get_audit_stream_stats(&owner);
+        assert_eq!(stats.queued_events, 3);
+        assert_eq!(stats.dropped_events, 5);
+        assert_eq!(storage::archive_queue_len(), 3);
+    }
+
+    #[test]
+    fn recovered_owner_gets_callbacks_for_the_previous_owners_archive() {
+        let old_owner = Principal::from_slice(&[1; 29]);
+        let new_owner = Principal::from_slice(&[2; 29]);
+        let archive = Principal::from_slice(&[9; 29]);
+
+        storage::insert_vault(&old_owner, vault(old_owner));
+        let id = storage::log_event(AuditEvent {
+            timestamp: 0,
+            event_type: EventType::Heartbeat,
+            blame: old_owner,
+            prev_hash: None,
+        });
+        storage::insert_archive(
+            0,
+            ArchiveInfo {
+                canister_id: archive,
+                installed: true,
+                first_event_id: None,
+                last_event_id: None,
+                events: 0,
+                created_at: 0,
+                aliases_pushed: None,
+            },
+        );
+        storage::complete_archive_batch(0, &[id]);
+
+        storage::transfer_vault_ownership(&old_owner, &new_owner).unwrap();
+
+        let owners: Vec<_> = archive_callbacks(&query(), Some(&new_owner))
+            .into_iter()
+            .map(|callback| {
+                assert_eq!(callback.callback.0.principal, archive);
+                callback.args.owner
+            })
+            .collect();
+        assert_eq!(owners, vec![Some(new_owner), Some(old_owner)]);
+
+        // The archive only lets the new owner read the old owner's events once it has this
+        assert_eq!(storage::get_archive(0).unwrap().aliases_pushed, None);
+        assert_eq!(
+            storage::list_owner_aliases(0),
+            vec![OwnerAlias {
+                previous: old_owner,
+                owner: Some(new_owner),
+            }]
+        );
+        assert!(storage::list_owner_aliases(1).is_empty());
+    }
+
+    #[test]
+    fn recovering_back_withdraws_the_earlier_alias() {
+        let first = Principal::from_slice(&[1; 29]);
+        let second = Principal::from_slice(&[2; 29]);
+        let third = Principal::from_slice(&[3; 29]);
+
+        storage::insert_vault(&first, vault(first));
+        storage::transfer_vault_ownership(&first, &second).unwrap();
+        // The first owner starts over with a vault, then recovers someone else's
+        storage::insert_vault(&third, vault(third));
+        storage::transfer_vault_ownership(&third, &first).unwrap();
+
+        assert_eq!(
+            storage::list_owner_aliases(1),
+            vec![
+                OwnerAlias {
+                    previous: first,
+                    owner: None,
+                },
+                OwnerAlias {
+                    previous: third,
+                    owner: Some(first),
+                },
+            ]
+        );
+    }
+}
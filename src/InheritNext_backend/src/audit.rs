@@ -0,0 +1,674 @@
+use candid::{Nat, Principal};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    archive,
+    helpers::{
+        DEFAULT_AUDIT_PAGE_SIZE, MAX_AUDIT_PAGE_SIZE, MAX_AUDIT_SCAN, MAX_AUDIT_VERIFY_RANGE,
+    },
+    storage,
+    types::{
+        ApiError, AuditChainBreak, AuditChainHead, AuditChainReport, AuditEntry, AuditEvent,
+        AuditPage, AuditQuery, EventId, EventKind, EventType, RecoveryAction,
+    },
+};
+
+// `prev` of the very first event in the chain
+pub const GENESIS_HASH: [u8; 32] = [0; 32];
+
+fn matches(query: &AuditQuery, event: &AuditEvent) -> bool {
+    if let Some(types) = &query.event_types {
+        if !types.contains(&event.event_type.kind()) {
+            return false;
+        }
+    }
+    if query.from_time.is_some_and(|t| event.timestamp < t) {
+        return false;
+    }
+    if query.to_time.is_some_and(|t| event.timestamp > t) {
+        return false;
+    }
+    true
+}
+
+// Event ids grow with time, so once we're past the requested window nothing further on
+// can match
+fn past_window(query: &AuditQuery, newest_first: bool, event: &AuditEvent) -> bool {
+    if newest_first {
+        query.from_time.is_some_and(|t| event.timestamp < t)
+    } else {
+        query.to_time.is_some_and(|t| event.timestamp > t)
+    }
+}
+
+// `blame: None` queries across every principal, otherwise only that principal's stream
+pub fn query_audit_log(query: &AuditQuery, blame: Option<&Principal>) -> AuditPage {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_AUDIT_PAGE_SIZE)
+        .clamp(1, MAX_AUDIT_PAGE_SIZE) as usize;
+    let newest_first = query.newest_first.unwrap_or(false);
+
+    let mut entries = Vec::new();
+    let mut scanned = 0;
+    let mut last_seen: Option<EventId> = None;
+    let mut exhausted = true;
+
+    storage::scan_audit_log(
+        blame,
+        query.cursor.map(EventId),
+        newest_first,
+        |id, event| {
+            if past_window(query, newest_first, &event) {
+                return false;
+            }
+            if entries.len() == limit || scanned == MAX_AUDIT_SCAN {
+                exhausted = false;
+                return false;
+            }
+
+            scanned += 1;
+            last_seen = Some(id);
+            if matches(query, &event) {
+                entries.push(AuditEntry { id: id.0, event });
+            }
+            true
+        },
+    );
+
+    AuditPage {
+        entries,
+        next_cursor: if exhausted {
+            None
+        } else {
+            last_seen.map(|id| id.0)
+        },
+        archived: archive::archive_callbacks(query, blame),
+    }
+}
+
+// Stable names for hashing. Changing one breaks verification of every event already logged
+// with that kind.
+fn kind_tag(kind: EventKind) -> &'static str {
+    match kind {
+        EventKind::VaultCreated => "VaultCreated",
+        EventKind::DmsConfigured => "DmsConfigured",
+        EventKind::AssetCreated => "AssetCreated",
+        EventKind::AssetUpdated => "AssetUpdated",
+        EventKind::AssetDeleted => "AssetDeleted",
+        EventKind::AllowanceVerified => "AllowanceVerified",
+        EventKind::NftApprovalVerified => "NftApprovalVerified",
+        EventKind::ControlVerified => "ControlVerified",
+        EventKind::CallbackVerified => "CallbackVerified",
+        EventKind::SecretKeyDerived => "SecretKeyDerived",
+        EventKind::DocumentUploaded => "DocumentUploaded",
+        EventKind::DocumentRemoved => "DocumentRemoved",
+        EventKind::DocumentAccessChanged => "DocumentAccessChanged",
+        EventKind::LetterWritten => "LetterWritten",
+        EventKind::LetterDeleted => "LetterDeleted",
+        EventKind::LetterRead => "LetterRead",
+        EventKind::BitcoinAddressDerived => "BitcoinAddressDerived",
+        EventKind::BitcoinTransactionSigned => "BitcoinTransactionSigned",
+        EventKind::EvmAddressDerived => "EvmAddressDerived",
+        EventKind::EvmTransactionSigned => "EvmTransactionSigned",
+        EventKind::EvmWithdrawalSigned => "EvmWithdrawalSigned",
+        EventKind::HeirAdded => "HeirAdded",
+        EventKind::HeirRemoved => "HeirRemoved",
+        EventKind::HeirChanged => "HeirChanged",
+        EventKind::Heartbeat => "Heartbeat",
+        EventKind::SwitchPending => "SwitchPending",
+        EventKind::VaultReleased => "VaultReleased",
+        EventKind::PayoutClaimed => "PayoutClaimed",
+        EventKind::PayoutCompleted => "PayoutCompleted",
+        EventKind::PayoutFailed => "PayoutFailed",
+        EventKind::RecoveryConfigured => "RecoveryConfigured",
+        EventKind::RecoveryInitiated => "RecoveryInitiated",
+        EventKind::RecoveryApproved => "RecoveryApproved",
+        EventKind::RecoveryCancelled => "RecoveryCancelled",
+        EventKind::RecoveryExecuted => "RecoveryExecuted",
+        EventKind::VaultRecovered => "VaultRecovered",
+        EventKind::EscrowDeposited => "EscrowDeposited",
+        EventKind::EscrowWithdrawn => "EscrowWithdrawn",
+        EventKind::ControllersChanged => "ControllersChanged",
+    }
+}
+
+fn hash_field(hasher: &mut Sha256, bytes: &[u8]) {
+    hasher.update((bytes.len() as u64).to_be_bytes());
+    hasher.update(bytes);
+}
+
+fn hash_u64(hasher: &mut Sha256, value: u64) {
+    hasher.update(value.to_be_bytes());
+}
+
+fn hash_nat(hasher: &mut Sha256, value: &Nat) {
+    hash_field(hasher, &value.0.to_bytes_be());
+}
+
+fn hash_principals(hasher: &mut Sha256, principals: &[Principal]) {
+    hash_u64(hasher, principals.len() as u64);
+    for principal in principals {
+        hash_field(hasher, principal.as_slice());
+    }
+}
+
+fn hash_recovery_action(hasher: &mut Sha256, action: &RecoveryAction) {
+    match action {
+        RecoveryAction::TransferOwnership { new_owner } => {
+            hash_field(hasher, b"TransferOwnership");
+            hash_field(hasher, new_owner.as_slice());
+        }
+        RecoveryAction::CancelPending => hash_field(hasher, b"CancelPending"),
+    }
+}
+
+// Payload fields in declaration order. Legacy events hash their details exactly as they
+// did before payloads existed, so their chain keeps verifying.
+fn hash_payload(hasher: &mut Sha256, event_type: &EventType) {
+    match event_type {
+        EventType::VaultCreated | EventType::Heartbeat | EventType::RecoveryCancelled => {}
+        EventType::DmsConfigured {
+            old_heartbeat_interval,
+            new_heartbeat_interval,
+            old_grace_period,
+            new_grace_period,
+        } => {
+            hash_u64(hasher, *old_heartbeat_interval);
+            hash_u64(hasher, *new_heartbeat_interval);
+            hash_u64(hasher, *old_grace_period);
+            hash_u64(hasher, *new_grace_period);
+        }
+        EventType::AssetCreated { asset_id, name } | EventType::AssetDeleted { asset_id, name } => {
+            hash_u64(hasher, *asset_id);
+            hash_field(hasher, name.as_bytes());
+        }
+        EventType::AssetUpdated {
+            asset_id,
+            archived_version,
+            changes,
+        } => {
+            hash_u64(hasher, *asset_id);
+            hash_u64(hasher, *archived_version as u64);
+            hash_u64(hasher, changes.len() as u64);
+            for change in changes {
+                hash_field(hasher, change.as_bytes());
+            }
+        }
+        EventType::AllowanceVerified {
+            ledger_canister,
+            required,
+            allowance,
+        } => {
+            hash_field(hasher, ledger_canister.as_slice());
+            hash_u64(hasher, *required);
+            hash_nat(hasher, allowance);
+        }
+        EventType::NftApprovalVerified {
+            collection,
+            token_ids,
+        } => {
+            hash_field(hasher, collection.as_slice());
+            hash_u64(hasher, token_ids.len() as u64);
+            for token_id in token_ids {
+                hash_nat(hasher, token_id);
+            }
+        }
+        EventType::ControlVerified {
+            canister_id,
+            controllers,
+        } => {
+            hash_field(hasher, canister_id.as_slice());
+            hash_principals(hasher, controllers);
+        }
+        EventType::DocumentUploaded {
+            document_id,
+            name,
+            size,
+            sha256,
+        } => {
+            hash_u64(hasher, *document_id);
+            hash_field(hasher, name.as_bytes());
+            hash_u64(hasher, *size);
+            hash_field(hasher, sha256);
+        }
+        EventType::DocumentRemoved { document_id } => hash_u64(hasher, *document_id),
+        EventType::BitcoinAddressDerived { asset_id, address }
+        | EventType::EvmAddressDerived { asset_id, address } => {
+            hash_u64(hasher, *asset_id);
+            hash_field(hasher, address.as_bytes());
+        }
+        EventType::BitcoinTransactionSigned {
+            asset_id,
+            txid,
+            inputs,
+            fee,
+        } => {
+            hash_u64(hasher, *asset_id);
+            hash_field(hasher, txid.as_bytes());
+            hash_u64(hasher, *inputs as u64);
+            hash_u64(hasher, *fee);
+        }
+        EventType::EvmTransactionSigned {
+            asset_id,
+            heir,
+            nonce,
+            tx_hash,
+        } => {
+            hash_u64(hasher, *asset_id);
+            hash_field(hasher, heir.as_slice());
+            hash_u64(hasher, *nonce);
+            hash_field(hasher, tx_hash.as_bytes());
+        }
+        EventType::EvmWithdrawalSigned {
+            asset_id,
+            to,
+            value,
+            nonce,
+            tx_hash,
+        } => {
+            hash_u64(hasher, *asset_id);
+            hash_field(hasher, to.as_bytes());
+            hash_field(hasher, &value.to_be_bytes());
+            hash_u64(hasher, *nonce);
+            hash_field(hasher, tx_hash.as_bytes());
+        }
+        EventType::LetterWritten { heir }
+        | EventType::LetterDeleted { heir }
+        | EventType::LetterRead { heir } => hash_field(hasher, heir.as_slice()),
+        EventType::DocumentAccessChanged {
+            document_id,
+            access,
+        } => {
+            hash_u64(hasher, *document_id);
+            hash_principals(hasher, access);
+        }
+        EventType::CallbackVerified { canister, method } => {
+            hash_field(hasher, canister.as_slice());
+            hash_field(hasher, method.as_bytes());
+        }
+        EventType::HeirAdded {
+            asset_id,
+            heir,
+            percentage,
+        }
+        | EventType::HeirRemoved {
+            asset_id,
+            heir,
+            percentage,
+        } => {
+            hash_u64(hasher, *asset_id);
+            hash_field(hasher, heir.as_slice());
+            hash_u64(hasher, *percentage as u64);
+        }
+        EventType::HeirChanged {
+            asset_id,
+            heir,
+            old_percentage,
+            new_percentage,
+        } => {
+            hash_u64(hasher, *asset_id);
+            hash_field(hasher, heir.as_slice());
+            hash_u64(hasher, *old_percentage as u64);
+            hash_u64(hasher, *new_percentage as u64);
+        }
+        EventType::SwitchPending { last_heartbeat } => hash_u64(hasher, *last_heartbeat),
+        EventType::VaultReleased { pending_since } => hash_u64(hasher, *pending_since),
+        EventType::PayoutClaimed { asset_id, heir }
+        | EventType::SecretKeyDerived { asset_id, heir } => {
+            hash_u64(hasher, *asset_id);
+            hash_field(hasher, heir.as_slice());
+        }
+        EventType::PayoutCompleted {
+            asset_id,
+            heir,
+            ledger_canister,
+            amount,
+            fee,
+            block_index,
+        } => {
+            hash_u64(hasher, *asset_id);
+            hash_field(hasher, heir.as_slice());
+            hash_field(hasher, ledger_canister.as_slice());
+            hash_u64(hasher, *amount);
+            hash_u64(hasher, *fee);
+            hash_nat(hasher, block_index);
+        }
+        EventType::PayoutFailed {
+            asset_id,
+            heir,
+            ledger_canister,
+            reason,
+        } => {
+            hash_u64(hasher, *asset_id);
+            hash_field(hasher, heir.as_slice());
+            hash_field(hasher, ledger_canister.as_slice());
+            hash_field(hasher, reason.as_bytes());
+        }
+        EventType::RecoveryConfigured {
+            recovery_principals,
+            threshold,
+        } => {
+            hash_principals(hasher, recovery_principals);
+            hash_u64(hasher, *threshold as u64);
+        }
+        EventType::RecoveryInitiated {
+            action,
+            initiated_by,
+        } => {
+            hash_recovery_action(hasher, action);
+            hash_field(hasher, initiated_by.as_slice());
+        }
+        EventType::RecoveryApproved {
+            approver,
+            approvals,
+        } => {
+            hash_field(hasher, approver.as_slice());
+            hash_u64(hasher, *approvals as u64);
+        }
+        EventType::RecoveryExecuted {
+            action,
+            executed_by,
+        } => {
+            hash_recovery_action(hasher, action);
+            hash_field(hasher, executed_by.as_slice());
+        }
+        EventType::VaultRecovered { previous_owner } => {
+            hash_field(hasher, previous_owner.as_slice())
+        }
+        EventType::EscrowDeposited {
+            asset_id,
+            ledger_canister,
+            amount,
+        } => {
+            hash_u64(hasher, *asset_id);
+            hash_field(hasher, ledger_canister.as_slice());
+            hash_u64(hasher, *amount);
+        }
+        EventType::EscrowWithdrawn {
+            asset_id,
+            ledger_canister,
+            amount,
+            fee,
+            block_index,
+        } => {
+            hash_u64(hasher, *asset_id);
+            hash_field(hasher, ledger_canister.as_slice());
+            hash_u64(hasher, *amount);
+            hash_u64(hasher, *fee);
+            hash_nat(hasher, block_index);
+        }
+        EventType::ControllersChanged {
+            asset_id,
+            canister_id,
+            added,
+            removed,
+        } => {
+            hash_u64(hasher, *asset_id);
+            hash_field(hasher, canister_id.as_slice());
+            hash_principals(hasher, added);
+            hash_principals(hasher, removed);
+        }
+        EventType::Legacy { details, .. } => hash_field(hasher, details.as_bytes()),
+    }
+}
+
+// sha256(prev ++ id ++ timestamp ++ len-prefixed kind tag and blame ++ payload fields).
+// Integers are big-endian u64, strings, principals and nats are length-prefixed. Clients
+// recompute this to check events against the certified head.
+pub fn chain_hash(prev: &[u8; 32], id: EventId, event: &AuditEvent) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(prev);
+    hasher.update(id.0.to_be_bytes());
+    hasher.update(event.timestamp.to_be_bytes());
+    hash_field(&mut hasher, kind_tag(event.event_type.kind()).as_bytes());
+    hash_field(&mut hasher, event.blame.as_slice());
+    hash_payload(&mut hasher, &event.event_type);
+    hasher.finalize().into()
+}
+
+// Must run in update context, after every append and after an upgrade
+pub fn certify_chain_head() {
+    let head = storage::audit_chain_head().map_or(GENESIS_HASH, |(_, hash)| hash);
+    ic_cdk::api::certified_data_set(head);
+}
+
+pub fn chain_head() -> AuditChainHead {
+    let head = storage::audit_chain_head();
+    AuditChainHead {
+        event_id: head.map(|(id, _)| id.0),
+        hash: head.map_or(GENESIS_HASH, |(_, hash)| hash).to_vec(),
+        certificate: ic_cdk::api::data_certificate(),
+    }
+}
+
+// Re-walks `from..=to`, checking every retained event against its stored hash and every
+// link against the hash before it. Archived events take their hash with them, so the link
+// just after one can only be checked against the archive and is taken as stored.
+pub fn verify_chain(from: u64, to: u64) -> Result<AuditChainReport, ApiError> {
+    if to < from {
+        return Err(ApiError::validation("to", "Must not be before from"));
+    }
+    if to - from >= MAX_AUDIT_VERIFY_RANGE {
+        return Err(ApiError::validation(
+            "to",
+            format!(
+                "At most {} events can be verified per call",
+                MAX_AUDIT_VERIFY_RANGE
+            ),
+        ));
+    }
+
+    let head = storage::audit_chain_head();
+    let last = head.map_or(0, |(id, _)| id.0);
+    let mut prev = storage::audit_hash_before(EventId(from)).unwrap_or(GENESIS_HASH);
+    let mut after_gap = from > 0 && storage::get_audit_hash(EventId(from - 1)).is_none();
+    let mut report = AuditChainReport {
+        from,
+        to,
+        checked: 0,
+        pruned: 0,
+        breaks: Vec::new(),
+        head_matches: None,
+    };
+
+    if head.is_none() || from > last {
+        return Ok(report);
+    }
+
+    for id in from..=to.min(last) {
+        let event_id = EventId(id);
+        let reason = match (
+            storage::get_audit_hash(event_id),
+            storage::get_audit_event(event_id),
+        ) {
+            // Pruned before the chain existed, or archived
+            (None, None) => {
+                after_gap = true;
+                continue;
+            }
+            (None, Some(_)) => Some("Event has no chain hash".to_string()),
+            (Some(hash), None) => {
+                report.pruned += 1;
+                prev = hash;
+                after_gap = false;
+                continue;
+            }
+            (Some(hash), Some(event)) => {
+                report.checked += 1;
+                if after_gap {
+                    if let Some(stored) = event.prev_hash.as_deref().and_then(|p| p.try_into().ok())
+                    {
+                        prev = stored;
+                    }
+                }
+                after_gap = false;
+                let reason = if event.prev_hash.as_deref() != Some(&prev[..]) {
+                    Some("Previous hash does not match the chain".to_string())
+                } else if chain_hash(&prev, event_id, &event) != hash {
+                    Some("Event content does not match its hash".to_string())
+                } else {
+                    None
+                };
+                prev = hash;
+                reason
+            }
+        };
+
+        if let Some(reason) = reason {
+            report.breaks.push(AuditChainBreak {
+                event_id: id,
+                reason,
+            });
+        }
+    }
+
+    if to >= last {
+        report.head_matches = head.map(|(_, hash)| hash == prev);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(timestamp: u64, event_type: EventType) -> AuditEvent {
+        AuditEvent {
+            timestamp,
+            event_type,
+            blame: Principal::from_slice(&[1; 29]),
+            prev_hash: None,
+        }
+    }
+
+    fn dms_configured(new_grace_period: u64) -> EventType {
+        EventType::DmsConfigured {
+            old_heartbeat_interval: 1,
+            new_heartbeat_interval: 2,
+            old_grace_period: 3,
+            new_grace_period,
+        }
+    }
+
+    fn log(count: u64) {
+        for i in 0..count {
+            storage::log_event(event(i, EventType::Heartbeat));
+        }
+    }
+
+    #[test]
+    fn chain_hash_follows_documented_layout() {
+        let prev = [7u8; 32];
+        let heartbeat = event(42, EventType::Heartbeat);
+
+        let mut expected = Sha256::new();
+        expected.update(prev);
+        expected.update(5u64.to_be_bytes());
+        expected.update(42u64.to_be_bytes());
+        expected.update(9u64.to_be_bytes());
+        expected.update(b"Heartbeat");
+        expected.update(29u64.to_be_bytes());
+        expected.update([1u8; 29]);
+        let expected: [u8; 32] = expected.finalize().into();
+
+        assert_eq!(chain_hash(&prev, EventId(5), &heartbeat), expected);
+    }
+
+    #[test]
+    fn chain_hash_covers_every_field() {
+        let base = event(42, dms_configured(4));
+        let hash = chain_hash(&GENESIS_HASH, EventId(5), &base);
+
+        assert_ne!(chain_hash(&[1; 32], EventId(5), &base), hash);
+        assert_ne!(chain_hash(&GENESIS_HASH, EventId(6), &base), hash);
+        assert_ne!(
+            chain_hash(&GENESIS_HASH, EventId(5), &event(43, dms_configured(4))),
+            hash
+        );
+        assert_ne!(
+            chain_hash(&GENESIS_HASH, EventId(5), &event(42, dms_configured(5))),
+            hash
+        );
+        let other_blame = AuditEvent {
+            blame: Principal::from_slice(&[2; 29]),
+            ..base.clone()
+        };
+        assert_ne!(chain_hash(&GENESIS_HASH, EventId(5), &other_blame), hash);
+        // The stored link is checked separately, it is not part of the hashed content
+        let linked = AuditEvent {
+            prev_hash: Some(vec![9; 32]),
+            ..base
+        };
+        assert_eq!(chain_hash(&GENESIS_HASH, EventId(5), &linked), hash);
+    }
+
+    #[test]
+    fn intact_chain_verifies_up_to_the_head() {
+        log(5);
+        let report = verify_chain(0, 10).unwrap();
+        assert_eq!(report.checked, 5);
+        assert!(report.breaks.is_empty());
+        assert_eq!(report.head_matches, Some(true));
+
+        let partial = verify_chain(1, 3).unwrap();
+        assert_eq!(partial.checked, 3);
+        assert!(partial.breaks.is_empty());
+        assert_eq!(partial.head_matches, None);
+    }
+
+    #[test]
+    fn tampered_event_breaks_the_chain() {
+        log(5);
+        let mut tampered = storage::get_audit_event(EventId(2)).unwrap();
+        tampered.timestamp += 1;
+        storage::overwrite_audit_event(EventId(2), tampered);
+
+        let mut relinked = storage::get_audit_event(EventId(3)).unwrap();
+        relinked.prev_hash = Some(vec![0; 32]);
+        storage::overwrite_audit_event(EventId(3), relinked);
+
+        let report = verify_chain(0, 4).unwrap();
+        let broken: Vec<u64> = report.breaks.iter().map(|b| b.event_id).collect();
+        assert_eq!(broken, vec![2, 3]);
+        assert_eq!(
+            report.breaks[0].reason,
+            "Event content does not match its hash"
+        );
+        assert_eq!(
+            report.breaks[1].reason,
+            "Previous hash does not match the chain"
+        );
+        assert_eq!(report.head_matches, Some(true));
+    }
+
+    #[test]
+    fn archived_events_leave_a_verifiable_gap() {
+        log(5);
+        storage::complete_archive_batch(0, &[EventId(1), EventId(2)]);
+        assert_eq!(storage::get_audit_hash(EventId(1)), None);
+
+        let report = verify_chain(0, 4).unwrap();
+        assert_eq!(report.checked, 3);
+        assert!(report.breaks.is_empty());
+        assert_eq!(report.head_matches, Some(true));
+
+        let after_gap = verify_chain(3, 4).unwrap();
+        assert_eq!(after_gap.checked, 2);
+        assert!(after_gap.breaks.is_empty());
+    }
+
+    #[test]
+    fn invalid_ranges_are_rejected() {
+        assert!(verify_chain(5, 4).is_err());
+        assert!(verify_chain(0, MAX_AUDIT_VERIFY_RANGE).is_err());
+        assert!(verify_chain(0, MAX_AUDIT_VERIFY_RANGE - 1).is_ok());
+    }
+
+    #[test]
+    fn empty_log_has_nothing_to_check() {
+        let report = verify_chain(0, 10).unwrap();
+        assert_eq!(report.checked, 0);
+        assert_eq!(report.head_matches, None);
+    }
+}
@@ -0,0 +1,890 @@
+use std::{cell::RefCell, collections::BTreeSet};
+
+use bech32::{segwit, Hrp};
+use candid::{Nat, Principal};
+use ic_cdk::{
+    bitcoin_canister::{
+        bitcoin_get_balance, bitcoin_get_current_fee_percentiles, bitcoin_get_utxos,
+        bitcoin_send_transaction, get_bitcoin_canister_id, GetBalanceRequest,
+        GetCurrentFeePercentilesRequest, GetUtxosRequest, Network, SendTransactionRequest, Utxo,
+        UtxosFilter,
+    },
+    call::CallErrorExt,
+    management_canister::{
+        ecdsa_public_key, sign_with_ecdsa, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgs,
+        SignWithEcdsaArgs,
+    },
+};
+use icrc_ledger_types::icrc2::transfer_from::TransferFromError;
+use ripemd::Ripemd160;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    helpers::{
+        compute_heir_shares, log_event, remainder_policy, BITCOIN_DUST_THRESHOLD,
+        DEFAULT_BITCOIN_FEE_RATE, ECDSA_KEY_NAME, MAX_BITCOIN_INPUTS,
+    },
+    release::AttemptError,
+    storage::{self, get_asset, get_release_job, get_vault, insert_release_job},
+    types::{ApiError, AssetType, EventType, JobState, ReleaseJob, ReleaseJobKey},
+};
+
+// secp256k1 group order and half of it, for low-S normalization
+const CURVE_ORDER: [u8; 32] = [
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFE,
+    0xBA, 0xAE, 0xDC, 0xE6, 0xAF, 0x48, 0xA0, 0x3B, 0xBF, 0xD2, 0x5E, 0x8C, 0xD0, 0x36, 0x41, 0x41,
+];
+const HALF_CURVE_ORDER: [u8; 32] = [
+    0x7F, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    0x5D, 0x57, 0x6E, 0x73, 0x57, 0xA4, 0x50, 0x1D, 0xDF, 0xE9, 0x2F, 0x46, 0x68, 0x1B, 0x20, 0xA0,
+];
+const SIGHASH_ALL: u32 = 1;
+// Upper bounds for P2WPKH inputs and any standard output, in vbytes
+const TX_OVERHEAD_VBYTES: u64 = 11;
+const INPUT_VBYTES: u64 = 68;
+const OUTPUT_VBYTES: u64 = 43;
+
+thread_local! {
+    // Assets whose payout transaction is being built
+    static BUSY: RefCell<BTreeSet<u64>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+struct BuildGuard(u64);
+
+impl BuildGuard {
+    fn acquire(asset_id: u64) -> Option<Self> {
+        BUSY.with(|b| b.borrow_mut().insert(asset_id))
+            .then_some(BuildGuard(asset_id))
+    }
+}
+
+impl Drop for BuildGuard {
+    fn drop(&mut self) {
+        BUSY.with(|b| b.borrow_mut().remove(&self.0));
+    }
+}
+
+// Picked at build time, `regtest` for a local replica
+pub fn network() -> Network {
+    match option_env!("BITCOIN_NETWORK") {
+        Some("regtest") => Network::Regtest,
+        Some("testnet") => Network::Testnet,
+        _ => Network::Mainnet,
+    }
+}
+
+pub fn bitcoin_canister() -> Principal {
+    get_bitcoin_canister_id(&network())
+}
+
+fn hrp() -> Hrp {
+    match network() {
+        Network::Mainnet => bech32::hrp::BC,
+        Network::Testnet => bech32::hrp::TB,
+        Network::Regtest => bech32::hrp::BCRT,
+    }
+}
+
+// P2PKH and P2SH version bytes
+fn base58_versions() -> (u8, u8) {
+    match network() {
+        Network::Mainnet => (0x00, 0x05),
+        Network::Testnet | Network::Regtest => (0x6F, 0xC4),
+    }
+}
+
+// The EVM addresses in `evm` come from the same key, under their own derivation paths
+pub fn key_id() -> EcdsaKeyId {
+    EcdsaKeyId {
+        curve: EcdsaCurve::Secp256k1,
+        name: ECDSA_KEY_NAME.to_string(),
+    }
+}
+
+fn hash160(bytes: &[u8]) -> [u8; 20] {
+    Ripemd160::digest(Sha256::digest(bytes)).into()
+}
+
+fn double_sha256(bytes: &[u8]) -> [u8; 32] {
+    Sha256::digest(Sha256::digest(bytes)).into()
+}
+
+// Accepts the address types a wallet hands out: P2PKH, P2SH and every segwit version
+pub fn script_pubkey(address: &str) -> Result<Vec<u8>, String> {
+    if let Ok((hrp_found, version, program)) = segwit::decode(address) {
+        if hrp_found != hrp() {
+            return Err(format!("not an address on {:?}", network()));
+        }
+        let version = version.to_u8();
+        let mut script = vec![if version == 0 { 0x00 } else { 0x50 + version }];
+        script.push(program.len() as u8);
+        script.extend_from_slice(&program);
+        return Ok(script);
+    }
+
+    let decoded = bs58::decode(address)
+        .with_check(None)
+        .into_vec()
+        .map_err(|_| "not a valid Bitcoin address".to_string())?;
+    let (p2pkh, p2sh) = base58_versions();
+    match decoded.split_first() {
+        Some((version, hash)) if hash.len() == 20 && *version == p2pkh => {
+            let mut script = vec![0x76, 0xA9, 0x14];
+            script.extend_from_slice(hash);
+            script.extend_from_slice(&[0x88, 0xAC]);
+            Ok(script)
+        }
+        Some((version, hash)) if hash.len() == 20 && *version == p2sh => {
+            let mut script = vec![0xA9, 0x14];
+            script.extend_from_slice(hash);
+            script.push(0x87);
+            Ok(script)
+        }
+        _ => Err(format!("not an address on {:?}", network())),
+    }
+}
+
+pub fn derivation_path(owner: &Principal, asset_id: u64) -> Vec<Vec<u8>> {
+    vec![owner.as_slice().to_vec(), asset_id.to_be_bytes().to_vec()]
+}
+
+pub async fn public_key(derivation_path: &[Vec<u8>]) -> Result<Vec<u8>, String> {
+    let args = EcdsaPublicKeyArgs {
+        canister_id: None,
+        derivation_path: derivation_path.to_vec(),
+        key_id: key_id(),
+    };
+    ecdsa_public_key(&args)
+        .await
+        .map(|r| r.public_key)
+        .map_err(|e| format!("ecdsa_public_key failed: {:?}", e))
+}
+
+// The path is fixed here, so a later ownership transfer keeps signing for the same address
+pub async fn derive_address(owner: &Principal, asset_id: u64) -> Result<AssetType, ApiError> {
+    let derivation_path = derivation_path(owner, asset_id);
+    let public_key = public_key(&derivation_path)
+        .await
+        .map_err(|reason| ApiError::BitcoinCallFailed { reason })?;
+    let address = segwit::encode_v0(hrp(), &hash160(&public_key)).map_err(|e| {
+        ApiError::BitcoinCallFailed {
+            reason: format!("Failed to encode address: {:?}", e),
+        }
+    })?;
+
+    log_event(
+        EventType::BitcoinAddressDerived {
+            asset_id,
+            address: address.clone(),
+        },
+        owner,
+    );
+
+    Ok(AssetType::BitcoinAddress {
+        address,
+        derivation_path,
+    })
+}
+
+pub async fn balance(address: &str) -> Result<u64, ApiError> {
+    let args = GetBalanceRequest {
+        network: network(),
+        address: address.to_string(),
+        min_confirmations: None,
+    };
+    bitcoin_get_balance(&args)
+        .await
+        .map_err(|e| ApiError::BitcoinCallFailed {
+            reason: format!("bitcoin_get_balance failed: {:?}", e),
+        })
+}
+
+// Only a release spends from the address, so coins sent to it would be stranded once the
+// asset stops being a Bitcoin address
+pub async fn ensure_empty(address: &str, field: &str, action: &str) -> Result<(), ApiError> {
+    let balance = balance(address).await?;
+    if balance > 0 {
+        return Err(ApiError::validation(
+            field,
+            format!(
+                "{} still holds {} satoshi that would be stranded by {}",
+                address, balance, action
+            ),
+        ));
+    }
+    Ok(())
+}
+
+async fn all_utxos(address: &str) -> Result<Vec<Utxo>, String> {
+    let mut utxos = Vec::new();
+    let mut filter = None;
+    loop {
+        let args = GetUtxosRequest {
+            network: network(),
+            address: address.to_string(),
+            filter,
+        };
+        let page = bitcoin_get_utxos(&args)
+            .await
+            .map_err(|e| format!("bitcoin_get_utxos failed: {:?}", e))?;
+        utxos.extend(page.utxos);
+        match page.next_page {
+            Some(next) => filter = Some(UtxosFilter::Page(next)),
+            None => return Ok(utxos),
+        }
+    }
+}
+
+// Median of recent fees in millisatoshi per vbyte. Regtest has no history to go on.
+async fn fee_rate() -> Result<u64, String> {
+    let args = GetCurrentFeePercentilesRequest { network: network() };
+    let percentiles = bitcoin_get_current_fee_percentiles(&args)
+        .await
+        .map_err(|e| format!("bitcoin_get_current_fee_percentiles failed: {:?}", e))?;
+    Ok(percentiles
+        .get(50)
+        .copied()
+        .unwrap_or(DEFAULT_BITCOIN_FEE_RATE))
+}
+
+fn write_compact_size(out: &mut Vec<u8>, n: usize) {
+    match n {
+        0..=0xFC => out.push(n as u8),
+        0xFD..=0xFFFF => {
+            out.push(0xFD);
+            out.extend_from_slice(&(n as u16).to_le_bytes());
+        }
+        _ => {
+            out.push(0xFE);
+            out.extend_from_slice(&(n as u32).to_le_bytes());
+        }
+    }
+}
+
+struct TxOut {
+    value: u64,
+    script_pubkey: Vec<u8>,
+}
+
+fn write_outpoint(out: &mut Vec<u8>, utxo: &Utxo) {
+    out.extend_from_slice(&utxo.outpoint.txid);
+    out.extend_from_slice(&utxo.outpoint.vout.to_le_bytes());
+}
+
+fn write_output(out: &mut Vec<u8>, output: &TxOut) {
+    out.extend_from_slice(&output.value.to_le_bytes());
+    write_compact_size(out, output.script_pubkey.len());
+    out.extend_from_slice(&output.script_pubkey);
+}
+
+// The parts of a transaction that are not inputs or outputs
+struct Framing {
+    version: u32,
+    sequences: Vec<u32>,
+    lock_time: u32,
+}
+
+impl Framing {
+    // Version 2, no locktime, final sequence on every input
+    fn standard(inputs: usize) -> Self {
+        Framing {
+            version: 2,
+            sequences: vec![u32::MAX; inputs],
+            lock_time: 0,
+        }
+    }
+}
+
+fn serialize_tx(
+    framing: &Framing,
+    inputs: &[Utxo],
+    outputs: &[TxOut],
+    witnesses: Option<&[Vec<Vec<u8>>]>,
+) -> Vec<u8> {
+    let mut tx = framing.version.to_le_bytes().to_vec();
+    if witnesses.is_some() {
+        tx.extend_from_slice(&[0x00, 0x01]);
+    }
+    write_compact_size(&mut tx, inputs.len());
+    for (input, sequence) in inputs.iter().zip(&framing.sequences) {
+        write_outpoint(&mut tx, input);
+        tx.push(0x00);
+        tx.extend_from_slice(&sequence.to_le_bytes());
+    }
+    write_compact_size(&mut tx, outputs.len());
+    for output in outputs {
+        write_output(&mut tx, output);
+    }
+    for witness in witnesses.unwrap_or_default() {
+        write_compact_size(&mut tx, witness.len());
+        for item in witness {
+            write_compact_size(&mut tx, item.len());
+            tx.extend_from_slice(item);
+        }
+    }
+    tx.extend_from_slice(&framing.lock_time.to_le_bytes());
+    tx
+}
+
+// BIP-143 digest for spending a P2WPKH input with SIGHASH_ALL
+fn sighash(
+    framing: &Framing,
+    inputs: &[Utxo],
+    outputs: &[TxOut],
+    index: usize,
+    pubkey_hash: &[u8; 20],
+) -> [u8; 32] {
+    let mut prevouts = Vec::new();
+    let mut sequences = Vec::new();
+    for (input, sequence) in inputs.iter().zip(&framing.sequences) {
+        write_outpoint(&mut prevouts, input);
+        sequences.extend_from_slice(&sequence.to_le_bytes());
+    }
+    let mut serialized_outputs = Vec::new();
+    for output in outputs {
+        write_output(&mut serialized_outputs, output);
+    }
+
+    let input = &inputs[index];
+    let mut preimage = framing.version.to_le_bytes().to_vec();
+    preimage.extend_from_slice(&double_sha256(&prevouts));
+    preimage.extend_from_slice(&double_sha256(&sequences));
+    write_outpoint(&mut preimage, input);
+    preimage.extend_from_slice(&[0x19, 0x76, 0xA9, 0x14]);
+    preimage.extend_from_slice(pubkey_hash);
+    preimage.extend_from_slice(&[0x88, 0xAC]);
+    preimage.extend_from_slice(&input.value.to_le_bytes());
+    preimage.extend_from_slice(&framing.sequences[index].to_le_bytes());
+    preimage.extend_from_slice(&double_sha256(&serialized_outputs));
+    preimage.extend_from_slice(&framing.lock_time.to_le_bytes());
+    preimage.extend_from_slice(&SIGHASH_ALL.to_le_bytes());
+    double_sha256(&preimage)
+}
+
+// Consensus rules only accept the low-S form of a signature
+fn normalize_s(s: &mut [u8; 32]) {
+    if *s <= HALF_CURVE_ORDER {
+        return;
+    }
+    let mut borrow = 0i16;
+    for i in (0..32).rev() {
+        let diff = CURVE_ORDER[i] as i16 - s[i] as i16 - borrow;
+        borrow = (diff < 0) as i16;
+        s[i] = (diff + 256 * borrow) as u8;
+    }
+}
+
+fn der_integer(out: &mut Vec<u8>, value: &[u8]) {
+    let start = value
+        .iter()
+        .position(|b| *b != 0)
+        .unwrap_or(value.len() - 1);
+    let value = &value[start..];
+    let pad = value[0] & 0x80 != 0;
+    out.push(0x02);
+    out.push(value.len() as u8 + pad as u8);
+    if pad {
+        out.push(0x00);
+    }
+    out.extend_from_slice(value);
+}
+
+// Turns the 64-byte r ‖ s from sign_with_ecdsa into a DER signature with the sighash byte
+fn encode_signature(signature: &[u8]) -> Vec<u8> {
+    let mut s = [0u8; 32];
+    s.copy_from_slice(&signature[32..64]);
+    normalize_s(&mut s);
+
+    let mut body = Vec::with_capacity(70);
+    der_integer(&mut body, &signature[..32]);
+    der_integer(&mut body, &s);
+
+    let mut der = vec![0x30, body.len() as u8];
+    der.extend_from_slice(&body);
+    der.push(SIGHASH_ALL as u8);
+    der
+}
+
+fn txid(unsigned_tx: &[u8]) -> String {
+    let mut hash = double_sha256(unsigned_tx);
+    hash.reverse();
+    hex::encode(hash)
+}
+
+fn permanent(message: String) -> Result<Result<Nat, TransferFromError>, AttemptError> {
+    Ok(Err(TransferFromError::GenericError {
+        error_code: Nat::from(0u64),
+        message,
+    }))
+}
+
+// One set of transactions pays every heir still waiting, so the UTXOs are split exactly
+// once. It is stored on each of their jobs before it is broadcast, and every later attempt
+// of any of those jobs only rebroadcasts it.
+pub async fn send_share(
+    job: &mut ReleaseJob,
+) -> Result<Result<Nat, TransferFromError>, AttemptError> {
+    if job.bitcoin_tx.is_none() {
+        let Some(_guard) = BuildGuard::acquire(job.asset_id) else {
+            return Err(AttemptError::Clean(
+                "Another payout of this asset is building its transaction".to_string(),
+            ));
+        };
+        if let Err(result) = build_transactions(job).await {
+            return result;
+        }
+    }
+    let Some(first) = job.bitcoin_tx.clone() else {
+        return permanent("No transaction to send".to_string());
+    };
+
+    let transactions =
+        std::iter::once(first).chain(job.bitcoin_extra_txs.clone().unwrap_or_default());
+    for transaction in transactions {
+        let args = SendTransactionRequest {
+            network: network(),
+            transaction,
+        };
+        bitcoin_send_transaction(&args).await.map_err(|e| {
+            let reason = format!("bitcoin_send_transaction failed: {:?}", e);
+            if e.is_clean_reject() {
+                AttemptError::Clean(reason)
+            } else {
+                AttemptError::Unknown(reason)
+            }
+        })?;
+    }
+
+    // The other heirs were paid, this one's share was too small for an output in every batch
+    if job.share == 0 {
+        return permanent(format!(
+            "Share was below the dust threshold of {} satoshi in every transaction",
+            BITCOIN_DUST_THRESHOLD
+        ));
+    }
+    Ok(Ok(Nat::from(0u64)))
+}
+
+// A heir's share of one transaction, or None when it is too small for an output
+fn output_value(share: u64) -> Option<u64> {
+    (share >= BITCOIN_DUST_THRESHOLD).then_some(share)
+}
+
+// The address can hold more UTXOs than fit in one transaction, so they are spent in
+// batches of MAX_BITCOIN_INPUTS, largest first. Every batch is a transaction of its own that
+// splits its value between the heirs, so none of them depends on another confirming.
+async fn build_transactions(
+    job: &mut ReleaseJob,
+) -> Result<(), Result<Result<Nat, TransferFromError>, AttemptError>> {
+    let Some(asset) = get_asset(job.asset_id) else {
+        return Err(permanent("Asset no longer exists".to_string()));
+    };
+    let AssetType::BitcoinAddress {
+        address,
+        derivation_path,
+    } = asset.asset_type.clone()
+    else {
+        return Err(permanent("Asset no longer holds bitcoin".to_string()));
+    };
+    let Some(vault) = get_vault(&asset.owner) else {
+        return Err(permanent("Vault no longer exists".to_string()));
+    };
+    let clean = |reason: String| Err(Err(AttemptError::Clean(reason)));
+
+    let mut utxos = match all_utxos(&address).await {
+        Ok(utxos) => utxos,
+        Err(reason) => return clean(reason),
+    };
+    utxos.sort_by_key(|u| std::cmp::Reverse(u.value));
+    let total: u64 = utxos.iter().map(|u| u.value).sum();
+
+    let rate = match fee_rate().await {
+        Ok(rate) => rate,
+        Err(reason) => return clean(reason),
+    };
+    let public_key = match public_key(&derivation_path).await {
+        Ok(key) => key,
+        Err(reason) => return clean(reason),
+    };
+    let pubkey_hash = hash160(&public_key);
+
+    // Heirs whose payout hasn't been settled by an earlier transaction
+    let mut waiting: Vec<ReleaseJob> = storage::list_owner_release_jobs(&job.owner)
+        .into_iter()
+        .filter(|j| j.asset_id == job.asset_id && j.heir != job.heir && j.bitcoin_tx.is_none())
+        .filter(|j| matches!(j.state, JobState::Queued | JobState::FailedRetryable { .. }))
+        .collect();
+    waiting.push(job.clone());
+
+    let mut scripts = Vec::with_capacity(waiting.len());
+    for waiting_job in &waiting {
+        let Some(script) = waiting_job
+            .bitcoin_address
+            .as_deref()
+            .and_then(|a| script_pubkey(a).ok())
+        else {
+            return Err(permanent(format!(
+                "Invalid Bitcoin address for heir {}",
+                waiting_job.heir.to_text()
+            )));
+        };
+        scripts.push(script);
+    }
+    let mut change_script = vec![0x00, 0x14];
+    change_script.extend_from_slice(&pubkey_hash);
+
+    let mut paid = vec![0u64; waiting.len()];
+    let mut transactions = Vec::new();
+    for batch in utxos.chunks(MAX_BITCOIN_INPUTS) {
+        let batch_total: u64 = batch.iter().map(|u| u.value).sum();
+        let outputs_upper = waiting.len() as u64 + 1;
+        let fee = (TX_OVERHEAD_VBYTES
+            + INPUT_VBYTES * batch.len() as u64
+            + OUTPUT_VBYTES * outputs_upper)
+            * rate
+            / 1000;
+        // Batches only get smaller, so no later one pays for itself either
+        if batch_total <= fee {
+            break;
+        }
+
+        let shares = compute_heir_shares(
+            batch_total - fee,
+            &asset.heir_assingment,
+            &remainder_policy(&vault),
+        );
+        let mut outputs = Vec::new();
+        for (i, waiting_job) in waiting.iter().enumerate() {
+            let share: u64 = shares
+                .iter()
+                .filter(|(heir, _)| *heir == waiting_job.heir)
+                .map(|(_, share)| share)
+                .sum();
+            // Dust would be rejected by the network, so it stays with the change
+            if let Some(value) = output_value(share) {
+                outputs.push(TxOut {
+                    value,
+                    script_pubkey: scripts[i].clone(),
+                });
+                paid[i] += value;
+            }
+        }
+
+        // Shares of heirs paid earlier or of nobody go back to the vault address
+        let change = batch_total - fee - outputs.iter().map(|o| o.value).sum::<u64>();
+        if change >= BITCOIN_DUST_THRESHOLD {
+            outputs.push(TxOut {
+                value: change,
+                script_pubkey: change_script.clone(),
+            });
+        }
+        if outputs.is_empty() {
+            break;
+        }
+
+        let framing = Framing::standard(batch.len());
+        let mut witnesses = Vec::with_capacity(batch.len());
+        for index in 0..batch.len() {
+            let args = SignWithEcdsaArgs {
+                message_hash: sighash(&framing, batch, &outputs, index, &pubkey_hash).to_vec(),
+                derivation_path: derivation_path.clone(),
+                key_id: key_id(),
+            };
+            let signature = match sign_with_ecdsa(&args).await {
+                Ok(result) => result.signature,
+                Err(e) => return clean(format!("sign_with_ecdsa failed: {:?}", e)),
+            };
+            witnesses.push(vec![encode_signature(&signature), public_key.clone()]);
+        }
+
+        let network_fee = batch_total - outputs.iter().map(|o| o.value).sum::<u64>();
+        transactions.push((
+            serialize_tx(&framing, batch, &outputs, Some(&witnesses)),
+            txid(&serialize_tx(&framing, batch, &outputs, None)),
+            batch.len() as u32,
+            network_fee,
+        ));
+    }
+    if transactions.is_empty() {
+        return Err(Ok(Err(TransferFromError::InsufficientFunds {
+            balance: Nat::from(total),
+        })));
+    }
+
+    let mut signed = transactions.iter().map(|(tx, ..)| tx.clone());
+    let first = signed.next();
+    let extra: Vec<Vec<u8>> = signed.collect();
+    let extra = (!extra.is_empty()).then_some(extra);
+
+    // Persisted on every paid job before anything is broadcast
+    for (waiting_job, share) in waiting.iter().zip(paid) {
+        if waiting_job.heir == job.heir {
+            job.share = share;
+            job.bitcoin_tx = first.clone();
+            job.bitcoin_extra_txs = extra.clone();
+            insert_release_job(job.clone());
+            continue;
+        }
+        let key = ReleaseJobKey {
+            asset_id: job.asset_id,
+            heir: waiting_job.heir,
+        };
+        if let Some(mut sibling) = get_release_job(&key) {
+            sibling.share = share;
+            sibling.bitcoin_tx = first.clone();
+            sibling.bitcoin_extra_txs = extra.clone();
+            insert_release_job(sibling);
+        }
+    }
+
+    for (_, txid, inputs, fee) in transactions {
+        log_event(
+            EventType::BitcoinTransactionSigned {
+                asset_id: job.asset_id,
+                txid,
+                inputs,
+                fee,
+            },
+            &job.owner,
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_cdk::bitcoin_canister::Outpoint;
+    use k256::ecdsa::{signature::hazmat::PrehashVerifier, Signature, VerifyingKey};
+
+    use super::*;
+
+    fn bytes(hex: &str) -> Vec<u8> {
+        hex::decode(hex).unwrap()
+    }
+
+    fn p2pkh(hash: &str) -> Vec<u8> {
+        bytes(&format!("76a914{}88ac", hash))
+    }
+
+    // The P2SH-P2WPKH example of BIP-143. Its input is signed with the same P2WPKH
+    // script code as a native one, so it exercises `sighash` unchanged.
+    struct Bip143 {
+        framing: Framing,
+        inputs: Vec<Utxo>,
+        outputs: Vec<TxOut>,
+        pubkey_hash: [u8; 20],
+    }
+
+    const BIP143_UNSIGNED: &str = "0100000001db6b1b20aa0fd7b23880be2ecbd4a98130974cf4748fb66092\
+        ac4d3ceb1a54770100000000feffffff02b8b4eb0b000000001976a914a457b684d7f0d539a46a45bbc043f35b\
+        59d0d96388ac0008af2f000000001976a914fd270b1ee6abcaea97fea7ad0402e8bd8ad6d77c88ac92040000";
+    const BIP143_SIGNATURE: &str = "3044022047ac8e878352d3ebbde1c94ce3a10d057c24175747116f8288e5d\
+        794d12d482f0220217f36a485cae903c713331d877c1f64677e3622ad4010726870540656fe9dcb01";
+    const BIP143_PUBKEY: &str =
+        "03ad1d8e89212f0b92c74d23bb710c00662ad1470198ac48c43f7d6f93a2a26873";
+
+    fn bip143() -> Bip143 {
+        Bip143 {
+            framing: Framing {
+                version: 1,
+                sequences: vec![0xFFFF_FFFE],
+                lock_time: 0x492,
+            },
+            inputs: vec![Utxo {
+                outpoint: Outpoint {
+                    txid: bytes("db6b1b20aa0fd7b23880be2ecbd4a98130974cf4748fb66092ac4d3ceb1a5477"),
+                    vout: 1,
+                },
+                value: 1_000_000_000,
+                height: 0,
+            }],
+            outputs: vec![
+                TxOut {
+                    value: 199_996_600,
+                    script_pubkey: p2pkh("a457b684d7f0d539a46a45bbc043f35b59d0d963"),
+                },
+                TxOut {
+                    value: 800_000_000,
+                    script_pubkey: p2pkh("fd270b1ee6abcaea97fea7ad0402e8bd8ad6d77c"),
+                },
+            ],
+            pubkey_hash: bytes("79091972186c449eb1ded22b78e40d009bdf0089")
+                .try_into()
+                .unwrap(),
+        }
+    }
+
+    #[test]
+    fn sighash_matches_bip143() {
+        let v = bip143();
+        assert_eq!(
+            hex::encode(sighash(
+                &v.framing,
+                &v.inputs,
+                &v.outputs,
+                0,
+                &v.pubkey_hash
+            )),
+            "64f3b0f4dd2bb3aa1ce8566d220cc74dda9df97d8490cc81d89d735c92e59fb6"
+        );
+    }
+
+    #[test]
+    fn bip143_signature_verifies_against_our_sighash() {
+        let v = bip143();
+        let digest = sighash(&v.framing, &v.inputs, &v.outputs, 0, &v.pubkey_hash);
+        let der = bytes(BIP143_SIGNATURE);
+        let signature = Signature::from_der(&der[..der.len() - 1]).unwrap();
+        let key = VerifyingKey::from_sec1_bytes(&bytes(BIP143_PUBKEY)).unwrap();
+        assert_eq!(hash160(&bytes(BIP143_PUBKEY)), v.pubkey_hash);
+        key.verify_prehash(&digest, &signature).unwrap();
+    }
+
+    #[test]
+    fn serializes_the_bip143_unsigned_transaction() {
+        let v = bip143();
+        assert_eq!(
+            hex::encode(serialize_tx(&v.framing, &v.inputs, &v.outputs, None)),
+            BIP143_UNSIGNED
+        );
+    }
+
+    #[test]
+    fn witnesses_go_between_outputs_and_locktime() {
+        let v = bip143();
+        let witness = vec![bytes(BIP143_SIGNATURE), bytes(BIP143_PUBKEY)];
+        let signed = serialize_tx(&v.framing, &v.inputs, &v.outputs, Some(&[witness]));
+
+        let unsigned = bytes(BIP143_UNSIGNED);
+        let (body, lock_time) = unsigned.split_at(unsigned.len() - 4);
+        let mut expected = body[..4].to_vec();
+        expected.extend_from_slice(&[0x00, 0x01]);
+        expected.extend_from_slice(&body[4..]);
+        expected.push(0x02);
+        expected.push(0x47);
+        expected.extend_from_slice(&bytes(BIP143_SIGNATURE));
+        expected.push(0x21);
+        expected.extend_from_slice(&bytes(BIP143_PUBKEY));
+        expected.extend_from_slice(lock_time);
+        assert_eq!(signed, expected);
+    }
+
+    #[test]
+    fn standard_framing() {
+        let framing = Framing::standard(2);
+        assert_eq!(framing.version, 2);
+        assert_eq!(framing.sequences, vec![u32::MAX; 2]);
+        assert_eq!(framing.lock_time, 0);
+
+        let v = bip143();
+        let tx = serialize_tx(&framing, &v.inputs, &v.outputs, None);
+        assert_eq!(tx[..4], [2, 0, 0, 0]);
+        assert_eq!(tx[tx.len() - 4..], [0, 0, 0, 0]);
+        assert_eq!(tx[42..46], [0xFF; 4]);
+    }
+
+    #[test]
+    fn script_pubkeys_for_each_address_type() {
+        // BIP-173, BIP-350 and the hash160 of the compressed key 1
+        assert_eq!(
+            script_pubkey("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4").unwrap(),
+            bytes("0014751e76e8199196d454941c45d1b3a323f1433bd6")
+        );
+        assert_eq!(
+            script_pubkey("BC1QW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4").unwrap(),
+            bytes("0014751e76e8199196d454941c45d1b3a323f1433bd6")
+        );
+        assert_eq!(
+            script_pubkey("bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0")
+                .unwrap(),
+            bytes("512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798")
+        );
+        assert_eq!(
+            script_pubkey("1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH").unwrap(),
+            p2pkh("751e76e8199196d454941c45d1b3a323f1433bd6")
+        );
+        assert_eq!(
+            script_pubkey("3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy").unwrap(),
+            bytes("a914b472a266d0bd89c13706a4132ccfb16f7c3b9fcb87")
+        );
+    }
+
+    #[test]
+    fn rejects_other_networks_and_bad_checksums() {
+        for address in [
+            "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx",
+            "mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn",
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t5",
+            "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMJ",
+            "",
+        ] {
+            assert!(script_pubkey(address).is_err(), "{}", address);
+        }
+    }
+
+    #[test]
+    fn normalize_s_keeps_low_values() {
+        let mut s = HALF_CURVE_ORDER;
+        normalize_s(&mut s);
+        assert_eq!(s, HALF_CURVE_ORDER);
+
+        let mut s = [0u8; 32];
+        s[31] = 1;
+        normalize_s(&mut s);
+        assert_eq!(s[31], 1);
+    }
+
+    #[test]
+    fn normalize_s_flips_high_values() {
+        let mut s = CURVE_ORDER;
+        s[31] -= 1;
+        normalize_s(&mut s);
+        let mut one = [0u8; 32];
+        one[31] = 1;
+        assert_eq!(s, one);
+
+        // n is odd, so n - (n/2 + 1) is n/2
+        let mut s = HALF_CURVE_ORDER;
+        s[31] += 1;
+        normalize_s(&mut s);
+        assert_eq!(s, HALF_CURVE_ORDER);
+    }
+
+    #[test]
+    fn der_integers_are_minimal_and_positive() {
+        let encode = |value: &[u8]| {
+            let mut out = Vec::new();
+            der_integer(&mut out, value);
+            hex::encode(out)
+        };
+        assert_eq!(encode(&[0; 32]), "020100");
+        assert_eq!(encode(&[0, 0, 0x7F]), "02017f");
+        assert_eq!(encode(&[0, 0, 0x80]), "02020080");
+        assert_eq!(encode(&[0x12, 0x34]), "02021234");
+        assert_eq!(encode(&[0x00, 0xFF, 0x00]), "020300ff00");
+    }
+
+    #[test]
+    fn encodes_the_bip143_signature() {
+        let der = bytes(BIP143_SIGNATURE);
+        let r = bytes("47ac8e878352d3ebbde1c94ce3a10d057c24175747116f8288e5d794d12d482f");
+        let low_s = bytes("217f36a485cae903c713331d877c1f64677e3622ad4010726870540656fe9dcb");
+        let high_s = bytes("de80c95b7a3516fc38eccce27883e09a5330a6c402088fc957620a867937a376");
+
+        for s in [low_s, high_s] {
+            let mut signature = r.clone();
+            signature.extend_from_slice(&s);
+            assert_eq!(encode_signature(&signature), der);
+        }
+    }
+
+    #[test]
+    fn dust_shares_get_no_output() {
+        assert_eq!(output_value(0), None);
+        assert_eq!(output_value(BITCOIN_DUST_THRESHOLD - 1), None);
+        assert_eq!(
+            output_value(BITCOIN_DUST_THRESHOLD),
+            Some(BITCOIN_DUST_THRESHOLD)
+        );
+    }
+}
@@ -0,0 +1,315 @@
+use candid::Principal;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    helpers::{
+        check_is_anonymous, log_event, now, DOCUMENT_CHUNK_SIZE, MAX_DOCUMENTS_PER_USER,
+        MAX_DOCUMENT_ACCESS, MAX_DOCUMENT_SIZE, MAX_MIME_TYPE_LENGTH, MAX_NAME_LENGTH,
+        STORAGE_QUOTA_PER_USER,
+    },
+    storage::{self, get_asset, get_document, get_vault, insert_document},
+    types::{
+        ApiError, Document, DocumentLink, DocumentStatus, DocumentUpload, EventType, StorageUsage,
+        VaultStatus,
+    },
+};
+
+// Documents can't change once the vault is released
+fn check_vault_writable(owner: &Principal) -> Result<(), ApiError> {
+    let vault = get_vault(owner).ok_or(ApiError::VaultNotFound)?;
+    if vault.status == VaultStatus::Released {
+        return Err(ApiError::VaultReleased);
+    }
+    Ok(())
+}
+
+fn owned_document(owner: &Principal, document_id: u64) -> Result<Document, ApiError> {
+    let document = get_document(document_id).ok_or(ApiError::DocumentNotFound)?;
+    if document.owner != *owner {
+        return Err(ApiError::Unauthorized);
+    }
+    Ok(document)
+}
+
+fn validate_link(owner: &Principal, link: &DocumentLink) -> Result<(), ApiError> {
+    if let DocumentLink::Asset { asset_id } = link {
+        let asset = get_asset(*asset_id).ok_or(ApiError::AssetNotFound)?;
+        if asset.owner != *owner {
+            return Err(ApiError::Unauthorized);
+        }
+    }
+    Ok(())
+}
+
+fn validate_access(owner: &Principal, access: &[Principal]) -> Result<(), ApiError> {
+    if access.len() > MAX_DOCUMENT_ACCESS {
+        return Err(ApiError::validation(
+            "access",
+            format!("too many heirs (max {})", MAX_DOCUMENT_ACCESS),
+        ));
+    }
+
+    for (i, heir) in access.iter().enumerate() {
+        if check_is_anonymous(heir) {
+            return Err(ApiError::validation(
+                format!("access[{}]", i),
+                "anonymous principal cannot be an heir",
+            ));
+        }
+        if heir == owner {
+            return Err(ApiError::validation(
+                format!("access[{}]", i),
+                "owner cannot be their own heir",
+            ));
+        }
+        if access[..i].contains(heir) {
+            return Err(ApiError::validation(
+                format!("access[{}]", i),
+                format!("duplicate heir {}", heir.to_text()),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn chunk_len(document: &Document, index: u32) -> u64 {
+    if index + 1 == document.chunk_count {
+        document.size - index as u64 * DOCUMENT_CHUNK_SIZE
+    } else {
+        DOCUMENT_CHUNK_SIZE
+    }
+}
+
+pub fn storage_usage(owner: &Principal) -> StorageUsage {
+    let documents = storage::list_user_documents(owner);
+    StorageUsage {
+        used: documents.iter().map(|d| d.size).sum(),
+        quota: STORAGE_QUOTA_PER_USER,
+        documents: documents.len() as u32,
+    }
+}
+
+// The declared size counts against the quota from here on, until the document is deleted
+pub fn start_upload(owner: &Principal, upload: DocumentUpload) -> Result<u64, ApiError> {
+    check_vault_writable(owner)?;
+
+    if upload.name.is_empty() {
+        return Err(ApiError::validation("name", "cannot be empty"));
+    }
+    if upload.name.chars().count() > MAX_NAME_LENGTH {
+        return Err(ApiError::validation(
+            "name",
+            format!("too long (max {} characters)", MAX_NAME_LENGTH),
+        ));
+    }
+    if upload.mime_type.len() > MAX_MIME_TYPE_LENGTH {
+        return Err(ApiError::validation(
+            "mime_type",
+            format!("too long (max {} characters)", MAX_MIME_TYPE_LENGTH),
+        ));
+    }
+    if upload.size == 0 {
+        return Err(ApiError::validation("size", "must be greater than 0"));
+    }
+    if upload.size > MAX_DOCUMENT_SIZE {
+        return Err(ApiError::validation(
+            "size",
+            format!("too large (max {} bytes)", MAX_DOCUMENT_SIZE),
+        ));
+    }
+    validate_link(owner, &upload.link)?;
+    validate_access(owner, &upload.access)?;
+
+    let usage = storage_usage(owner);
+    if usage.documents as usize >= MAX_DOCUMENTS_PER_USER {
+        return Err(ApiError::validation(
+            "documents",
+            format!("too many documents (max {})", MAX_DOCUMENTS_PER_USER),
+        ));
+    }
+    if usage.used.saturating_add(upload.size) > usage.quota {
+        return Err(ApiError::StorageQuotaExceeded {
+            used: usage.used,
+            quota: usage.quota,
+        });
+    }
+
+    let id = storage::next_document_id();
+    insert_document(Document {
+        id,
+        owner: *owner,
+        name: upload.name,
+        mime_type: upload.mime_type,
+        size: upload.size,
+        chunk_count: upload.size.div_ceil(DOCUMENT_CHUNK_SIZE) as u32,
+        link: upload.link,
+        access: upload.access,
+        status: DocumentStatus::Uploading,
+        sha256: None,
+        created_at: now(),
+    });
+    Ok(id)
+}
+
+// Re-sending a chunk overwrites it, so a failed put can simply be retried
+pub fn put_chunk(
+    owner: &Principal,
+    document_id: u64,
+    index: u32,
+    bytes: Vec<u8>,
+) -> Result<(), ApiError> {
+    check_vault_writable(owner)?;
+    let document = owned_document(owner, document_id)?;
+    if document.status != DocumentStatus::Uploading {
+        return Err(ApiError::validation("document_id", "already committed"));
+    }
+
+    if index >= document.chunk_count {
+        return Err(ApiError::validation(
+            "index",
+            format!(
+                "out of range (document has {} chunks)",
+                document.chunk_count
+            ),
+        ));
+    }
+    let expected = chunk_len(&document, index);
+    if bytes.len() as u64 != expected {
+        return Err(ApiError::validation(
+            "chunk",
+            format!("must be {} bytes, got {}", expected, bytes.len()),
+        ));
+    }
+
+    storage::insert_document_chunk(document_id, index, bytes);
+    Ok(())
+}
+
+// Hashes the stored chunks and only commits when they match what the owner uploaded
+pub fn commit_upload(
+    owner: &Principal,
+    document_id: u64,
+    sha256: Vec<u8>,
+) -> Result<Document, ApiError> {
+    check_vault_writable(owner)?;
+    let mut document = owned_document(owner, document_id)?;
+    if document.status != DocumentStatus::Uploading {
+        return Err(ApiError::validation("document_id", "already committed"));
+    }
+
+    let present = storage::list_document_chunk_indexes(document_id);
+    if present.len() != document.chunk_count as usize {
+        let missing: Vec<String> = (0..document.chunk_count)
+            .filter(|i| !present.contains(i))
+            .take(10)
+            .map(|i| i.to_string())
+            .collect();
+        return Err(ApiError::validation(
+            "chunks",
+            format!("missing chunks {}", missing.join(", ")),
+        ));
+    }
+
+    let mut hasher = Sha256::new();
+    for index in 0..document.chunk_count {
+        if let Some(chunk) = storage::get_document_chunk(document_id, index) {
+            hasher.update(&chunk);
+        }
+    }
+    let actual = hasher.finalize().to_vec();
+    if actual != sha256 {
+        return Err(ApiError::validation(
+            "sha256",
+            format!("content hashes to {}", hex::encode(&actual)),
+        ));
+    }
+
+    document.status = DocumentStatus::Committed;
+    document.sha256 = Some(actual.clone());
+    insert_document(document.clone());
+
+    log_event(
+        EventType::DocumentUploaded {
+            document_id,
+            name: document.name.clone(),
+            size: document.size,
+            sha256: actual,
+        },
+        owner,
+    );
+
+    Ok(document)
+}
+
+pub fn delete_document(owner: &Principal, document_id: u64) -> Result<(), ApiError> {
+    check_vault_writable(owner)?;
+    owned_document(owner, document_id)?;
+
+    storage::remove_document(document_id);
+
+    log_event(EventType::DocumentRemoved { document_id }, owner);
+    Ok(())
+}
+
+pub fn set_access(
+    owner: &Principal,
+    document_id: u64,
+    access: Vec<Principal>,
+) -> Result<(), ApiError> {
+    check_vault_writable(owner)?;
+    let mut document = owned_document(owner, document_id)?;
+    validate_access(owner, &access)?;
+    if document.access == access {
+        return Ok(());
+    }
+
+    document.access = access.clone();
+    insert_document(document);
+
+    log_event(
+        EventType::DocumentAccessChanged {
+            document_id,
+            access,
+        },
+        owner,
+    );
+    Ok(())
+}
+
+// Documents of a removed asset stay with the vault
+pub fn detach_asset(owner: &Principal, asset_id: u64) {
+    for mut document in storage::list_user_documents(owner) {
+        if document.link == (DocumentLink::Asset { asset_id }) {
+            document.link = DocumentLink::Vault;
+            insert_document(document);
+        }
+    }
+}
+
+fn heir_can_read(heir: &Principal, document: &Document) -> bool {
+    document.status == DocumentStatus::Committed
+        && document.access.contains(heir)
+        && get_vault(&document.owner).is_some_and(|v| v.status == VaultStatus::Released)
+}
+
+pub fn list_inherited(heir: &Principal) -> Vec<Document> {
+    storage::list_heir_documents(heir)
+        .into_iter()
+        .filter(|document| heir_can_read(heir, document))
+        .collect()
+}
+
+// The owner can read back their own uploads at any time, heirs only after release
+pub fn read_chunk(caller: &Principal, document_id: u64, index: u32) -> Result<Vec<u8>, ApiError> {
+    let document = get_document(document_id).ok_or(ApiError::DocumentNotFound)?;
+    if document.owner != *caller && !heir_can_read(caller, &document) {
+        return Err(ApiError::Unauthorized);
+    }
+
+    storage::get_document_chunk(document_id, index).ok_or_else(|| {
+        ApiError::validation(
+            "index",
+            format!("no chunk {} in document {}", index, document_id),
+        )
+    })
+}
@@ -0,0 +1,345 @@
+use std::{cell::RefCell, collections::BTreeSet};
+
+use candid::{Nat, Principal};
+use ic_cdk::call::Call;
+use icrc_ledger_types::icrc1::{
+    account::{Account, Subaccount, DEFAULT_SUBACCOUNT},
+    transfer::{Memo, TransferArg},
+};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    helpers::{log_event, now},
+    release::{self, AttemptError},
+    storage::{get_asset, get_vault, insert_asset, list_user_assets, update_vault},
+    types::{
+        ApiError, Asset, AssetType, EscrowAccount, EventType, IcpTimeStamp, IcpTransferArgs,
+        Tokens, Vault, VaultStatus,
+    },
+    vault,
+};
+
+const SUBACCOUNT_DOMAIN: &[u8] = b"inheritnext-escrow";
+
+thread_local! {
+    // Subaccounts with a deposit sync or withdrawal waiting on a ledger
+    static BUSY: RefCell<BTreeSet<Subaccount>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+// Releases the subaccount however the call ends, including a trap after an await
+struct EscrowGuard(Subaccount);
+
+impl EscrowGuard {
+    fn acquire(subaccount: Subaccount) -> Result<Self, ApiError> {
+        if !BUSY.with(|b| b.borrow_mut().insert(subaccount)) {
+            return Err(ApiError::EscrowBusy);
+        }
+        Ok(EscrowGuard(subaccount))
+    }
+}
+
+impl Drop for EscrowGuard {
+    fn drop(&mut self) {
+        BUSY.with(|b| b.borrow_mut().remove(&self.0));
+    }
+}
+
+fn derive_subaccount(owner: &Principal, created_at: u64) -> Subaccount {
+    let mut hasher = Sha256::new();
+    hasher.update(SUBACCOUNT_DOMAIN);
+    hasher.update([owner.as_slice().len() as u8]);
+    hasher.update(owner.as_slice());
+    hasher.update(created_at.to_be_bytes());
+    hasher.finalize().into()
+}
+
+// Vaults that never escrowed anything get the subaccount their current owner would derive
+pub fn vault_subaccount(vault: &Vault) -> Subaccount {
+    vault
+        .escrow_subaccount
+        .unwrap_or_else(|| derive_subaccount(&vault.owner, vault.created_at))
+}
+
+// Pins the subaccount on the vault so a later ownership transfer keeps pointing at it
+pub fn pin_vault_subaccount(owner: &Principal) -> Result<Subaccount, ApiError> {
+    update_vault(owner, |vault| {
+        let subaccount = vault_subaccount(vault);
+        vault.escrow_subaccount = Some(subaccount);
+        Ok(subaccount)
+    })
+}
+
+// Ledger and credited balance of assets held in the vault's subaccount
+fn escrowed(asset_type: &AssetType) -> Option<(Principal, u64)> {
+    match asset_type {
+        AssetType::ICRC1Escrow {
+            ledger_canister,
+            amount,
+        }
+        | AssetType::ICPLedger {
+            ledger_canister,
+            amount,
+        } => Some((*ledger_canister, *amount)),
+        AssetType::ICRC2Token { .. }
+        | AssetType::ICRC7Nft { .. }
+        | AssetType::CanisterControl { .. }
+        | AssetType::Callback { .. }
+        | AssetType::EncryptedSecret { .. }
+        | AssetType::BitcoinAddress { .. }
+        | AssetType::EvmAddress { .. } => None,
+    }
+}
+
+// Everything a vault escrows sits in one subaccount, so a ledger's balance there can only be
+// attributed to an asset if no other escrow asset of the vault uses that ledger
+pub fn ensure_sole_escrow_on_ledger(
+    owner: &Principal,
+    asset_id: Option<u64>,
+    asset_type: &AssetType,
+) -> Result<(), ApiError> {
+    let Some((ledger_canister, _)) = escrowed(asset_type) else {
+        return Ok(());
+    };
+    let shared = list_user_assets(owner).iter().any(|other| {
+        Some(other.id) != asset_id
+            && escrowed(&other.asset_type).is_some_and(|(ledger, _)| ledger == ledger_canister)
+    });
+    if shared {
+        return Err(ApiError::validation(
+            "asset_type",
+            "another escrow asset of this vault already holds tokens of this ledger",
+        ));
+    }
+    Ok(())
+}
+
+fn is_legacy_icp(asset: &Asset) -> bool {
+    matches!(asset.asset_type, AssetType::ICPLedger { .. })
+}
+
+fn escrow_asset(owner: &Principal, asset_id: u64) -> Result<(Asset, Principal, u64), ApiError> {
+    let asset = get_asset(asset_id).ok_or(ApiError::AssetNotFound)?;
+    if asset.owner != *owner {
+        return Err(ApiError::Unauthorized);
+    }
+
+    let (ledger_canister, amount) = escrowed(&asset.asset_type)
+        .ok_or_else(|| ApiError::validation("asset_id", "not an escrow asset"))?;
+    Ok((asset, ledger_canister, amount))
+}
+
+fn set_escrowed_amount(asset: &mut Asset, new_amount: u64) {
+    if let AssetType::ICRC1Escrow { amount, .. } | AssetType::ICPLedger { amount, .. } =
+        &mut asset.asset_type
+    {
+        *amount = new_amount;
+    }
+}
+
+fn escrow_account(subaccount: Subaccount) -> Account {
+    Account {
+        owner: ic_cdk::api::canister_self(),
+        subaccount: Some(subaccount),
+    }
+}
+
+// Where the owner sends tokens for this asset. Every escrow asset of a vault shares the
+// account, but on a ledger of its own, so `sync_escrow_deposit` can tell deposits apart.
+pub fn get_escrow_account(owner: &Principal, asset_id: u64) -> Result<EscrowAccount, ApiError> {
+    escrow_asset(owner, asset_id)?;
+    let subaccount = pin_vault_subaccount(owner)?;
+    let account = escrow_account(subaccount);
+    Ok(EscrowAccount {
+        account_identifier: hex::encode(vault::account_identifier(&account.owner, &subaccount)),
+        account,
+    })
+}
+
+// Credits whatever the subaccount holds on the asset's ledger beyond what the asset already
+// accounts for. Returns the amount credited.
+pub async fn sync_escrow_deposit(owner: &Principal, asset_id: u64) -> Result<u64, ApiError> {
+    let (asset, ledger_canister, _) = escrow_asset(owner, asset_id)?;
+    // Vaults from before the one-asset-per-ledger rule may still share one
+    ensure_sole_escrow_on_ledger(owner, Some(asset_id), &asset.asset_type)?;
+    let vault = get_vault(owner).ok_or(ApiError::VaultNotFound)?;
+    if vault.status == VaultStatus::Released {
+        return Err(ApiError::VaultReleased);
+    }
+
+    let subaccount = pin_vault_subaccount(owner)?;
+    let _guard = EscrowGuard::acquire(subaccount)?;
+
+    let account = escrow_account(subaccount);
+    let balance = if is_legacy_icp(&asset) {
+        let id = vault::account_identifier(&account.owner, &subaccount);
+        vault::icp_account_balance(&ledger_canister, id).await?
+    } else {
+        icrc1_balance_of(&ledger_canister, account).await?
+    };
+
+    // Re-read everything, the vault may have been released or recovered meanwhile
+    let (mut asset, _, amount) = escrow_asset(owner, asset_id)?;
+    if get_vault(owner).is_none_or(|v| v.status == VaultStatus::Released) {
+        return Err(ApiError::VaultReleased);
+    }
+    ensure_sole_escrow_on_ledger(owner, Some(asset_id), &asset.asset_type)?;
+
+    let credit = balance.saturating_sub(amount);
+    if credit == 0 {
+        return Ok(0);
+    }
+
+    set_escrowed_amount(&mut asset, amount + credit);
+    insert_asset(asset);
+
+    log_event(
+        EventType::EscrowDeposited {
+            asset_id,
+            ledger_canister,
+            amount: credit,
+        },
+        owner,
+    );
+
+    Ok(credit)
+}
+
+// Sends `amount` back to the owner's default account. The ledger fee comes on top and is
+// taken from the escrowed balance too. Returns the block index.
+pub async fn withdraw_escrow(
+    owner: &Principal,
+    asset_id: u64,
+    amount: u64,
+) -> Result<Nat, ApiError> {
+    if amount == 0 {
+        return Err(ApiError::validation("amount", "must be greater than 0"));
+    }
+
+    let (asset, ledger_canister, _) = escrow_asset(owner, asset_id)?;
+    let legacy = is_legacy_icp(&asset);
+    let vault = get_vault(owner).ok_or(ApiError::VaultNotFound)?;
+    match vault.status {
+        VaultStatus::Active => {}
+        VaultStatus::Released => return Err(ApiError::VaultReleased),
+        _ => return Err(ApiError::VaultNotActive),
+    }
+
+    let subaccount = vault_subaccount(&vault);
+    let _guard = EscrowGuard::acquire(subaccount)?;
+
+    let fee = if legacy {
+        vault::icp_transfer_fee(&ledger_canister).await
+    } else {
+        release::fetch_icrc1_fee(&ledger_canister).await
+    }
+    .map_err(|reason| ApiError::LedgerCallFailed {
+        ledger: ledger_canister,
+        reason,
+    })?;
+
+    let (mut asset, _, balance) = escrow_asset(owner, asset_id)?;
+    if get_vault(owner).is_none_or(|v| v.status != VaultStatus::Active) {
+        return Err(ApiError::VaultNotActive);
+    }
+
+    let total = amount.saturating_add(fee);
+    if total > balance {
+        return Err(ApiError::validation(
+            "amount",
+            format!(
+                "{} plus ledger fee {} exceeds the escrowed balance {}",
+                amount, fee, balance
+            ),
+        ));
+    }
+
+    // Reserved before the transfer so a payout or a second withdrawal can't spend it too
+    set_escrowed_amount(&mut asset, balance - total);
+    insert_asset(asset);
+
+    let result = if legacy {
+        let args = IcpTransferArgs {
+            memo: asset_id,
+            amount: Tokens { e8s: amount },
+            fee: Tokens { e8s: fee },
+            from_subaccount: Some(subaccount),
+            to: vault::account_identifier(owner, DEFAULT_SUBACCOUNT),
+            created_at_time: Some(IcpTimeStamp {
+                timestamp_nanos: now(),
+            }),
+        };
+        release::icp_transfer(&ledger_canister, args)
+            .await
+            .map(|r| r.map(Nat::from).map_err(|e| format!("{:?}", e)))
+    } else {
+        let args = TransferArg {
+            from_subaccount: Some(subaccount),
+            to: Account {
+                owner: *owner,
+                subaccount: None,
+            },
+            fee: Some(Nat::from(fee)),
+            created_at_time: Some(now()),
+            memo: Some(Memo::from(asset_id)),
+            amount: Nat::from(amount),
+        };
+        release::icrc1_transfer(&ledger_canister, args)
+            .await
+            .map(|r| r.map_err(|e| e.to_string()))
+    };
+
+    let reason = match result {
+        Ok(Ok(block_index)) => {
+            log_event(
+                EventType::EscrowWithdrawn {
+                    asset_id,
+                    ledger_canister,
+                    amount,
+                    fee,
+                    block_index: block_index.clone(),
+                },
+                owner,
+            );
+            return Ok(block_index);
+        }
+        Ok(Err(reason)) => reason,
+        Err(AttemptError::Clean(reason)) => reason,
+        // The tokens may have left. If they didn't, the next deposit sync credits them back to
+        // this asset, the only one on the ledger.
+        Err(AttemptError::Unknown(reason)) => {
+            return Err(ApiError::LedgerCallFailed {
+                ledger: ledger_canister,
+                reason,
+            })
+        }
+    };
+
+    // Nothing left the subaccount, give the reservation back
+    if let Some(mut asset) = get_asset(asset_id) {
+        if let Some((_, current)) = escrowed(&asset.asset_type) {
+            set_escrowed_amount(&mut asset, current + total);
+            insert_asset(asset);
+        }
+    }
+
+    Err(ApiError::LedgerCallFailed {
+        ledger: ledger_canister,
+        reason,
+    })
+}
+
+async fn icrc1_balance_of(ledger_canister: &Principal, account: Account) -> Result<u64, ApiError> {
+    let failed = |reason: String| ApiError::LedgerCallFailed {
+        ledger: *ledger_canister,
+        reason,
+    };
+
+    let balance: Nat = Call::unbounded_wait(*ledger_canister, "icrc1_balance_of")
+        .with_arg(account)
+        .await
+        .map_err(|e| failed(format!("icrc1_balance_of call failed: {:?}", e)))?
+        .candid()
+        .map_err(|e| failed(format!("Failed to decode icrc1_balance_of: {:?}", e)))?;
+
+    u64::try_from(balance.0).map_err(|_| failed("Balance does not fit in u64".to_string()))
+}
@@ -0,0 +1,662 @@
+use candid::{Nat, Principal};
+use ic_cdk::management_canister::{sign_with_ecdsa, SignWithEcdsaArgs};
+use icrc_ledger_types::icrc2::transfer_from::TransferFromError;
+use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use sha3::{Digest, Keccak256};
+
+use crate::{
+    bitcoin,
+    helpers::{log_event, ERC20_TRANSFER_GAS, EVM_TRANSFER_GAS},
+    release::AttemptError,
+    storage::{self, get_asset, get_vault, insert_asset, insert_release_job},
+    types::{ApiError, AssetType, EventType, EvmTransfer, ReleaseJob, VaultStatus},
+};
+
+const EIP1559_TX_TYPE: u8 = 0x02;
+// transfer(address,uint256)
+const ERC20_TRANSFER_SELECTOR: [u8; 4] = [0xA9, 0x05, 0x9C, 0xBB];
+
+fn keccak256(bytes: &[u8]) -> [u8; 32] {
+    Keccak256::digest(bytes).into()
+}
+
+// EIP-55 mixed-case checksum encoding
+pub fn checksum_address(address: &[u8; 20]) -> String {
+    let lower = hex::encode(address);
+    let hash = keccak256(lower.as_bytes());
+
+    let mut out = String::with_capacity(42);
+    out.push_str("0x");
+    for (i, c) in lower.chars().enumerate() {
+        let nibble = (hash[i / 2] >> if i % 2 == 0 { 4 } else { 0 }) & 0x0F;
+        if c.is_ascii_alphabetic() && nibble >= 8 {
+            out.push(c.to_ascii_uppercase());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+// All-lowercase and all-uppercase addresses carry no checksum, mixed case must match EIP-55
+pub fn parse_address(text: &str) -> Result<[u8; 20], String> {
+    let digits = text
+        .strip_prefix("0x")
+        .ok_or_else(|| "must start with 0x".to_string())?;
+    if digits.len() != 40 {
+        return Err("must be 20 bytes of hex".to_string());
+    }
+    let mut address = [0u8; 20];
+    hex::decode_to_slice(digits, &mut address).map_err(|_| "not valid hex".to_string())?;
+
+    let has_lower = digits.chars().any(|c| c.is_ascii_lowercase());
+    let has_upper = digits.chars().any(|c| c.is_ascii_uppercase());
+    if has_lower && has_upper && checksum_address(&address) != text {
+        return Err("EIP-55 checksum does not match".to_string());
+    }
+    Ok(address)
+}
+
+pub fn derivation_path(owner: &Principal, asset_id: u64) -> Vec<Vec<u8>> {
+    vec![
+        b"evm".to_vec(),
+        owner.as_slice().to_vec(),
+        asset_id.to_be_bytes().to_vec(),
+    ]
+}
+
+fn address_of(key: &VerifyingKey) -> [u8; 20] {
+    let point = key.to_encoded_point(false);
+    let hash = keccak256(&point.as_bytes()[1..]);
+    let mut address = [0u8; 20];
+    address.copy_from_slice(&hash[12..]);
+    address
+}
+
+async fn verifying_key(derivation_path: &[Vec<u8>]) -> Result<VerifyingKey, String> {
+    let public_key = bitcoin::public_key(derivation_path).await?;
+    VerifyingKey::from_sec1_bytes(&public_key)
+        .map_err(|e| format!("Invalid public key from ecdsa_public_key: {}", e))
+}
+
+// Like Bitcoin addresses, the path is fixed here and survives an ownership transfer
+pub async fn derive_address(
+    owner: &Principal,
+    asset_id: u64,
+    asset_type: AssetType,
+) -> Result<AssetType, ApiError> {
+    let AssetType::EvmAddress {
+        chain_id,
+        token,
+        amount,
+        max_fee_per_gas,
+        max_priority_fee_per_gas,
+        ..
+    } = asset_type
+    else {
+        return Ok(asset_type);
+    };
+
+    let derivation_path = derivation_path(owner, asset_id);
+    let key = verifying_key(&derivation_path)
+        .await
+        .map_err(|reason| ApiError::KeyDerivationFailed { reason })?;
+    let address = checksum_address(&address_of(&key));
+
+    log_event(
+        EventType::EvmAddressDerived {
+            asset_id,
+            address: address.clone(),
+        },
+        owner,
+    );
+
+    Ok(AssetType::EvmAddress {
+        address,
+        derivation_path,
+        next_nonce: 0,
+        chain_id,
+        token,
+        amount,
+        max_fee_per_gas,
+        max_priority_fee_per_gas,
+    })
+}
+
+fn rlp_length(out: &mut Vec<u8>, offset: u8, len: usize) {
+    if len < 56 {
+        out.push(offset + len as u8);
+    } else {
+        let be = len.to_be_bytes();
+        let skip = be.iter().take_while(|b| **b == 0).count();
+        out.push(offset + 55 + (be.len() - skip) as u8);
+        out.extend_from_slice(&be[skip..]);
+    }
+}
+
+fn rlp_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    if bytes.len() == 1 && bytes[0] < 0x80 {
+        out.push(bytes[0]);
+    } else {
+        rlp_length(out, 0x80, bytes.len());
+        out.extend_from_slice(bytes);
+    }
+}
+
+// Integers are big-endian without leading zeros, so 0 is the empty string
+fn rlp_uint(out: &mut Vec<u8>, value: &[u8]) {
+    let skip = value.iter().take_while(|b| **b == 0).count();
+    rlp_bytes(out, &value[skip..]);
+}
+
+struct Eip1559Tx {
+    chain_id: u64,
+    nonce: u64,
+    max_priority_fee_per_gas: u128,
+    max_fee_per_gas: u128,
+    gas_limit: u64,
+    to: [u8; 20],
+    value: u128,
+    data: Vec<u8>,
+}
+
+impl Eip1559Tx {
+    // The signing payload without a signature, the raw transaction with one
+    fn encode(&self, signature: Option<(u8, &[u8], &[u8])>) -> Vec<u8> {
+        let mut fields = Vec::new();
+        rlp_uint(&mut fields, &self.chain_id.to_be_bytes());
+        rlp_uint(&mut fields, &self.nonce.to_be_bytes());
+        rlp_uint(&mut fields, &self.max_priority_fee_per_gas.to_be_bytes());
+        rlp_uint(&mut fields, &self.max_fee_per_gas.to_be_bytes());
+        rlp_uint(&mut fields, &self.gas_limit.to_be_bytes());
+        rlp_bytes(&mut fields, &self.to);
+        rlp_uint(&mut fields, &self.value.to_be_bytes());
+        rlp_bytes(&mut fields, &self.data);
+        // Empty access list
+        fields.push(0xC0);
+        if let Some((y_parity, r, s)) = signature {
+            rlp_uint(&mut fields, &[y_parity]);
+            rlp_uint(&mut fields, r);
+            rlp_uint(&mut fields, s);
+        }
+
+        let mut out = vec![EIP1559_TX_TYPE];
+        rlp_length(&mut out, 0xC0, fields.len());
+        out.extend_from_slice(&fields);
+        out
+    }
+}
+
+fn erc20_transfer_data(to: &[u8; 20], value: u128) -> Vec<u8> {
+    let mut data = ERC20_TRANSFER_SELECTOR.to_vec();
+    data.extend_from_slice(&[0u8; 12]);
+    data.extend_from_slice(to);
+    data.extend_from_slice(&[0u8; 16]);
+    data.extend_from_slice(&value.to_be_bytes());
+    data
+}
+
+// Most a single transfer from this asset can spend on gas, in wei
+pub fn max_gas_cost(asset_type: &AssetType) -> u128 {
+    match asset_type {
+        AssetType::EvmAddress {
+            token: None,
+            max_fee_per_gas,
+            ..
+        } => EVM_TRANSFER_GAS as u128 * max_fee_per_gas,
+        AssetType::EvmAddress {
+            token: Some(_),
+            max_fee_per_gas,
+            ..
+        } => ERC20_TRANSFER_GAS as u128 * max_fee_per_gas,
+        _ => 0,
+    }
+}
+
+// Signs a transfer of `value` from the asset's address, native or ERC-20 depending on the
+// asset. Returns the raw transaction and its hash.
+async fn sign_transfer(
+    asset_type: &AssetType,
+    to: &str,
+    nonce: u64,
+    value: u128,
+) -> Result<(Vec<u8>, String), String> {
+    let AssetType::EvmAddress {
+        derivation_path,
+        chain_id,
+        token,
+        max_fee_per_gas,
+        max_priority_fee_per_gas,
+        ..
+    } = asset_type
+    else {
+        return Err("Asset is not an EVM address".to_string());
+    };
+    let to = parse_address(to).map_err(|reason| format!("Invalid address {}: {}", to, reason))?;
+
+    let tx = match token {
+        None => Eip1559Tx {
+            chain_id: *chain_id,
+            nonce,
+            max_priority_fee_per_gas: *max_priority_fee_per_gas,
+            max_fee_per_gas: *max_fee_per_gas,
+            gas_limit: EVM_TRANSFER_GAS,
+            to,
+            value,
+            data: Vec::new(),
+        },
+        Some(contract) => Eip1559Tx {
+            chain_id: *chain_id,
+            nonce,
+            max_priority_fee_per_gas: *max_priority_fee_per_gas,
+            max_fee_per_gas: *max_fee_per_gas,
+            gas_limit: ERC20_TRANSFER_GAS,
+            to: parse_address(contract)
+                .map_err(|reason| format!("Invalid token {}: {}", contract, reason))?,
+            value: 0,
+            data: erc20_transfer_data(&to, value),
+        },
+    };
+
+    let key = verifying_key(derivation_path).await?;
+    let hash = keccak256(&tx.encode(None));
+    let args = SignWithEcdsaArgs {
+        message_hash: hash.to_vec(),
+        derivation_path: derivation_path.clone(),
+        key_id: bitcoin::key_id(),
+    };
+    let signature = sign_with_ecdsa(&args)
+        .await
+        .map_err(|e| format!("sign_with_ecdsa failed: {:?}", e))?
+        .signature;
+    let signature = Signature::from_slice(&signature)
+        .map_err(|_| "sign_with_ecdsa returned a malformed signature".to_string())?;
+
+    // Ethereum only accepts low-S signatures, and the recovery id has to be found by trying
+    let signature = signature.normalize_s().unwrap_or(signature);
+    let y_parity = (0..2u8)
+        .find(|&id| {
+            RecoveryId::from_byte(id)
+                .and_then(|id| VerifyingKey::recover_from_prehash(&hash, &signature, id).ok())
+                == Some(key)
+        })
+        .ok_or_else(|| "Could not recover the signing key".to_string())?;
+
+    let (r, s) = signature.split_bytes();
+    let raw = tx.encode(Some((y_parity, &r, &s)));
+    let tx_hash = format!("0x{}", hex::encode(keccak256(&raw)));
+    Ok((raw, tx_hash))
+}
+
+fn permanent(message: String) -> Result<Result<Nat, TransferFromError>, AttemptError> {
+    Ok(Err(TransferFromError::GenericError {
+        error_code: Nat::from(0u64),
+        message,
+    }))
+}
+
+// Nothing is sent from here. The signed transaction is kept on the job for the heir or a
+// relayer to broadcast, and a retry after it was stored hands back the same one.
+pub async fn sign_share(
+    job: &mut ReleaseJob,
+) -> Result<Result<Nat, TransferFromError>, AttemptError> {
+    let Some(transfer) = job.evm_transfer.clone() else {
+        return permanent("Not an EVM payout".to_string());
+    };
+    if transfer.signed_tx.is_some() {
+        return Ok(Ok(Nat::from(0u64)));
+    }
+
+    let Some(asset) = get_asset(job.asset_id) else {
+        return permanent("Asset no longer exists".to_string());
+    };
+    if parse_address(&transfer.to).is_err() {
+        return permanent(format!("Invalid EVM address {}", transfer.to));
+    }
+    let (raw, tx_hash) = sign_transfer(
+        &asset.asset_type,
+        &transfer.to,
+        transfer.nonce,
+        transfer.value,
+    )
+    .await
+    .map_err(AttemptError::Clean)?;
+
+    job.evm_transfer = Some(EvmTransfer {
+        signed_tx: Some(raw),
+        tx_hash: Some(tx_hash.clone()),
+        ..transfer
+    });
+    insert_release_job(job.clone());
+
+    log_event(
+        EventType::EvmTransactionSigned {
+            asset_id: job.asset_id,
+            heir: job.heir,
+            nonce: transfer.nonce,
+            tx_hash,
+        },
+        &job.owner,
+    );
+
+    Ok(Ok(Nat::from(0u64)))
+}
+
+// The owner's way to take funds back before release. The next nonce is only used up once
+// the transaction is signed, and the payouts continue from there, so it has to be
+// broadcast before the vault is released.
+pub async fn sign_withdrawal(
+    owner: &Principal,
+    asset_id: u64,
+    to: String,
+    value: u128,
+) -> Result<EvmTransfer, ApiError> {
+    if value == 0 {
+        return Err(ApiError::validation("value", "must be greater than 0"));
+    }
+    parse_address(&to).map_err(|reason| ApiError::validation("to", reason))?;
+
+    let asset = get_asset(asset_id).ok_or(ApiError::AssetNotFound)?;
+    if asset.owner != *owner {
+        return Err(ApiError::Unauthorized);
+    }
+    let AssetType::EvmAddress {
+        amount, next_nonce, ..
+    } = asset.asset_type
+    else {
+        return Err(ApiError::validation("asset_id", "not an EVM address"));
+    };
+    let vault = get_vault(owner).ok_or(ApiError::VaultNotFound)?;
+    match vault.status {
+        VaultStatus::Active => {}
+        VaultStatus::Released => return Err(ApiError::VaultReleased),
+        _ => return Err(ApiError::VaultNotActive),
+    }
+
+    // Native ETH pays for its own gas
+    let spent = match &asset.asset_type {
+        AssetType::EvmAddress { token: None, .. } => {
+            value.saturating_add(max_gas_cost(&asset.asset_type))
+        }
+        _ => value,
+    };
+    if spent > amount {
+        return Err(ApiError::validation(
+            "value",
+            format!("{} with gas exceeds the declared amount {}", spent, amount),
+        ));
+    }
+
+    let (raw, tx_hash) = sign_transfer(&asset.asset_type, &to, next_nonce, value)
+        .await
+        .map_err(|reason| ApiError::KeyDerivationFailed { reason })?;
+
+    // A second withdrawal signed meanwhile took this nonce
+    let mut asset_now = get_asset(asset_id).ok_or(ApiError::AssetNotFound)?;
+    if asset_now != asset {
+        return Err(ApiError::ConcurrentModification);
+    }
+    if get_vault(owner).is_none_or(|v| v.status != VaultStatus::Active) {
+        return Err(ApiError::VaultNotActive);
+    }
+    if let AssetType::EvmAddress {
+        amount, next_nonce, ..
+    } = &mut asset_now.asset_type
+    {
+        *amount -= spent;
+        *next_nonce += 1;
+    }
+    insert_asset(asset_now);
+
+    log_event(
+        EventType::EvmWithdrawalSigned {
+            asset_id,
+            to: to.clone(),
+            value,
+            nonce: next_nonce,
+            tx_hash: tx_hash.clone(),
+        },
+        owner,
+    );
+
+    Ok(EvmTransfer {
+        to,
+        nonce: next_nonce,
+        value,
+        signed_tx: Some(raw),
+        tx_hash: Some(tx_hash),
+    })
+}
+
+// Every payout of the asset in nonce order. Later nonces only go through once the earlier
+// ones are mined, so whoever relays them needs the whole list.
+pub fn list_transactions(caller: &Principal, asset_id: u64) -> Result<Vec<EvmTransfer>, ApiError> {
+    let asset = get_asset(asset_id).ok_or(ApiError::AssetNotFound)?;
+    if !matches!(asset.asset_type, AssetType::EvmAddress { .. }) {
+        return Err(ApiError::validation("asset_id", "not an EVM address"));
+    }
+
+    let is_heir = asset
+        .heir_assingment
+        .iter()
+        .any(|h| h.heir_principal == *caller);
+    if asset.owner != *caller && !is_heir {
+        return Err(ApiError::Unauthorized);
+    }
+
+    let vault = get_vault(&asset.owner).ok_or(ApiError::VaultNotFound)?;
+    if vault.status != VaultStatus::Released {
+        return Err(ApiError::VaultNotReleased);
+    }
+
+    let mut transfers: Vec<EvmTransfer> = storage::list_owner_release_jobs(&asset.owner)
+        .into_iter()
+        .filter(|job| job.asset_id == asset_id)
+        .filter_map(|job| job.evm_transfer)
+        .collect();
+    transfers.sort_by_key(|t| t.nonce);
+    Ok(transfers)
+}
+
+#[cfg(test)]
+mod tests {
+    use k256::ecdsa::SigningKey;
+
+    use super::*;
+
+    // The mixed-case examples of EIP-55, plus its all-caps ones whose checksum happens to
+    // uppercase every letter
+    const EIP55: [&str; 6] = [
+        "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+        "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359",
+        "0xdbF03B407c01E7cD3CBea99509d93f8DDDC8C6FB",
+        "0xD1220A0cf47c7B9Be7A2E6BA89F429762e7b9aDb",
+        "0x52908400098527886E0F7030069857D2E4169EE7",
+        "0x8617E340B3D01FA5F11F306F4090FD50E238070D",
+    ];
+
+    fn rlp(f: impl FnOnce(&mut Vec<u8>)) -> String {
+        let mut out = Vec::new();
+        f(&mut out);
+        hex::encode(out)
+    }
+
+    fn to() -> [u8; 20] {
+        parse_address(EIP55[0]).unwrap()
+    }
+
+    fn native_transfer() -> Eip1559Tx {
+        Eip1559Tx {
+            chain_id: 1,
+            nonce: 0,
+            max_priority_fee_per_gas: 1_000_000_000,
+            max_fee_per_gas: 30_000_000_000,
+            gas_limit: EVM_TRANSFER_GAS,
+            to: to(),
+            value: 1_000_000_000_000_000_000,
+            data: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn checksums_match_eip55() {
+        for address in EIP55 {
+            let bytes: [u8; 20] = hex::decode(&address[2..]).unwrap().try_into().unwrap();
+            assert_eq!(checksum_address(&bytes), address);
+        }
+    }
+
+    #[test]
+    fn derives_the_address_of_key_one() {
+        let mut secret = [0u8; 32];
+        secret[31] = 1;
+        let key = SigningKey::from_slice(&secret).unwrap();
+        assert_eq!(
+            checksum_address(&address_of(key.verifying_key())),
+            "0x7E5F4552091A69125d5DfCb7b8C2659029395Bdf"
+        );
+    }
+
+    #[test]
+    fn parses_checksummed_and_single_case_addresses() {
+        for address in EIP55 {
+            let expected = hex::decode(&address[2..]).unwrap();
+            assert_eq!(parse_address(address).unwrap().to_vec(), expected);
+            let lower = format!("0x{}", address[2..].to_ascii_lowercase());
+            assert_eq!(parse_address(&lower).unwrap().to_vec(), expected);
+            let upper = format!("0x{}", address[2..].to_ascii_uppercase());
+            assert_eq!(parse_address(&upper).unwrap().to_vec(), expected);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_checksums_and_shapes() {
+        for address in [
+            // One letter of an EIP-55 example with its case flipped
+            "0x5AAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+            "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d35A",
+            "5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+            "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeA",
+            "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAedaa",
+            "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAeg",
+            "0x",
+        ] {
+            assert!(parse_address(address).is_err(), "{}", address);
+        }
+    }
+
+    #[test]
+    fn rlp_strings() {
+        // The examples of the Ethereum RLP specification
+        assert_eq!(rlp(|out| rlp_bytes(out, b"dog")), "83646f67");
+        assert_eq!(rlp(|out| rlp_bytes(out, b"")), "80");
+        assert_eq!(rlp(|out| rlp_bytes(out, &[0x0F])), "0f");
+        assert_eq!(rlp(|out| rlp_bytes(out, &[0x80])), "8180");
+        assert_eq!(rlp(|out| rlp_bytes(out, &[0x04, 0x00])), "820400");
+
+        let lorem = b"Lorem ipsum dolor sit amet, consectetur adipisicing elit";
+        assert_eq!(
+            rlp(|out| rlp_bytes(out, lorem)),
+            format!("b838{}", hex::encode(lorem))
+        );
+        let long = [0xAB; 1024];
+        assert_eq!(
+            rlp(|out| rlp_bytes(out, &long)),
+            format!("b90400{}", hex::encode(long))
+        );
+    }
+
+    #[test]
+    fn rlp_integers_drop_leading_zeros() {
+        assert_eq!(rlp(|out| rlp_uint(out, &0u64.to_be_bytes())), "80");
+        assert_eq!(rlp(|out| rlp_uint(out, &15u64.to_be_bytes())), "0f");
+        assert_eq!(rlp(|out| rlp_uint(out, &1024u64.to_be_bytes())), "820400");
+        assert_eq!(
+            rlp(|out| rlp_uint(out, &u128::MAX.to_be_bytes())),
+            format!("90{}", "ff".repeat(16))
+        );
+    }
+
+    #[test]
+    fn rlp_list_headers() {
+        assert_eq!(rlp(|out| rlp_length(out, 0xC0, 0)), "c0");
+        assert_eq!(rlp(|out| rlp_length(out, 0xC0, 55)), "f7");
+        assert_eq!(rlp(|out| rlp_length(out, 0xC0, 56)), "f838");
+        assert_eq!(rlp(|out| rlp_length(out, 0xC0, 0x0400)), "f90400");
+    }
+
+    #[test]
+    fn encodes_the_unsigned_eip1559_payload() {
+        let expected = format!(
+            "02f0{}{}{}{}{}94{}{}{}c0",
+            "01",
+            "80",
+            "843b9aca00",
+            "8506fc23ac00",
+            "825208",
+            hex::encode(to()),
+            "880de0b6b3a7640000",
+            "80",
+        );
+        assert_eq!(hex::encode(native_transfer().encode(None)), expected);
+    }
+
+    #[test]
+    fn signed_payload_appends_parity_r_and_s() {
+        let tx = native_transfer();
+        let unsigned = tx.encode(None);
+        let r = [0x11; 32];
+        let mut s = [0x22; 32];
+        s[0] = 0;
+
+        let signed = tx.encode(Some((1, &r, &s)));
+        // 48 bytes of fields, then the parity and 32 and 31 byte integers
+        assert_eq!(hex::encode(&signed[..2]), "02f8");
+        assert_eq!(signed[2] as usize, 48 + 1 + 33 + 32);
+        assert_eq!(signed[3..51], unsigned[2..]);
+        assert_eq!(signed[51], 0x01);
+        assert_eq!(signed[52], 0xA0);
+        assert_eq!(signed[53..85], r);
+        assert_eq!(signed[85], 0x9F);
+        assert_eq!(signed[86..], s[1..]);
+
+        let even = tx.encode(Some((0, &r, &s)));
+        assert_eq!(even[51], 0x80);
+    }
+
+    #[test]
+    fn erc20_transfers_carry_calldata() {
+        let recipient = parse_address(EIP55[1]).unwrap();
+        let data = erc20_transfer_data(&recipient, 1_000_000);
+        assert_eq!(
+            hex::encode(&data),
+            format!(
+                "a9059cbb{}{}{:064x}",
+                "00".repeat(12),
+                hex::encode(recipient),
+                1_000_000
+            )
+        );
+
+        let tx = Eip1559Tx {
+            gas_limit: ERC20_TRANSFER_GAS,
+            to: parse_address(EIP55[2]).unwrap(),
+            value: 0,
+            data: data.clone(),
+            ..native_transfer()
+        };
+        // 68 bytes of calldata push the fields past 55 bytes, into the long list header
+        let expected = format!(
+            "02f86e{}{}{}{}{}94{}{}b844{}c0",
+            "01",
+            "80",
+            "843b9aca00",
+            "8506fc23ac00",
+            "830186a0",
+            hex::encode(tx.to),
+            "80",
+            hex::encode(&data),
+        );
+        assert_eq!(hex::encode(tx.encode(None)), expected);
+    }
+}
@@ -0,0 +1,85 @@
+use std::collections::BTreeMap;
+
+use candid::Principal;
+
+use crate::{
+    storage::{self, get_vault},
+    types::{Asset, AssetType, HeirAssetView, HeirVisibility, InheritanceView, VaultStatus},
+};
+
+// Once released everything is visible; before that the owner's setting decides,
+// and a vault without a setting stays hidden
+fn visibility_for(status: &VaultStatus, setting: Option<HeirVisibility>) -> HeirVisibility {
+    if *status == VaultStatus::Released {
+        return HeirVisibility::Full;
+    }
+    setting.unwrap_or(HeirVisibility::Hidden)
+}
+
+fn asset_view(mut asset: Asset, heir: &Principal) -> HeirAssetView {
+    let percentage = asset
+        .heir_assingment
+        .iter()
+        .filter(|h| h.heir_principal == *heir)
+        .map(|h| h.percentage)
+        .fold(0u8, u8::saturating_add);
+
+    let token_ids = asset
+        .heir_assingment
+        .iter()
+        .filter(|h| h.heir_principal == *heir)
+        .flat_map(|h| h.token_ids.clone().unwrap_or_default())
+        .collect();
+
+    let transfers = asset
+        .transfers
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|t| t.heir_principal == *heir)
+        .collect();
+
+    // Each heir only sees the secret sealed to them
+    if let AssetType::EncryptedSecret { ciphertexts } = &mut asset.asset_type {
+        ciphertexts.retain(|c| c.heir == *heir);
+    }
+
+    HeirAssetView {
+        asset_id: asset.id,
+        name: asset.name,
+        description: asset.description,
+        asset_type: asset.asset_type,
+        percentage,
+        token_ids,
+        transfers,
+    }
+}
+
+pub fn list_inheritances(heir: &Principal) -> Vec<InheritanceView> {
+    let mut by_owner: BTreeMap<Principal, Vec<Asset>> = BTreeMap::new();
+    for asset in storage::list_heir_assets(heir) {
+        by_owner.entry(asset.owner).or_default().push(asset);
+    }
+
+    let mut result = Vec::new();
+    for (owner, assets) in by_owner {
+        let Some(vault) = get_vault(&owner) else {
+            continue;
+        };
+
+        let assets = match visibility_for(&vault.status, vault.heir_visibility) {
+            HeirVisibility::Hidden => continue,
+            HeirVisibility::ExistenceOnly => Vec::new(),
+            HeirVisibility::Full => assets
+                .into_iter()
+                .map(|asset| asset_view(asset, heir))
+                .collect(),
+        };
+
+        result.push(InheritanceView {
+            owner,
+            status: vault.status,
+            assets,
+        });
+    }
+    result
+}
@@ -1,8 +1,11 @@
-use candid::Principal;
+use candid::{Nat, Principal};
 
 use crate::{
-    storage,
-    types::{AssetType, AuditEvent, EventType, Vault, VaultStatus},
+    audit, bitcoin, evm, release, storage,
+    types::{
+        ApiError, Asset, AssetType, AuditEvent, EventKind, EventType, HeirAssignment,
+        RemainderPolicy, SealedSecret, Vault, VaultStatus,
+    },
     vault,
 };
 
@@ -10,21 +13,87 @@ pub const NANOS_PER_DAY: u64 = 86_400_000_000_000;
 pub const DEFAULT_HEARTBEAT_INTERVAL: u64 = 30 * NANOS_PER_DAY;
 pub const DEFAULT_GRACE_PERIOD: u64 = 7 * NANOS_PER_DAY;
 pub const MAX_NAME_LENGTH: usize = 100;
-pub const MAX_AUDIT_EVENT: u64 = 10_000;
+pub const MAX_CRITICAL_EVENTS_PER_VAULT: u64 = 2_000;
+pub const MAX_HEARTBEATS_PER_VAULT: u64 = 20;
+pub const DEFAULT_AUDIT_PAGE_SIZE: u32 = 50;
+pub const MAX_AUDIT_PAGE_SIZE: u32 = 200;
+pub const MAX_AUDIT_SCAN: usize = 5_000;
+pub const MAX_AUDIT_VERIFY_RANGE: u64 = 1_000;
+pub const ARCHIVE_INTERVAL_SECS: u64 = 5 * 60;
+pub const ARCHIVE_BATCH_SIZE: usize = 500;
+// Only heartbeats are dropped past this, critical events are kept however long the queue gets
+pub const MAX_ARCHIVE_QUEUE_LEN: u64 = 100_000;
+pub const MAX_EVENTS_PER_ARCHIVE: u64 = 1_000_000;
+pub const ARCHIVE_CREATION_CYCLES: u128 = 2_000_000_000_000;
 pub const MAX_DESCRIPTION_LENGTH: usize = 500;
+pub const MAX_HEIRS_PER_ASSET: usize = 20;
+pub const MAX_TOKENS_PER_NFT_ASSET: usize = 50;
+pub const MAX_CANISTER_CONTROLLERS: usize = 10;
+pub const MAX_CALLBACK_METHOD_LENGTH: usize = 64;
+pub const MAX_CALLBACK_PAYLOAD_BYTES: usize = 1024;
+// Callback heirs get their share of the position in basis points
+pub const CALLBACK_SHARE_SCALE: u64 = 10_000;
+pub const MAX_SECRET_CIPHERTEXT_BYTES: usize = 4096;
+// vetKD key of the subnet, `dfx_test_key` on a local replica
+pub const VETKD_KEY_NAME: &str = match option_env!("VETKD_KEY_NAME") {
+    Some(name) => name,
+    None => "key_1",
+};
+pub const SECRET_KEY_COOLDOWN: u64 = 60 * 1_000_000_000;
+// Leaves headroom under the 2 MiB ingress limit for the rest of the message
+pub const DOCUMENT_CHUNK_SIZE: u64 = 1024 * 1024;
+// Keeps the hash taken at commit within one message's instruction limit
+pub const MAX_DOCUMENT_SIZE: u64 = 64 * 1024 * 1024;
+pub const STORAGE_QUOTA_PER_USER: u64 = 256 * 1024 * 1024;
+pub const MAX_DOCUMENTS_PER_USER: usize = 100;
+pub const MAX_DOCUMENT_ACCESS: usize = MAX_HEIRS_PER_ASSET;
+pub const MAX_MIME_TYPE_LENGTH: usize = 100;
+pub const MAX_LETTER_LENGTH: usize = 20_000;
+pub const MAX_LETTERS_PER_VAULT: usize = 50;
+// Threshold ECDSA key of the subnet, `dfx_test_key` on a local replica
+pub const ECDSA_KEY_NAME: &str = match option_env!("ECDSA_KEY_NAME") {
+    Some(name) => name,
+    None => "key_1",
+};
+// Each input is signed with its own sign_with_ecdsa call
+pub const MAX_BITCOIN_INPUTS: usize = 100;
+pub const BITCOIN_DUST_THRESHOLD: u64 = 546;
+// Millisatoshi per vbyte, used when the network has no fee history (regtest)
+pub const DEFAULT_BITCOIN_FEE_RATE: u64 = 2_000;
+pub const EVM_TRANSFER_GAS: u64 = 21_000;
+// Gas limit for a `transfer` on common tokens
+pub const ERC20_TRANSFER_GAS: u64 = 100_000;
+// Leaves room for this canister and the owner among the controllers
+pub const MAX_CONTROLLER_HEIRS: usize = MAX_CANISTER_CONTROLLERS - 2;
+pub const MAX_RECOVERY_PRINCIPALS: usize = 10;
+pub const RECOVERY_REQUEST_TTL: u64 = 7 * NANOS_PER_DAY;
+pub const SWITCH_CHECK_INTERVAL_SECS: u64 = 60 * 60;
+pub const RELEASE_JOB_INTERVAL_SECS: u64 = 60;
+pub const MAX_RELEASE_JOBS_PER_TICK: usize = 20;
+pub const MAX_RELEASE_ATTEMPTS: u32 = 10;
+pub const RELEASE_RETRY_BASE_DELAY: u64 = 60_000_000_000;
+pub const RELEASE_RETRY_MAX_DELAY: u64 = 6 * 60 * 60 * 1_000_000_000;
+pub const CLAIM_COOLDOWN: u64 = RELEASE_RETRY_BASE_DELAY;
 
 pub fn now() -> u64 {
     ic_cdk::api::time()
 }
 
-pub fn log_event(event_type: EventType, blame: &Principal, details: String) {
+pub fn log_event(event_type: EventType, blame: &Principal) {
     let event = AuditEvent {
         blame: *blame,
         timestamp: now(),
         event_type,
-        details,
+        prev_hash: None,
     };
     storage::log_event(event);
+    audit::certify_chain_head();
+}
+
+// High-volume events are kept in a short per-vault window and compacted into counters,
+// so they can never push a vault's critical history out
+pub fn is_compactable_event(event_type: &EventType) -> bool {
+    event_type.kind() == EventKind::Heartbeat
 }
 
 pub fn check_is_anonymous(caller: &Principal) -> bool {
@@ -35,38 +104,994 @@ pub fn is_vault_released(vault: &Vault) -> bool {
     vault.status == VaultStatus::Released
 }
 
-pub fn validate_asset_input(name: &str, desc: &str) -> Result<(), String> {
+// Splits `amount` between heirs by percentage. Rounding dust goes to the heir with the
+// largest percentage (first one wins a tie) so the result is deterministic. What happens
+// to the part not covered by percentages is up to the vault's remainder policy.
+pub fn compute_heir_shares(
+    amount: u64,
+    heirs: &[HeirAssignment],
+    policy: &RemainderPolicy,
+) -> Vec<(Principal, u64)> {
+    compute_wide_heir_shares(amount as u128, heirs, policy)
+        .into_iter()
+        .map(|(heir, share)| (heir, share as u64))
+        .collect()
+}
+
+// `amount * pct / denominator` without overflowing for any u128 amount
+fn scale(amount: u128, pct: u128, denominator: u128) -> u128 {
+    amount / denominator * pct + amount % denominator * pct / denominator
+}
+
+// Same split for amounts that don't fit a u64, like wei
+pub fn compute_wide_heir_shares(
+    amount: u128,
+    heirs: &[HeirAssignment],
+    policy: &RemainderPolicy,
+) -> Vec<(Principal, u128)> {
+    let total_pct: u128 = heirs.iter().map(|h| h.percentage as u128).sum();
+    if total_pct == 0 {
+        return Vec::new();
+    }
+    let denominator = match policy {
+        RemainderPolicy::SplitProportionally => total_pct,
+        _ => total_pct.max(100),
+    };
+
+    let mut shares: Vec<(Principal, u128)> = heirs
+        .iter()
+        .map(|h| {
+            let share = scale(amount, h.percentage as u128, denominator);
+            (h.heir_principal, share)
+        })
+        .collect();
+
+    let assigned = scale(amount, total_pct, denominator);
+    let distributed: u128 = shares.iter().map(|(_, share)| share).sum();
+    let remainder = assigned - distributed;
+
+    if remainder > 0 {
+        let mut largest = 0;
+        for (i, h) in heirs.iter().enumerate() {
+            if h.percentage > heirs[largest].percentage {
+                largest = i;
+            }
+        }
+        shares[largest].1 += remainder;
+    }
+
+    if let RemainderPolicy::ToHeir { heir } = policy {
+        if amount > assigned {
+            shares.push((*heir, amount - assigned));
+        }
+    }
+
+    shares
+}
+
+// Vaults created before remainder policies existed get the strictest one
+pub fn remainder_policy(vault: &Vault) -> RemainderPolicy {
+    vault
+        .remainder_policy
+        .clone()
+        .unwrap_or(RemainderPolicy::RequireFull)
+}
+
+pub fn validate_heir_assignments(
+    owner: &Principal,
+    asset_type: &AssetType,
+    heirs: &[HeirAssignment],
+    policy: &RemainderPolicy,
+) -> Result<(), ApiError> {
+    if heirs.is_empty() {
+        return Err(ApiError::validation(
+            "heir_assingment",
+            "at least one heir is required",
+        ));
+    }
+
+    if heirs.len() > MAX_HEIRS_PER_ASSET {
+        return Err(ApiError::validation(
+            "heir_assingment",
+            format!("too many heirs (max {})", MAX_HEIRS_PER_ASSET),
+        ));
+    }
+
+    for (i, heir) in heirs.iter().enumerate() {
+        if check_is_anonymous(&heir.heir_principal) {
+            return Err(ApiError::validation(
+                format!("heir_assingment[{}].heir_principal", i),
+                "anonymous principal cannot be an heir",
+            ));
+        }
+
+        if heir.heir_principal == *owner {
+            return Err(ApiError::validation(
+                format!("heir_assingment[{}].heir_principal", i),
+                "owner cannot be their own heir",
+            ));
+        }
+
+        if heirs[..i]
+            .iter()
+            .any(|h| h.heir_principal == heir.heir_principal)
+        {
+            return Err(ApiError::validation(
+                format!("heir_assingment[{}].heir_principal", i),
+                format!("duplicate heir {}", heir.heir_principal.to_text()),
+            ));
+        }
+    }
+
+    match asset_type {
+        AssetType::ICRC7Nft { token_ids, .. } => {
+            validate_token_allocation(token_ids, heirs, policy)
+        }
+        AssetType::CanisterControl { .. } => validate_controller_heirs(heirs),
+        AssetType::EncryptedSecret { ciphertexts } => validate_secret_heirs(ciphertexts, heirs),
+        _ => validate_percentages(heirs, policy),
+    }
+}
+
+fn validate_percentages(
+    heirs: &[HeirAssignment],
+    policy: &RemainderPolicy,
+) -> Result<(), ApiError> {
+    let mut total: u32 = 0;
+    for (i, heir) in heirs.iter().enumerate() {
+        if heir.token_ids.is_some() {
+            return Err(ApiError::validation(
+                format!("heir_assingment[{}].token_ids", i),
+                "only supported for ICRC7Nft assets",
+            ));
+        }
+
+        if heir.percentage == 0 {
+            return Err(ApiError::validation(
+                format!("heir_assingment[{}].percentage", i),
+                "must be greater than 0",
+            ));
+        }
+
+        total += heir.percentage as u32;
+    }
+
+    if total > 100 {
+        return Err(ApiError::validation(
+            "heir_assingment",
+            format!("percentages sum to {} (max 100)", total),
+        ));
+    }
+
+    if total < 100 && *policy == RemainderPolicy::RequireFull {
+        return Err(ApiError::validation(
+            "heir_assingment",
+            format!("percentages sum to {} but must sum to 100", total),
+        ));
+    }
+
+    Ok(())
+}
+
+// NFTs can't be split, so every heir names the tokens they get and each token goes to at
+// most one heir. Tokens nobody was given follow the remainder policy.
+fn validate_token_allocation(
+    token_ids: &[Nat],
+    heirs: &[HeirAssignment],
+    policy: &RemainderPolicy,
+) -> Result<(), ApiError> {
+    let mut assigned: Vec<&Nat> = Vec::new();
+    for (i, heir) in heirs.iter().enumerate() {
+        if heir.percentage != 0 {
+            return Err(ApiError::validation(
+                format!("heir_assingment[{}].percentage", i),
+                "must be 0 for NFTs, assign token_ids instead",
+            ));
+        }
+
+        let field = format!("heir_assingment[{}].token_ids", i);
+        let tokens = match &heir.token_ids {
+            Some(tokens) if !tokens.is_empty() => tokens,
+            _ => {
+                return Err(ApiError::validation(
+                    field,
+                    "at least one token is required",
+                ))
+            }
+        };
+
+        for token_id in tokens {
+            if !token_ids.contains(token_id) {
+                return Err(ApiError::validation(
+                    field,
+                    format!("token {} is not part of the asset", token_id),
+                ));
+            }
+            if assigned.contains(&token_id) {
+                return Err(ApiError::validation(
+                    field,
+                    format!("token {} is assigned twice", token_id),
+                ));
+            }
+            assigned.push(token_id);
+        }
+    }
+
+    if assigned.len() < token_ids.len() {
+        match policy {
+            RemainderPolicy::RequireFull => {
+                return Err(ApiError::validation(
+                    "heir_assingment",
+                    format!(
+                        "{} of {} tokens assigned but all must be",
+                        assigned.len(),
+                        token_ids.len()
+                    ),
+                ))
+            }
+            RemainderPolicy::SplitProportionally => {
+                return Err(ApiError::validation(
+                    "heir_assingment",
+                    "unassigned tokens can't be split proportionally, assign every token",
+                ))
+            }
+            RemainderPolicy::KeepWithOwner | RemainderPolicy::ToHeir { .. } => {}
+        }
+    }
+
+    Ok(())
+}
+
+// Control isn't divisible either, every heir becomes a full controller
+fn validate_controller_heirs(heirs: &[HeirAssignment]) -> Result<(), ApiError> {
+    if heirs.len() > MAX_CONTROLLER_HEIRS {
+        return Err(ApiError::validation(
+            "heir_assingment",
+            format!(
+                "too many heirs for a canister (max {})",
+                MAX_CONTROLLER_HEIRS
+            ),
+        ));
+    }
+
+    for (i, heir) in heirs.iter().enumerate() {
+        if heir.percentage != 0 {
+            return Err(ApiError::validation(
+                format!("heir_assingment[{}].percentage", i),
+                "must be 0, every heir becomes a controller",
+            ));
+        }
+        if heir.token_ids.is_some() {
+            return Err(ApiError::validation(
+                format!("heir_assingment[{}].token_ids", i),
+                "only supported for ICRC7Nft assets",
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// Each heir gets the whole secret, sealed to them alone
+fn validate_secret_heirs(
+    ciphertexts: &[SealedSecret],
+    heirs: &[HeirAssignment],
+) -> Result<(), ApiError> {
+    for (i, heir) in heirs.iter().enumerate() {
+        if heir.percentage != 0 {
+            return Err(ApiError::validation(
+                format!("heir_assingment[{}].percentage", i),
+                "must be 0, every heir gets the whole secret",
+            ));
+        }
+        if heir.token_ids.is_some() {
+            return Err(ApiError::validation(
+                format!("heir_assingment[{}].token_ids", i),
+                "only supported for ICRC7Nft assets",
+            ));
+        }
+        if !ciphertexts.iter().any(|c| c.heir == heir.heir_principal) {
+            return Err(ApiError::validation(
+                format!("heir_assingment[{}].heir_principal", i),
+                "no ciphertext sealed to this heir",
+            ));
+        }
+    }
+
+    for (i, sealed) in ciphertexts.iter().enumerate() {
+        if !heirs.iter().any(|h| h.heir_principal == sealed.heir) {
+            return Err(ApiError::validation(
+                format!("ciphertexts[{}].heir", i),
+                format!("{} is not an heir of this asset", sealed.heir.to_text()),
+            ));
+        }
+        if ciphertexts[..i].iter().any(|c| c.heir == sealed.heir) {
+            return Err(ApiError::validation(
+                format!("ciphertexts[{}].heir", i),
+                format!("duplicate ciphertext for {}", sealed.heir.to_text()),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// Payout addresses only mean something where heirs can't be paid to their principal
+pub fn validate_heir_accounts(
+    asset_type: &AssetType,
+    heirs: &[HeirAssignment],
+) -> Result<(), ApiError> {
+    for (i, heir) in heirs.iter().enumerate() {
+        let field = format!("heir_assingment[{}].account_identifier", i);
+
+        match (asset_type, &heir.account_identifier) {
+            (AssetType::ICPLedger { .. }, Some(text)) => {
+                vault::parse_account_identifier(text)
+                    .map_err(|reason| ApiError::validation(field, reason))?;
+            }
+            (AssetType::BitcoinAddress { .. }, Some(text)) => {
+                bitcoin::script_pubkey(text)
+                    .map_err(|reason| ApiError::validation(field, reason))?;
+            }
+            (AssetType::BitcoinAddress { .. }, None) => {
+                return Err(ApiError::validation(
+                    field,
+                    "a Bitcoin address is required for every heir",
+                ));
+            }
+            (AssetType::EvmAddress { .. }, Some(text)) => {
+                evm::parse_address(text).map_err(|reason| ApiError::validation(field, reason))?;
+            }
+            (AssetType::EvmAddress { .. }, None) => {
+                return Err(ApiError::validation(
+                    field,
+                    "an EVM address is required for every heir",
+                ));
+            }
+            (_, Some(_)) => {
+                return Err(ApiError::validation(
+                    field,
+                    "only supported for ICPLedger, BitcoinAddress and EvmAddress assets",
+                ));
+            }
+            (_, None) => {}
+        }
+    }
+
+    Ok(())
+}
+
+pub fn validate_remainder_policy(
+    owner: &Principal,
+    policy: &RemainderPolicy,
+) -> Result<(), ApiError> {
+    if let RemainderPolicy::ToHeir { heir } = policy {
+        if check_is_anonymous(heir) {
+            return Err(ApiError::validation(
+                "remainder_policy.heir",
+                "anonymous principal cannot be an heir",
+            ));
+        }
+        if heir == owner {
+            return Err(ApiError::validation(
+                "remainder_policy.heir",
+                "owner cannot be their own heir",
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+pub struct HeirDiff {
+    pub added: Vec<HeirAssignment>,
+    pub removed: Vec<HeirAssignment>,
+    // (heir, old percentage, new percentage)
+    pub changed: Vec<(Principal, u8, u8)>,
+}
+
+pub fn diff_heirs(old: &[HeirAssignment], new: &[HeirAssignment]) -> HeirDiff {
+    let mut diff = HeirDiff::default();
+
+    for heir in new {
+        match old.iter().find(|h| h.heir_principal == heir.heir_principal) {
+            None => diff.added.push(heir.clone()),
+            Some(prev) if prev.percentage != heir.percentage => {
+                diff.changed
+                    .push((heir.heir_principal, prev.percentage, heir.percentage));
+            }
+            Some(_) => {}
+        }
+    }
+
+    for heir in old {
+        if !new.iter().any(|h| h.heir_principal == heir.heir_principal) {
+            diff.removed.push(heir.clone());
+        }
+    }
+
+    diff
+}
+
+// A derived address can't be replaced with one of the owner's choosing. An EVM asset keeps
+// its address, path and nonce, which stay the previous owner's after a recovery, and only
+// takes the new transfer settings.
+pub fn merge_asset_type(original: &AssetType, requested: AssetType) -> AssetType {
+    match (requested, original) {
+        (AssetType::BitcoinAddress { .. }, AssetType::BitcoinAddress { .. }) => original.clone(),
+        (
+            AssetType::EvmAddress {
+                chain_id,
+                token,
+                amount,
+                max_fee_per_gas,
+                max_priority_fee_per_gas,
+                ..
+            },
+            AssetType::EvmAddress {
+                address,
+                derivation_path,
+                next_nonce,
+                ..
+            },
+        ) => AssetType::EvmAddress {
+            address: address.clone(),
+            derivation_path: derivation_path.clone(),
+            next_nonce: *next_nonce,
+            chain_id,
+            token,
+            amount,
+            max_fee_per_gas,
+            max_priority_fee_per_gas,
+        },
+        (asset_type, _) => asset_type,
+    }
+}
+
+// Only an asset that becomes a Bitcoin or EVM address gets one derived, one that already
+// was keeps it
+pub fn needs_derived_address(original: &AssetType, updated: &AssetType) -> bool {
+    match updated {
+        AssetType::BitcoinAddress { .. } => !matches!(original, AssetType::BitcoinAddress { .. }),
+        AssetType::EvmAddress { .. } => !matches!(original, AssetType::EvmAddress { .. }),
+        _ => false,
+    }
+}
+
+// Heir changes are logged as their own events, so only the asset's own fields are described
+pub fn describe_asset_changes(old: &Asset, new: &Asset) -> Vec<String> {
+    let mut changes = Vec::new();
+
+    if old.name != new.name {
+        changes.push(format!("name: '{}' -> '{}'", old.name, new.name));
+    }
+    if old.description != new.description {
+        changes.push("description changed".to_string());
+    }
+
+    match (&old.asset_type, &new.asset_type) {
+        (
+            AssetType::ICRC2Token {
+                ledger_canister: old_ledger,
+                amount: old_amount,
+            },
+            AssetType::ICRC2Token {
+                ledger_canister: new_ledger,
+                amount: new_amount,
+            },
+        ) => {
+            if old_ledger != new_ledger {
+                changes.push(format!(
+                    "ledger_canister: {} -> {}",
+                    old_ledger.to_text(),
+                    new_ledger.to_text()
+                ));
+            }
+            if old_amount != new_amount {
+                changes.push(format!("amount: {} -> {}", old_amount, new_amount));
+            }
+        }
+        (
+            AssetType::ICRC1Escrow {
+                ledger_canister: old_ledger,
+                ..
+            },
+            AssetType::ICRC1Escrow {
+                ledger_canister: new_ledger,
+                ..
+            },
+        )
+        | (
+            AssetType::ICPLedger {
+                ledger_canister: old_ledger,
+                ..
+            },
+            AssetType::ICPLedger {
+                ledger_canister: new_ledger,
+                ..
+            },
+        ) if old_ledger != new_ledger => {
+            changes.push(format!(
+                "ledger_canister: {} -> {}",
+                old_ledger.to_text(),
+                new_ledger.to_text()
+            ));
+        }
+        (
+            AssetType::ICRC7Nft {
+                collection: old_collection,
+                token_ids: old_tokens,
+            },
+            AssetType::ICRC7Nft {
+                collection: new_collection,
+                token_ids: new_tokens,
+            },
+        ) => {
+            if old_collection != new_collection {
+                changes.push(format!(
+                    "collection: {} -> {}",
+                    old_collection.to_text(),
+                    new_collection.to_text()
+                ));
+            }
+            if old_tokens != new_tokens {
+                changes.push(format!(
+                    "token_ids: {} -> {} tokens",
+                    old_tokens.len(),
+                    new_tokens.len()
+                ));
+            }
+        }
+        (
+            AssetType::CanisterControl {
+                canister_id: old_canister,
+                remove_owner: old_remove,
+            },
+            AssetType::CanisterControl {
+                canister_id: new_canister,
+                remove_owner: new_remove,
+            },
+        ) => {
+            if old_canister != new_canister {
+                changes.push(format!(
+                    "canister_id: {} -> {}",
+                    old_canister.to_text(),
+                    new_canister.to_text()
+                ));
+            }
+            if old_remove != new_remove {
+                changes.push(format!("remove_owner: {} -> {}", old_remove, new_remove));
+            }
+        }
+        (
+            AssetType::Callback {
+                canister: old_canister,
+                method: old_method,
+                payload: old_payload,
+            },
+            AssetType::Callback {
+                canister: new_canister,
+                method: new_method,
+                payload: new_payload,
+            },
+        ) => {
+            if old_canister != new_canister {
+                changes.push(format!(
+                    "canister: {} -> {}",
+                    old_canister.to_text(),
+                    new_canister.to_text()
+                ));
+            }
+            if old_method != new_method {
+                changes.push(format!("method: '{}' -> '{}'", old_method, new_method));
+            }
+            if old_payload != new_payload {
+                changes.push("payload changed".to_string());
+            }
+        }
+        (
+            AssetType::EncryptedSecret {
+                ciphertexts: old_ciphertexts,
+            },
+            AssetType::EncryptedSecret {
+                ciphertexts: new_ciphertexts,
+            },
+        ) => {
+            for sealed in new_ciphertexts {
+                if !old_ciphertexts.contains(sealed) {
+                    changes.push(format!("secret resealed for {}", sealed.heir.to_text()));
+                }
+            }
+            for sealed in old_ciphertexts {
+                if !new_ciphertexts.iter().any(|c| c.heir == sealed.heir) {
+                    changes.push(format!("secret removed for {}", sealed.heir.to_text()));
+                }
+            }
+        }
+        (old_type, new_type) if old_type != new_type => {
+            changes.push("asset_type changed".to_string());
+        }
+        _ => {}
+    }
+
+    // Payout destinations and token allocations aren't covered by the heir events, which
+    // only track percentages
+    for heir in &new.heir_assingment {
+        let Some(prev) = old
+            .heir_assingment
+            .iter()
+            .find(|h| h.heir_principal == heir.heir_principal)
+        else {
+            continue;
+        };
+        if prev.account_identifier != heir.account_identifier {
+            changes.push(format!(
+                "payout account of {} changed",
+                heir.heir_principal.to_text()
+            ));
+        }
+        if prev.token_ids != heir.token_ids {
+            changes.push(format!(
+                "tokens of {} changed",
+                heir.heir_principal.to_text()
+            ));
+        }
+    }
+
+    changes
+}
+
+pub fn validate_asset_input(name: &str, desc: &str) -> Result<(), ApiError> {
     if name.is_empty() {
-        return Err("Asset name cannot be empty".to_string());
+        return Err(ApiError::validation("name", "cannot be empty"));
     }
 
     if name.chars().count() > MAX_NAME_LENGTH {
-        return Err(format!(
-            "Asset name too long (max {} characters)",
-            MAX_NAME_LENGTH
+        return Err(ApiError::validation(
+            "name",
+            format!("too long (max {} characters)", MAX_NAME_LENGTH),
         ));
     }
 
     if desc.chars().count() > MAX_DESCRIPTION_LENGTH {
-        return Err(format!(
-            "Description too long (max {} characters)",
-            MAX_DESCRIPTION_LENGTH
+        return Err(ApiError::validation(
+            "description",
+            format!("too long (max {} characters)", MAX_DESCRIPTION_LENGTH),
         ));
     }
 
     Ok(())
 }
 
-pub async fn verify_asset_type(caller: &Principal, asset_type: &AssetType) -> Result<(), String> {
+pub async fn verify_asset_type(caller: &Principal, asset_type: &AssetType) -> Result<(), ApiError> {
     match asset_type {
         AssetType::ICRC2Token {
             ledger_canister,
             amount,
         } => {
             if *amount == 0 {
-                return Err("Asset amount must be greater than 0".to_string());
+                return Err(ApiError::validation("amount", "must be greater than 0"));
             }
             vault::verify_icrc2_allowance(caller, ledger_canister, *amount).await
         }
+        // Nothing is escrowed until the owner deposits, we only check the ledger answers
+        AssetType::ICRC1Escrow {
+            ledger_canister,
+            amount,
+        }
+        | AssetType::ICPLedger {
+            ledger_canister,
+            amount,
+        } => {
+            if *amount != 0 {
+                return Err(ApiError::validation(
+                    "amount",
+                    "must be 0, escrowed balances are credited from deposits",
+                ));
+            }
+            let fee = if matches!(asset_type, AssetType::ICPLedger { .. }) {
+                vault::icp_transfer_fee(ledger_canister).await
+            } else {
+                release::fetch_icrc1_fee(ledger_canister).await
+            };
+            fee.map(|_| ())
+                .map_err(|reason| ApiError::LedgerCallFailed {
+                    ledger: *ledger_canister,
+                    reason,
+                })
+        }
+        AssetType::ICRC7Nft {
+            collection,
+            token_ids,
+        } => {
+            if token_ids.is_empty() {
+                return Err(ApiError::validation("token_ids", "cannot be empty"));
+            }
+            if token_ids.len() > MAX_TOKENS_PER_NFT_ASSET {
+                return Err(ApiError::validation(
+                    "token_ids",
+                    format!("too many tokens (max {})", MAX_TOKENS_PER_NFT_ASSET),
+                ));
+            }
+            for (i, token_id) in token_ids.iter().enumerate() {
+                if token_ids[..i].contains(token_id) {
+                    return Err(ApiError::validation(
+                        "token_ids",
+                        format!("duplicate token {}", token_id),
+                    ));
+                }
+            }
+            vault::verify_icrc7_tokens(caller, collection, token_ids).await
+        }
+        AssetType::CanisterControl { canister_id, .. } => {
+            vault::verify_canister_control(caller, canister_id).await
+        }
+        AssetType::Callback {
+            canister,
+            method,
+            payload,
+        } => {
+            if method.is_empty() {
+                return Err(ApiError::validation("method", "cannot be empty"));
+            }
+            if method.len() > MAX_CALLBACK_METHOD_LENGTH {
+                return Err(ApiError::validation(
+                    "method",
+                    format!("too long (max {} bytes)", MAX_CALLBACK_METHOD_LENGTH),
+                ));
+            }
+            if payload.len() > MAX_CALLBACK_PAYLOAD_BYTES {
+                return Err(ApiError::validation(
+                    "payload",
+                    format!("too large (max {} bytes)", MAX_CALLBACK_PAYLOAD_BYTES),
+                ));
+            }
+            vault::verify_callback(caller, canister, method, payload).await
+        }
+        // The address is derived by the backend once the asset has an id
+        AssetType::BitcoinAddress { .. } => Ok(()),
+        // There is no RPC to check the chain against, only that a payout could be signed
+        AssetType::EvmAddress {
+            chain_id,
+            token,
+            amount,
+            max_fee_per_gas,
+            max_priority_fee_per_gas,
+            ..
+        } => {
+            if *chain_id == 0 {
+                return Err(ApiError::validation("chain_id", "must be greater than 0"));
+            }
+            if let Some(token) = token {
+                evm::parse_address(token)
+                    .map_err(|reason| ApiError::validation("token", reason))?;
+            }
+            if *amount == 0 {
+                return Err(ApiError::validation("amount", "must be greater than 0"));
+            }
+            if *max_fee_per_gas == 0 {
+                return Err(ApiError::validation(
+                    "max_fee_per_gas",
+                    "must be greater than 0",
+                ));
+            }
+            if max_priority_fee_per_gas > max_fee_per_gas {
+                return Err(ApiError::validation(
+                    "max_priority_fee_per_gas",
+                    "cannot be above max_fee_per_gas",
+                ));
+            }
+            Ok(())
+        }
+        AssetType::EncryptedSecret { ciphertexts } => {
+            for (i, sealed) in ciphertexts.iter().enumerate() {
+                if sealed.ciphertext.is_empty() {
+                    return Err(ApiError::validation(
+                        format!("ciphertexts[{}].ciphertext", i),
+                        "cannot be empty",
+                    ));
+                }
+                if sealed.ciphertext.len() > MAX_SECRET_CIPHERTEXT_BYTES {
+                    return Err(ApiError::validation(
+                        format!("ciphertexts[{}].ciphertext", i),
+                        format!("too large (max {} bytes)", MAX_SECRET_CIPHERTEXT_BYTES),
+                    ));
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heir(n: u8, percentage: u8) -> HeirAssignment {
+        HeirAssignment {
+            heir_principal: Principal::from_slice(&[n; 29]),
+            percentage,
+            account_identifier: None,
+            token_ids: None,
+        }
+    }
+
+    fn amounts(shares: &[(Principal, u64)]) -> Vec<u64> {
+        shares.iter().map(|(_, share)| *share).collect()
+    }
+
+    #[test]
+    fn exact_split() {
+        let heirs = [heir(1, 50), heir(2, 50)];
+        let shares = compute_heir_shares(1_000, &heirs, &RemainderPolicy::RequireFull);
+        assert_eq!(
+            shares,
+            vec![
+                (heirs[0].heir_principal, 500),
+                (heirs[1].heir_principal, 500)
+            ]
+        );
+    }
+
+    #[test]
+    fn rounding_dust_goes_to_largest_percentage() {
+        let heirs = [heir(1, 33), heir(2, 34), heir(3, 33)];
+        let shares = compute_heir_shares(10, &heirs, &RemainderPolicy::RequireFull);
+        assert_eq!(amounts(&shares), vec![3, 4, 3]);
+    }
+
+    #[test]
+    fn rounding_dust_tie_goes_to_first_heir() {
+        let heirs = [heir(1, 50), heir(2, 50)];
+        let shares = compute_heir_shares(3, &heirs, &RemainderPolicy::RequireFull);
+        assert_eq!(amounts(&shares), vec![2, 1]);
+    }
+
+    #[test]
+    fn keep_with_owner_leaves_unassigned_part() {
+        let heirs = [heir(1, 30), heir(2, 20)];
+        let shares = compute_heir_shares(1_001, &heirs, &RemainderPolicy::KeepWithOwner);
+        assert_eq!(amounts(&shares), vec![300, 200]);
+    }
+
+    #[test]
+    fn split_proportionally_scales_up() {
+        let heirs = [heir(1, 30), heir(2, 20)];
+        let shares = compute_heir_shares(1_000, &heirs, &RemainderPolicy::SplitProportionally);
+        assert_eq!(amounts(&shares), vec![600, 400]);
+    }
+
+    #[test]
+    fn to_heir_gets_unassigned_part() {
+        let residual = heir(9, 0).heir_principal;
+        let heirs = [heir(1, 30), heir(2, 20)];
+        let shares =
+            compute_heir_shares(1_001, &heirs, &RemainderPolicy::ToHeir { heir: residual });
+        assert_eq!(amounts(&shares), vec![300, 200, 501]);
+        assert_eq!(shares[2].0, residual);
+    }
+
+    #[test]
+    fn full_policies_never_lose_or_create_tokens() {
+        let heirs = [heir(1, 17), heir(2, 41), heir(3, 9)];
+        let residual = heir(9, 0).heir_principal;
+        for amount in [0, 1, 2, 99, 101, 12_345, u64::MAX / 3, u64::MAX] {
+            for policy in [
+                RemainderPolicy::SplitProportionally,
+                RemainderPolicy::ToHeir { heir: residual },
+            ] {
+                let total: u128 = compute_heir_shares(amount, &heirs, &policy)
+                    .iter()
+                    .map(|(_, share)| *share as u128)
+                    .sum();
+                assert_eq!(total, amount as u128, "{} {:?}", amount, policy);
+            }
+        }
+    }
+
+    #[test]
+    fn wide_amounts_do_not_overflow() {
+        let heirs = [heir(1, 60), heir(2, 40)];
+        let shares = compute_wide_heir_shares(u128::MAX, &heirs, &RemainderPolicy::RequireFull);
+        assert_eq!(shares[0].1 + shares[1].1, u128::MAX);
+        assert_eq!(shares[1].1, u128::MAX / 5 * 2);
+    }
+
+    #[test]
+    fn no_percentages_means_no_shares() {
+        let heirs = [heir(1, 0)];
+        assert!(compute_heir_shares(100, &heirs, &RemainderPolicy::KeepWithOwner).is_empty());
+        assert!(compute_heir_shares(100, &[], &RemainderPolicy::RequireFull).is_empty());
+    }
+
+    fn evm(
+        address: &str,
+        derivation_path: Vec<Vec<u8>>,
+        next_nonce: u64,
+        amount: u128,
+    ) -> AssetType {
+        AssetType::EvmAddress {
+            address: address.to_string(),
+            derivation_path,
+            next_nonce,
+            chain_id: 1,
+            token: None,
+            amount,
+            max_fee_per_gas: 30,
+            max_priority_fee_per_gas: 1,
+        }
+    }
+
+    #[test]
+    fn editing_an_evm_asset_keeps_its_address_after_withdrawals_and_recovery() {
+        let old_owner = Principal::from_slice(&[1; 29]);
+        let new_owner = Principal::from_slice(&[2; 29]);
+        let path = evm::derivation_path(&old_owner, 0);
+        storage::insert_vault(
+            &old_owner,
+            Vault {
+                owner: old_owner,
+                created_at: 0,
+                status: VaultStatus::Active,
+                dms: crate::types::DeadManSwitch {
+                    last_heartbeat: 0,
+                    heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+                    grace_period: DEFAULT_GRACE_PERIOD,
+                    pending_since: None,
+                },
+                recovery_config: None,
+                next_asset_id: 1,
+                recovery_request: None,
+                heir_visibility: None,
+                remainder_policy: None,
+                escrow_subaccount: None,
+            },
+        );
+        // Three withdrawals were signed from the address already
+        storage::insert_asset(Asset {
+            id: 0,
+            owner: old_owner,
+            asset_type: evm("0xabc", path.clone(), 3, 100),
+            name: "eth".to_string(),
+            description: String::new(),
+            created_at: 0,
+            heir_assingment: vec![heir(3, 100)],
+            transfers: None,
+            payout_from: None,
+        });
+
+        let requested = evm("", Vec::new(), 0, 50);
+        let edited = merge_asset_type(
+            &storage::get_asset(0).unwrap().asset_type,
+            requested.clone(),
+        );
+        assert_eq!(edited, evm("0xabc", path.clone(), 3, 50));
+
+        storage::transfer_vault_ownership(&old_owner, &new_owner).unwrap();
+        let original = storage::get_asset(0).unwrap();
+        assert_eq!(original.owner, new_owner);
+        let edited = merge_asset_type(&original.asset_type, requested);
+        assert_eq!(edited, evm("0xabc", path, 3, 50));
+        assert!(!needs_derived_address(&original.asset_type, &edited));
+    }
+
+    #[test]
+    fn only_new_address_assets_are_derived() {
+        let bitcoin = AssetType::BitcoinAddress {
+            address: "bc1q".to_string(),
+            derivation_path: Vec::new(),
+        };
+        let icrc2 = AssetType::ICRC2Token {
+            ledger_canister: Principal::anonymous(),
+            amount: 1,
+        };
+        let evm = evm("0xabc", Vec::new(), 0, 1);
+        assert!(needs_derived_address(&icrc2, &bitcoin));
+        assert!(needs_derived_address(&icrc2, &evm));
+        assert!(needs_derived_address(&bitcoin, &evm));
+        assert!(needs_derived_address(&evm, &bitcoin));
+        assert!(!needs_derived_address(&bitcoin, &bitcoin));
+        assert!(!needs_derived_address(&evm, &evm));
+        assert!(!needs_derived_address(&evm, &icrc2));
     }
 }
@@ -0,0 +1,118 @@
+use candid::Principal;
+
+use crate::{
+    helpers::{
+        check_is_anonymous, log_event, now, MAX_LETTERS_PER_VAULT, MAX_LETTER_LENGTH,
+        MAX_NAME_LENGTH,
+    },
+    storage::{self, get_letter, get_vault, insert_letter},
+    types::{ApiError, EventType, Letter, LetterNotice, VaultStatus},
+};
+
+fn check_vault_writable(owner: &Principal) -> Result<(), ApiError> {
+    let vault = get_vault(owner).ok_or(ApiError::VaultNotFound)?;
+    if vault.status == VaultStatus::Released {
+        return Err(ApiError::VaultReleased);
+    }
+    Ok(())
+}
+
+fn is_released(owner: &Principal) -> bool {
+    get_vault(owner).is_some_and(|v| v.status == VaultStatus::Released)
+}
+
+// Writing to an heir who already has a letter replaces it
+pub fn write_letter(
+    owner: &Principal,
+    heir: Principal,
+    subject: String,
+    body: String,
+) -> Result<(), ApiError> {
+    check_vault_writable(owner)?;
+
+    if check_is_anonymous(&heir) {
+        return Err(ApiError::validation(
+            "heir",
+            "anonymous principal cannot be an heir",
+        ));
+    }
+    if heir == *owner {
+        return Err(ApiError::validation(
+            "heir",
+            "owner cannot be their own heir",
+        ));
+    }
+    if subject.chars().count() > MAX_NAME_LENGTH {
+        return Err(ApiError::validation(
+            "subject",
+            format!("too long (max {} characters)", MAX_NAME_LENGTH),
+        ));
+    }
+    if body.is_empty() {
+        return Err(ApiError::validation("body", "cannot be empty"));
+    }
+    if body.chars().count() > MAX_LETTER_LENGTH {
+        return Err(ApiError::validation(
+            "body",
+            format!("too long (max {} characters)", MAX_LETTER_LENGTH),
+        ));
+    }
+
+    if get_letter(owner, &heir).is_none()
+        && storage::list_user_letters(owner).len() >= MAX_LETTERS_PER_VAULT
+    {
+        return Err(ApiError::validation(
+            "heir",
+            format!("too many letters (max {})", MAX_LETTERS_PER_VAULT),
+        ));
+    }
+
+    insert_letter(Letter {
+        owner: *owner,
+        heir,
+        subject,
+        body,
+        written_at: now(),
+        read_at: None,
+    });
+
+    log_event(EventType::LetterWritten { heir }, owner);
+    Ok(())
+}
+
+pub fn delete_letter(owner: &Principal, heir: &Principal) -> Result<(), ApiError> {
+    check_vault_writable(owner)?;
+    storage::remove_letter(owner, heir).ok_or(ApiError::LetterNotFound)?;
+
+    log_event(EventType::LetterDeleted { heir: *heir }, owner);
+    Ok(())
+}
+
+// Letters stay invisible to heirs, existence included, until release
+pub fn list_received(heir: &Principal) -> Vec<LetterNotice> {
+    storage::list_heir_letters(heir)
+        .into_iter()
+        .filter(|letter| is_released(&letter.owner))
+        .map(|letter| LetterNotice {
+            owner: letter.owner,
+            written_at: letter.written_at,
+            read_at: letter.read_at,
+        })
+        .collect()
+}
+
+// An update call so the first opening can be recorded as a read receipt
+pub fn read_letter(heir: &Principal, owner: &Principal) -> Result<Letter, ApiError> {
+    let mut letter = get_letter(owner, heir).ok_or(ApiError::LetterNotFound)?;
+    if !is_released(owner) {
+        return Err(ApiError::LetterNotFound);
+    }
+
+    if letter.read_at.is_none() {
+        letter.read_at = Some(now());
+        insert_letter(letter.clone());
+        log_event(EventType::LetterRead { heir: *heir }, owner);
+    }
+
+    Ok(letter)
+}
@@ -1,22 +1,64 @@
+#![allow(non_snake_case)]
+
+mod archive;
+mod audit;
+mod bitcoin;
+mod documents;
+mod escrow;
+mod evm;
+mod heir;
 mod helpers;
+mod letters;
+mod migrations;
+mod release;
+mod secret;
 mod storage;
 mod types;
 mod vault;
 
-use ic_cdk_macros::{query, update};
+use candid::{Nat, Principal};
+use ic_cdk_macros::{init, post_upgrade, query, update};
 
 use crate::{
     helpers::{
-        check_is_anonymous, log_event, now, validate_asset_input, verify_asset_type,
-        MAX_NAME_LENGTH,
+        check_is_anonymous, describe_asset_changes, diff_heirs, log_event, merge_asset_type,
+        needs_derived_address, now, remainder_policy, validate_asset_input, validate_heir_accounts,
+        validate_heir_assignments, verify_asset_type, MAX_NAME_LENGTH,
     },
     storage::{
-        create_user, get_asset, get_user, get_vault, insert_asset, is_user_registered,
-        list_user_assets, next_asset_id, remove_asset,
+        archive_asset_version, create_user, get_asset, get_user, get_vault, insert_asset,
+        is_user_registered, list_asset_history, list_user_assets, next_asset_id, remove_asset,
+    },
+    types::{
+        ApiError, ArchiveConfig, ArchiveInfo, Asset, AssetType, AssetUpdate, AssetVersion,
+        AuditChainHead, AuditChainReport, AuditPage, AuditQuery, AuditStreamStats, Document,
+        DocumentUpload, EscrowAccount, EvmTransfer, HeirVisibility, InheritanceView, Letter,
+        LetterNotice, RecoveryAction, ReleaseJob, RemainderPolicy, SecretDecryptionKey,
+        SecretEncryptionKey, StorageUsage, UserProfile, Vault,
     },
-    types::{Asset, AssetType, UserProfile, Vault},
 };
 
+#[init]
+fn init() {
+    audit::certify_chain_head();
+    vault::start_switch_timer();
+    release::start_release_timer();
+    archive::start_archive_timer();
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    storage::migrate_owner_index();
+    storage::migrate_heir_index();
+    storage::migrate_audit_chain();
+    storage::migrate_audit_streams();
+    audit::certify_chain_head();
+    release::recover_release_jobs();
+    vault::start_switch_timer();
+    release::start_release_timer();
+    archive::start_archive_timer();
+}
+
 #[query]
 fn is_registered() -> bool {
     let caller = ic_cdk::api::msg_caller();
@@ -24,28 +66,31 @@ fn is_registered() -> bool {
 }
 
 #[update]
-fn register_user(first_name: String, last_name: String) -> Result<(), String> {
+fn register_user(first_name: String, last_name: String) -> Result<(), ApiError> {
     let caller = ic_cdk::api::msg_caller();
 
     if check_is_anonymous(&caller) {
-        return Err("Anonymous user cannot register".to_string());
+        return Err(ApiError::AnonymousNotAllowed);
     }
 
-    if first_name.is_empty() || last_name.is_empty() {
-        return Err("Name fields cannot be empty".to_string());
+    if first_name.is_empty() {
+        return Err(ApiError::validation("first_name", "cannot be empty"));
+    }
+    if last_name.is_empty() {
+        return Err(ApiError::validation("last_name", "cannot be empty"));
     }
 
     // Not Doing .len() cause it returns bytes and didnt knew that
     if first_name.chars().count() > MAX_NAME_LENGTH {
-        return Err(format!(
-            "First name too long (max {} bytes)",
-            MAX_NAME_LENGTH
+        return Err(ApiError::validation(
+            "first_name",
+            format!("too long (max {} characters)", MAX_NAME_LENGTH),
         ));
     }
     if last_name.chars().count() > MAX_NAME_LENGTH {
-        return Err(format!(
-            "Last name too long (max {} bytes)",
-            MAX_NAME_LENGTH
+        return Err(ApiError::validation(
+            "last_name",
+            format!("too long (max {} characters)", MAX_NAME_LENGTH),
         ));
     }
 
@@ -59,62 +104,120 @@ fn register_user(first_name: String, last_name: String) -> Result<(), String> {
 }
 
 #[query]
-fn get_profile() -> Result<UserProfile, String> {
+fn get_profile() -> Result<UserProfile, ApiError> {
     let caller = ic_cdk::api::msg_caller();
 
-    get_user(&caller).ok_or("User not registered".to_string())
+    get_user(&caller).ok_or(ApiError::NotRegistered)
 }
 
 #[update]
-fn create_vault() -> Result<(), String> {
+fn create_vault() -> Result<(), ApiError> {
     let caller = ic_cdk::api::msg_caller();
 
     if check_is_anonymous(&caller) {
-        return Err("Anonymous User Cannot Create Vault".to_string());
+        return Err(ApiError::AnonymousNotAllowed);
     }
 
     if !is_user_registered(&caller) {
-        return Err("User must first register before Creating Vault".to_string());
+        return Err(ApiError::NotRegistered);
     }
 
     vault::create_new_vault(&caller)?;
 
-    log_event(
-        types::EventType::VaultCreated,
-        &caller,
-        "Vault Created".to_string(),
-    );
+    log_event(types::EventType::VaultCreated, &caller);
 
     Ok(())
 }
 
 #[update]
-fn configure_dms(hearbeat_interval_d: u32, grace_period_d: u32) -> Result<(), String> {
+fn configure_dms(hearbeat_interval_d: u32, grace_period_d: u32) -> Result<(), ApiError> {
     let caller = &ic_cdk::api::msg_caller();
     vault::configure_switch(caller, hearbeat_interval_d, grace_period_d)
 }
 
+#[update]
+fn set_heir_visibility(visibility: HeirVisibility) -> Result<(), ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    vault::set_heir_visibility(&caller, visibility)
+}
+
+#[update]
+fn set_remainder_policy(policy: RemainderPolicy) -> Result<(), ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    vault::set_remainder_policy(&caller, policy)
+}
+
+#[update]
+fn configure_recovery(recovery_principals: Vec<Principal>, threshold: u32) -> Result<(), ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    vault::configure_recovery(&caller, recovery_principals.clone(), threshold)?;
+
+    log_event(
+        types::EventType::RecoveryConfigured {
+            recovery_principals,
+            threshold,
+        },
+        &caller,
+    );
+    Ok(())
+}
+
+#[update]
+fn initiate_recovery(owner: Principal, action: RecoveryAction) -> Result<(), ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+
+    if check_is_anonymous(&caller) {
+        return Err(ApiError::AnonymousNotAllowed);
+    }
+
+    vault::initiate_recovery(&caller, &owner, action)
+}
+
+#[update]
+fn approve_recovery(owner: Principal) -> Result<(), ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+
+    if check_is_anonymous(&caller) {
+        return Err(ApiError::AnonymousNotAllowed);
+    }
+
+    vault::approve_recovery(&caller, &owner)
+}
+
+#[update]
+fn execute_recovery(owner: Principal) -> Result<(), ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+
+    if check_is_anonymous(&caller) {
+        return Err(ApiError::AnonymousNotAllowed);
+    }
+
+    vault::execute_recovery(&caller, &owner)
+}
+
+#[update]
+fn cancel_recovery() -> Result<(), ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+    vault::cancel_recovery(&caller)
+}
+
 #[query]
-fn get_my_vault() -> Result<Vault, String> {
+fn get_my_vault() -> Result<Vault, ApiError> {
     let caller = &ic_cdk::api::msg_caller();
-    get_vault(caller).ok_or("No vault found".to_string())
+    get_vault(caller).ok_or(ApiError::VaultNotFound)
 }
 
 #[update]
-fn heartbeat() -> Result<(), String> {
+fn heartbeat() -> Result<(), ApiError> {
     let caller = ic_cdk::api::msg_caller();
 
     if check_is_anonymous(&caller) {
-        return Err("Anonymous User NOT ALLOWED".to_string());
+        return Err(ApiError::AnonymousNotAllowed);
     }
 
     vault::send_heartbeat(&caller)?;
 
-    log_event(
-        types::EventType::Heartbeat,
-        &caller,
-        "Heartbeat Received".to_string(),
-    );
+    log_event(types::EventType::Heartbeat, &caller);
     Ok(())
 }
 
@@ -124,26 +227,48 @@ async fn add_asset(
     desc: String,
     asset_type: AssetType,
     heir_assingment: Vec<types::HeirAssignment>,
-) -> Result<u64, String> {
+) -> Result<u64, ApiError> {
     let caller = ic_cdk::api::msg_caller();
 
     if check_is_anonymous(&caller) {
-        return Err("Anonymous principal not allowed".to_string());
+        return Err(ApiError::AnonymousNotAllowed);
     }
 
     if !is_user_registered(&caller) {
-        return Err("User must be registered before adding assets".to_string());
+        return Err(ApiError::NotRegistered);
     }
     validate_asset_input(&name, &desc)?;
 
-    verify_asset_type(&caller, &asset_type).await?;
-
-    let vault_ref = get_vault(&caller).ok_or("Vault not found".to_string())?;
+    let vault_ref = get_vault(&caller).ok_or(ApiError::VaultNotFound)?;
     if vault_ref.status == types::VaultStatus::Released {
-        return Err("Cannot add assets to released vault".to_string());
+        return Err(ApiError::VaultReleased);
     }
 
+    validate_heir_assignments(
+        &caller,
+        &asset_type,
+        &heir_assingment,
+        &remainder_policy(&vault_ref),
+    )?;
+    validate_heir_accounts(&asset_type, &heir_assingment)?;
+    escrow::ensure_sole_escrow_on_ledger(&caller, None, &asset_type)?;
+
+    verify_asset_type(&caller, &asset_type).await?;
+    // Another escrow asset may have been added while we waited on the ledger
+    escrow::ensure_sole_escrow_on_ledger(&caller, None, &asset_type)?;
+
     let asset_id = next_asset_id();
+    // The address comes from the asset id, so it can only be derived now
+    let asset_type = match asset_type {
+        AssetType::BitcoinAddress { .. } => bitcoin::derive_address(&caller, asset_id).await?,
+        AssetType::EvmAddress { .. } => evm::derive_address(&caller, asset_id, asset_type).await?,
+        other => other,
+    };
+
+    // The switch may have fired while we waited, and a released vault gets no new jobs
+    if get_vault(&caller).is_none_or(|v| v.status == types::VaultStatus::Released) {
+        return Err(ApiError::VaultReleased);
+    }
 
     let asset = Asset {
         id: asset_id,
@@ -153,17 +278,190 @@ async fn add_asset(
         description: desc,
         created_at: now(),
         heir_assingment,
+        transfers: None,
+        payout_from: None,
     };
 
     insert_asset(asset);
 
+    log_event(types::EventType::AssetCreated { asset_id, name }, &caller);
+
+    Ok(asset_id)
+}
+
+#[update]
+async fn update_asset(asset_id: u64, update: AssetUpdate) -> Result<(), ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+
+    if check_is_anonymous(&caller) {
+        return Err(ApiError::AnonymousNotAllowed);
+    }
+
+    let original = get_asset(asset_id).ok_or(ApiError::AssetNotFound)?;
+    if original.owner != caller {
+        return Err(ApiError::Unauthorized);
+    }
+
+    let vault_ref = get_vault(&caller).ok_or(ApiError::VaultNotFound)?;
+    if vault_ref.status == types::VaultStatus::Released {
+        return Err(ApiError::VaultReleased);
+    }
+
+    let mut updated = original.clone();
+    if let Some(name) = update.name {
+        updated.name = name;
+    }
+    if let Some(desc) = update.description {
+        updated.description = desc;
+    }
+    if let Some(asset_type) = update.asset_type {
+        updated.asset_type = merge_asset_type(&original.asset_type, asset_type);
+    }
+    if let Some(heir_assingment) = update.heir_assingment {
+        updated.heir_assingment = heir_assingment;
+    }
+
+    if updated == original {
+        return Ok(());
+    }
+
+    // Deposited tokens belong to the escrow asset until they are withdrawn
+    if let AssetType::ICRC1Escrow { amount, .. } | AssetType::ICPLedger { amount, .. } =
+        original.asset_type
+    {
+        if amount > 0 && updated.asset_type != original.asset_type {
+            return Err(ApiError::validation(
+                "asset_type",
+                "withdraw the escrowed balance before changing an escrow asset",
+            ));
+        }
+    }
+    // Only this canister can sign for the address, so nothing may be left behind on it
+    if let AssetType::EvmAddress {
+        amount, next_nonce, ..
+    } = original.asset_type
+    {
+        let still_evm = matches!(updated.asset_type, AssetType::EvmAddress { .. });
+        if amount > 0 && !still_evm {
+            return Err(ApiError::validation(
+                "asset_type",
+                "withdraw the balance with sign_evm_withdrawal before changing the asset",
+            ));
+        }
+        // Deriving the address again would start over at nonce 0
+        if next_nonce > 0 && !still_evm {
+            return Err(ApiError::validation(
+                "asset_type",
+                "an EVM address that has signed transactions can only be removed",
+            ));
+        }
+    }
+
+    validate_asset_input(&updated.name, &updated.description)?;
+    validate_heir_assignments(
+        &caller,
+        &updated.asset_type,
+        &updated.heir_assingment,
+        &remainder_policy(&vault_ref),
+    )?;
+    validate_heir_accounts(&updated.asset_type, &updated.heir_assingment)?;
+    escrow::ensure_sole_escrow_on_ledger(&caller, Some(asset_id), &updated.asset_type)?;
+
+    if updated.asset_type != original.asset_type {
+        verify_asset_type(&caller, &updated.asset_type).await?;
+        // What was just verified is the caller's own allowance or approval
+        updated.payout_from = None;
+        if let AssetType::BitcoinAddress { address, .. } = &original.asset_type {
+            bitcoin::ensure_empty(address, "asset_type", "changing the asset").await?;
+        }
+        if needs_derived_address(&original.asset_type, &updated.asset_type) {
+            updated.asset_type = match updated.asset_type {
+                AssetType::BitcoinAddress { .. } => {
+                    bitcoin::derive_address(&caller, asset_id).await?
+                }
+                AssetType::EvmAddress { .. } => {
+                    evm::derive_address(&caller, asset_id, updated.asset_type).await?
+                }
+                other => other,
+            };
+        }
+
+        // Anything could have happened to the asset while we were waiting on the ledger
+        if get_asset(asset_id).as_ref() != Some(&original) {
+            return Err(ApiError::ConcurrentModification);
+        }
+        escrow::ensure_sole_escrow_on_ledger(&caller, Some(asset_id), &updated.asset_type)?;
+        if get_vault(&caller).is_none_or(|v| v.status == types::VaultStatus::Released) {
+            return Err(ApiError::VaultReleased);
+        }
+    }
+
+    let heir_diff = diff_heirs(&original.heir_assingment, &updated.heir_assingment);
+    let changes = describe_asset_changes(&original, &updated);
+
+    let archived_version = archive_asset_version(original, now());
+    insert_asset(updated);
+
     log_event(
-        types::EventType::AssetCreated,
+        types::EventType::AssetUpdated {
+            asset_id,
+            archived_version,
+            changes,
+        },
         &caller,
-        format!("Asset created: {}", name),
     );
+    for heir in heir_diff.added {
+        log_event(
+            types::EventType::HeirAdded {
+                asset_id,
+                heir: heir.heir_principal,
+                percentage: heir.percentage,
+            },
+            &caller,
+        );
+    }
+    for heir in heir_diff.removed {
+        log_event(
+            types::EventType::HeirRemoved {
+                asset_id,
+                heir: heir.heir_principal,
+                percentage: heir.percentage,
+            },
+            &caller,
+        );
+    }
+    for (heir, old_percentage, new_percentage) in heir_diff.changed {
+        log_event(
+            types::EventType::HeirChanged {
+                asset_id,
+                heir,
+                old_percentage,
+                new_percentage,
+            },
+            &caller,
+        );
+    }
 
-    Ok(asset_id)
+    Ok(())
+}
+
+#[query]
+fn get_asset_history(asset_id: u64) -> Result<Vec<AssetVersion>, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+
+    let history = list_asset_history(asset_id);
+
+    // Check against the history too so removed assets can still be looked up
+    let owner = get_asset(asset_id)
+        .map(|asset| asset.owner)
+        .or_else(|| history.first().map(|v| v.asset.owner))
+        .ok_or(ApiError::AssetNotFound)?;
+
+    if owner != caller {
+        return Err(ApiError::Unauthorized);
+    }
+
+    Ok(history)
 }
 
 #[query]
@@ -174,42 +472,371 @@ fn list_my_assets() -> Vec<Asset> {
 }
 
 #[query]
-fn get_asset_by_id(asset_id: u64) -> Result<Asset, String> {
+fn get_asset_by_id(asset_id: u64) -> Result<Asset, ApiError> {
     let caller = ic_cdk::api::msg_caller();
 
-    let asset = get_asset(asset_id).ok_or("Asset not found".to_string())?;
+    let asset = get_asset(asset_id).ok_or(ApiError::AssetNotFound)?;
 
     if asset.owner != caller {
-        return Err("Not authorized to view this asset".to_string());
+        return Err(ApiError::Unauthorized);
     }
 
     Ok(asset)
 }
 
 #[update]
-fn remove_asset_by_id(asset_id: u64) -> Result<(), String> {
+async fn remove_asset_by_id(asset_id: u64) -> Result<(), ApiError> {
     let caller = ic_cdk::api::msg_caller();
 
-    let asset = get_asset(asset_id).ok_or("Asset not found".to_string())?;
+    let asset = get_asset(asset_id).ok_or(ApiError::AssetNotFound)?;
 
     if asset.owner != caller {
-        return Err("Not authorized to remove this asset".to_string());
+        return Err(ApiError::Unauthorized);
     }
 
-    let vault = get_vault(&caller).ok_or("Vault not found".to_string())?;
+    let vault = get_vault(&caller).ok_or(ApiError::VaultNotFound)?;
     if vault.status == types::VaultStatus::Released {
-        return Err("Cannot remove assets from released vault".to_string());
+        return Err(ApiError::VaultReleased);
+    }
+
+    if let AssetType::ICRC1Escrow { amount, .. } | AssetType::ICPLedger { amount, .. } =
+        asset.asset_type
+    {
+        if amount > 0 {
+            return Err(ApiError::validation(
+                "asset_id",
+                "withdraw the escrowed balance before removing the asset",
+            ));
+        }
+    }
+    if let AssetType::EvmAddress { amount, .. } = asset.asset_type {
+        if amount > 0 {
+            return Err(ApiError::validation(
+                "asset_id",
+                "withdraw the balance with sign_evm_withdrawal before removing the asset",
+            ));
+        }
+    }
+
+    // Asked last, once nothing local stands in the way
+    if let AssetType::BitcoinAddress { address, .. } = &asset.asset_type {
+        bitcoin::ensure_empty(address, "asset_id", "removing the asset").await?;
+        if get_asset(asset_id).as_ref() != Some(&asset) {
+            return Err(ApiError::ConcurrentModification);
+        }
+        if get_vault(&caller).is_none_or(|v| v.status == types::VaultStatus::Released) {
+            return Err(ApiError::VaultReleased);
+        }
     }
 
     remove_asset(asset_id);
+    documents::detach_asset(&caller, asset_id);
 
     log_event(
-        types::EventType::AssetDeleted,
+        types::EventType::AssetDeleted {
+            asset_id,
+            name: asset.name,
+        },
         &caller,
-        format!("Asset deleted: {}", asset.name),
     );
 
     Ok(())
 }
 
+#[query]
+fn get_escrow_account(asset_id: u64) -> Result<EscrowAccount, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+
+    escrow::get_escrow_account(&caller, asset_id)
+}
+
+#[update]
+async fn sync_escrow_deposit(asset_id: u64) -> Result<u64, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+
+    if check_is_anonymous(&caller) {
+        return Err(ApiError::AnonymousNotAllowed);
+    }
+
+    escrow::sync_escrow_deposit(&caller, asset_id).await
+}
+
+#[update]
+async fn withdraw_escrow(asset_id: u64, amount: u64) -> Result<Nat, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+
+    if check_is_anonymous(&caller) {
+        return Err(ApiError::AnonymousNotAllowed);
+    }
+
+    escrow::withdraw_escrow(&caller, asset_id, amount).await
+}
+
+#[update]
+async fn sign_evm_withdrawal(
+    asset_id: u64,
+    to: String,
+    value: u128,
+) -> Result<EvmTransfer, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+
+    if check_is_anonymous(&caller) {
+        return Err(ApiError::AnonymousNotAllowed);
+    }
+
+    evm::sign_withdrawal(&caller, asset_id, to, value).await
+}
+
+#[query]
+fn get_evm_transactions(asset_id: u64) -> Result<Vec<EvmTransfer>, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+
+    evm::list_transactions(&caller, asset_id)
+}
+
+#[update]
+async fn get_secret_encryption_key(heir: Principal) -> Result<SecretEncryptionKey, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+
+    if check_is_anonymous(&caller) {
+        return Err(ApiError::AnonymousNotAllowed);
+    }
+
+    secret::get_secret_encryption_key(&caller, &heir).await
+}
+
+#[update]
+async fn get_secret_decryption_key(
+    asset_id: u64,
+    transport_public_key: Vec<u8>,
+) -> Result<SecretDecryptionKey, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+
+    if check_is_anonymous(&caller) {
+        return Err(ApiError::AnonymousNotAllowed);
+    }
+
+    secret::get_secret_decryption_key(&caller, asset_id, transport_public_key).await
+}
+
+#[update]
+fn start_document_upload(upload: DocumentUpload) -> Result<u64, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+
+    if check_is_anonymous(&caller) {
+        return Err(ApiError::AnonymousNotAllowed);
+    }
+
+    documents::start_upload(&caller, upload)
+}
+
+#[update]
+fn put_document_chunk(document_id: u64, index: u32, chunk: Vec<u8>) -> Result<(), ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+
+    documents::put_chunk(&caller, document_id, index, chunk)
+}
+
+#[update]
+fn commit_document_upload(document_id: u64, sha256: Vec<u8>) -> Result<Document, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+
+    documents::commit_upload(&caller, document_id, sha256)
+}
+
+#[update]
+fn delete_document(document_id: u64) -> Result<(), ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+
+    documents::delete_document(&caller, document_id)
+}
+
+#[update]
+fn set_document_access(document_id: u64, access: Vec<Principal>) -> Result<(), ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+
+    documents::set_access(&caller, document_id, access)
+}
+
+#[query]
+fn list_my_documents() -> Vec<Document> {
+    let caller = ic_cdk::api::msg_caller();
+
+    storage::list_user_documents(&caller)
+}
+
+#[query]
+fn get_storage_usage() -> StorageUsage {
+    let caller = ic_cdk::api::msg_caller();
+
+    documents::storage_usage(&caller)
+}
+
+#[query]
+fn list_inherited_documents() -> Vec<Document> {
+    let caller = ic_cdk::api::msg_caller();
+
+    documents::list_inherited(&caller)
+}
+
+#[query]
+fn get_document_chunk(document_id: u64, index: u32) -> Result<Vec<u8>, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+
+    documents::read_chunk(&caller, document_id, index)
+}
+
+#[update]
+fn write_letter(heir: Principal, subject: String, body: String) -> Result<(), ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+
+    if check_is_anonymous(&caller) {
+        return Err(ApiError::AnonymousNotAllowed);
+    }
+
+    letters::write_letter(&caller, heir, subject, body)
+}
+
+#[update]
+fn delete_letter(heir: Principal) -> Result<(), ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+
+    letters::delete_letter(&caller, &heir)
+}
+
+#[query]
+fn list_my_letters() -> Vec<Letter> {
+    let caller = ic_cdk::api::msg_caller();
+
+    storage::list_user_letters(&caller)
+}
+
+#[query]
+fn list_received_letters() -> Vec<LetterNotice> {
+    let caller = ic_cdk::api::msg_caller();
+
+    letters::list_received(&caller)
+}
+
+#[update]
+fn read_letter(owner: Principal) -> Result<Letter, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+
+    if check_is_anonymous(&caller) {
+        return Err(ApiError::AnonymousNotAllowed);
+    }
+
+    letters::read_letter(&caller, &owner)
+}
+
+#[query]
+fn get_release_jobs() -> Vec<ReleaseJob> {
+    let caller = ic_cdk::api::msg_caller();
+
+    release::list_release_jobs(&caller)
+}
+
+#[query]
+fn list_my_inheritances() -> Vec<InheritanceView> {
+    let caller = ic_cdk::api::msg_caller();
+
+    heir::list_inheritances(&caller)
+}
+
+#[update]
+async fn claim_inheritance(asset_id: u64) -> Result<ReleaseJob, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+
+    if check_is_anonymous(&caller) {
+        return Err(ApiError::AnonymousNotAllowed);
+    }
+
+    release::claim_share(&caller, asset_id).await
+}
+
+#[query]
+fn get_my_audit_log(query: AuditQuery) -> AuditPage {
+    let caller = ic_cdk::api::msg_caller();
+
+    audit::query_audit_log(&query, Some(&caller))
+}
+
+// The wasm new archive canisters are created with. The uploading controller also becomes a
+// controller of every archive created from it, so it can read them directly.
+#[update]
+fn admin_set_archive_wasm(wasm: Vec<u8>) -> Result<(), ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err(ApiError::Unauthorized);
+    }
+    if !(wasm.starts_with(b"\0asm") || wasm.starts_with(&[0x1f, 0x8b])) {
+        return Err(ApiError::validation(
+            "wasm",
+            "Not a wasm module or gzipped wasm module",
+        ));
+    }
+
+    storage::set_archive_config(ArchiveConfig {
+        wasm,
+        controllers: vec![caller],
+    });
+    Ok(())
+}
+
+// Ships a batch right away instead of waiting for the timer
+#[update]
+async fn admin_ship_audit_archive() -> Result<u64, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err(ApiError::Unauthorized);
+    }
+
+    archive::ship_archive_queue()
+        .await
+        .map_err(|reason| ApiError::ArchiveUnavailable { reason })
+}
+
+#[query]
+fn get_audit_archives() -> Vec<ArchiveInfo> {
+    storage::list_archives()
+}
+
+// Evicted events not yet shipped to an archive, across all streams
+#[query]
+fn get_audit_archive_backlog() -> u64 {
+    storage::archive_queue_len()
+}
+
+// Head of the audit hash chain with the certificate covering it
+#[query]
+fn get_audit_chain_head() -> AuditChainHead {
+    audit::chain_head()
+}
+
+#[query]
+fn verify_audit_chain(from: u64, to: u64) -> Result<AuditChainReport, ApiError> {
+    audit::verify_chain(from, to)
+}
+
+// Retention counters for the caller's audit stream, including compacted heartbeats
+#[query]
+fn get_my_audit_retention() -> AuditStreamStats {
+    let caller = ic_cdk::api::msg_caller();
+
+    storage::get_audit_stream_stats(&caller)
+}
+
+#[query]
+fn admin_get_audit_log(query: AuditQuery, blame: Option<Principal>) -> Result<AuditPage, ApiError> {
+    let caller = ic_cdk::api::msg_caller();
+
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err(ApiError::Unauthorized);
+    }
+
+    Ok(audit::query_audit_log(&query, blame.as_ref()))
+}
+
 ic_cdk::export_candid!();
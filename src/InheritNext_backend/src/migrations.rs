@@ -0,0 +1,504 @@
+// Versioned encoding for everything kept in stable memory.
+//
+// Stored values are `MAGIC ++ version ++ candid`. Values written before versioning existed
+// are plain candid (starting with "DIDL") and are treated as version 0.
+//
+// Candid already reads a value into a type that gained `opt` fields (as None) or variants,
+// so those changes need nothing here. Any other change, to a type or to anything nested
+// in it, means: bump its `VERSION`, copy the old layout into a `...Vn` struct built only
+// from the frozen types in this file, and read it in `decode_legacy`.
+
+use std::fmt;
+
+use candid::{CandidType, Principal};
+use serde::{de::DeserializeOwned, Deserialize};
+
+use crate::types::{
+    ArchiveConfig, ArchiveInfo, Asset, AssetVersion, AuditEvent, AuditStreamStats, Document,
+    EventKind, EventType, Letter, OwnerAlias, ReleaseJob, UserProfile, Vault,
+};
+
+const MAGIC: &[u8; 3] = b"INX";
+const LEGACY_PREFIX: &[u8; 4] = b"DIDL";
+
+pub trait Versioned: CandidType + DeserializeOwned {
+    const NAME: &'static str;
+    const VERSION: u8;
+
+    // Reads the versions whose layout the current type can't be decoded from. None means
+    // plain candid decoding works for `version`.
+    fn decode_legacy(_version: u8, _payload: &[u8]) -> Option<candid::Result<Self>> {
+        None
+    }
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Unrecognised,
+    TooNew { version: u8 },
+    Candid(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Unrecognised => write!(f, "unrecognised encoding"),
+            DecodeError::TooNew { version } => {
+                write!(f, "version {} is newer than this canister", version)
+            }
+            DecodeError::Candid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+pub fn encode<T: Versioned>(value: &T) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(T::VERSION);
+    bytes.extend(candid::encode_one(value).expect("Failed to encode stored value"));
+    bytes
+}
+
+fn split_version(bytes: &[u8]) -> Option<(u8, &[u8])> {
+    if bytes.starts_with(LEGACY_PREFIX) {
+        return Some((0, bytes));
+    }
+    if bytes.len() > MAGIC.len() && bytes.starts_with(MAGIC) {
+        return Some((bytes[MAGIC.len()], &bytes[MAGIC.len() + 1..]));
+    }
+    None
+}
+
+pub fn try_decode<T: Versioned>(bytes: &[u8]) -> Result<T, DecodeError> {
+    let (version, payload) = split_version(bytes).ok_or(DecodeError::Unrecognised)?;
+    if version > T::VERSION {
+        return Err(DecodeError::TooNew { version });
+    }
+    T::decode_legacy(version, payload)
+        .unwrap_or_else(|| candid::decode_one(payload))
+        .map_err(|e| DecodeError::Candid(e.to_string()))
+}
+
+// Stable memory that can't be read is not something to carry on from, so this is the one
+// place that traps
+pub fn decode<T: Versioned>(bytes: &[u8]) -> T {
+    try_decode(bytes).unwrap_or_else(|e| {
+        ic_cdk::api::trap(format!(
+            "Failed to decode stored {} - storage corruption detected: {}",
+            T::NAME,
+            e
+        ))
+    })
+}
+
+impl Versioned for UserProfile {
+    const NAME: &'static str = "UserProfile";
+    const VERSION: u8 = 1;
+}
+
+impl Versioned for Vault {
+    const NAME: &'static str = "Vault";
+    const VERSION: u8 = 1;
+}
+
+impl Versioned for Asset {
+    const NAME: &'static str = "Asset";
+    const VERSION: u8 = 1;
+}
+
+impl Versioned for AssetVersion {
+    const NAME: &'static str = "AssetVersion";
+    const VERSION: u8 = 1;
+}
+
+impl Versioned for ArchiveInfo {
+    const NAME: &'static str = "ArchiveInfo";
+    const VERSION: u8 = 1;
+}
+
+impl Versioned for OwnerAlias {
+    const NAME: &'static str = "OwnerAlias";
+    const VERSION: u8 = 1;
+}
+
+impl Versioned for ArchiveConfig {
+    const NAME: &'static str = "ArchiveConfig";
+    const VERSION: u8 = 1;
+}
+
+impl Versioned for Document {
+    const NAME: &'static str = "Document";
+    const VERSION: u8 = 1;
+}
+
+impl Versioned for Letter {
+    const NAME: &'static str = "Letter";
+    const VERSION: u8 = 1;
+}
+
+impl Versioned for ReleaseJob {
+    const NAME: &'static str = "ReleaseJob";
+    const VERSION: u8 = 1;
+}
+
+impl Versioned for AuditStreamStats {
+    const NAME: &'static str = "AuditStreamStats";
+    const VERSION: u8 = 1;
+}
+
+// Event types before they carried payloads
+#[derive(CandidType, Deserialize)]
+enum EventTypeV0 {
+    VaultCreated,
+    AssetCreated,
+    AssetUpdated,
+    AssetDeleted,
+    HeirAdded,
+    HeirRemoved,
+    Heartbeat,
+    SwitchPending,
+    VaultReleased,
+    RecoveryInitiated,
+}
+
+impl From<EventTypeV0> for EventKind {
+    fn from(v0: EventTypeV0) -> Self {
+        match v0 {
+            EventTypeV0::VaultCreated => EventKind::VaultCreated,
+            EventTypeV0::AssetCreated => EventKind::AssetCreated,
+            EventTypeV0::AssetUpdated => EventKind::AssetUpdated,
+            EventTypeV0::AssetDeleted => EventKind::AssetDeleted,
+            EventTypeV0::HeirAdded => EventKind::HeirAdded,
+            EventTypeV0::HeirRemoved => EventKind::HeirRemoved,
+            EventTypeV0::Heartbeat => EventKind::Heartbeat,
+            EventTypeV0::SwitchPending => EventKind::SwitchPending,
+            EventTypeV0::VaultReleased => EventKind::VaultReleased,
+            EventTypeV0::RecoveryInitiated => EventKind::RecoveryInitiated,
+        }
+    }
+}
+
+// The unversioned layout, from before typed payloads and the hash chain
+#[derive(CandidType, Deserialize)]
+struct AuditEventV0 {
+    timestamp: u64,
+    event_type: EventTypeV0,
+    blame: Principal,
+    details: String,
+}
+
+impl From<AuditEventV0> for AuditEvent {
+    fn from(v0: AuditEventV0) -> Self {
+        AuditEvent {
+            timestamp: v0.timestamp,
+            event_type: EventType::Legacy {
+                kind: v0.event_type.into(),
+                details: v0.details,
+            },
+            blame: v0.blame,
+            prev_hash: None,
+        }
+    }
+}
+
+impl Versioned for AuditEvent {
+    const NAME: &'static str = "AuditEvent";
+    const VERSION: u8 = 1;
+
+    fn decode_legacy(version: u8, payload: &[u8]) -> Option<candid::Result<Self>> {
+        (version == 0).then(|| candid::decode_one::<AuditEventV0>(payload).map(Into::into))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Nat;
+
+    use super::*;
+    use crate::types::{AssetType, JobState, VaultStatus};
+
+    // The unversioned layouts the canister first shipped with. Nested types are spelled
+    // out here too, so a later change to the live types can't quietly change the fixtures.
+
+    fn principal(n: u8) -> Principal {
+        Principal::from_slice(&[n; 29])
+    }
+
+    #[derive(CandidType)]
+    enum VaultStatusF {
+        Active,
+    }
+
+    #[derive(CandidType)]
+    struct DeadManSwitchF {
+        last_heartbeat: u64,
+        heartbeat_interval: u64,
+        grace_period: u64,
+        pending_since: Option<u64>,
+    }
+
+    #[derive(CandidType)]
+    struct RecoveryConfigF {
+        recovery_principals: Vec<Principal>,
+        threshold: u32,
+    }
+
+    #[derive(CandidType)]
+    enum AssetTypeF {
+        ICRC2Token {
+            ledger_canister: Principal,
+            amount: u64,
+        },
+    }
+
+    #[derive(CandidType)]
+    struct HeirAssignmentF {
+        heir_principal: Principal,
+        percentage: u8,
+    }
+
+    #[derive(CandidType)]
+    struct AssetF {
+        id: u64,
+        owner: Principal,
+        asset_type: AssetTypeF,
+        name: String,
+        description: String,
+        created_at: u64,
+        heir_assingment: Vec<HeirAssignmentF>,
+    }
+
+    #[derive(CandidType)]
+    enum EventTypeF {
+        Heartbeat,
+    }
+
+    fn unversioned<T: CandidType>(value: &T) -> Vec<u8> {
+        candid::encode_one(value).unwrap()
+    }
+
+    fn round_trips<T: Versioned + PartialEq + fmt::Debug>(value: T) {
+        assert_eq!(try_decode::<T>(&encode(&value)).unwrap(), value);
+    }
+
+    #[test]
+    fn unversioned_user_profile() {
+        #[derive(CandidType)]
+        struct UserProfileF {
+            first_name: String,
+            last_name: String,
+            created_at: u64,
+        }
+        let profile: UserProfile = try_decode(&unversioned(&UserProfileF {
+            first_name: "Ada".to_string(),
+            last_name: "Lovelace".to_string(),
+            created_at: 5,
+        }))
+        .unwrap();
+        assert_eq!(profile.first_name, "Ada");
+        assert_eq!(profile.created_at, 5);
+
+        let current: UserProfile = try_decode(&encode(&profile)).unwrap();
+        assert_eq!(current.last_name, "Lovelace");
+    }
+
+    #[test]
+    fn unversioned_vault() {
+        #[derive(CandidType)]
+        struct VaultF {
+            owner: Principal,
+            created_at: u64,
+            status: VaultStatusF,
+            dms: DeadManSwitchF,
+            recovery_config: Option<RecoveryConfigF>,
+            next_asset_id: u64,
+        }
+        let vault: Vault = try_decode(&unversioned(&VaultF {
+            owner: principal(1),
+            created_at: 4,
+            status: VaultStatusF::Active,
+            dms: DeadManSwitchF {
+                last_heartbeat: 1,
+                heartbeat_interval: 2,
+                grace_period: 3,
+                pending_since: None,
+            },
+            recovery_config: Some(RecoveryConfigF {
+                recovery_principals: vec![principal(3)],
+                threshold: 1,
+            }),
+            next_asset_id: 6,
+        }))
+        .unwrap();
+        assert_eq!(vault.owner, principal(1));
+        assert_eq!(vault.status, VaultStatus::Active);
+        assert_eq!(vault.dms.grace_period, 3);
+        assert_eq!(vault.next_asset_id, 6);
+        assert_eq!(vault.recovery_config.as_ref().unwrap().threshold, 1);
+        assert_eq!(vault.escrow_subaccount, None);
+
+        round_trips(Vault {
+            escrow_subaccount: Some([8; 32]),
+            ..vault
+        });
+    }
+
+    #[test]
+    fn unversioned_asset() {
+        let asset: Asset = try_decode(&unversioned(&AssetF {
+            id: 7,
+            owner: principal(1),
+            asset_type: AssetTypeF::ICRC2Token {
+                ledger_canister: principal(9),
+                amount: 1_000,
+            },
+            name: "n".to_string(),
+            description: "d".to_string(),
+            created_at: 4,
+            heir_assingment: vec![HeirAssignmentF {
+                heir_principal: principal(2),
+                percentage: 100,
+            }],
+        }))
+        .unwrap();
+        assert_eq!(asset.id, 7);
+        assert_eq!(
+            asset.asset_type,
+            AssetType::ICRC2Token {
+                ledger_canister: principal(9),
+                amount: 1_000,
+            }
+        );
+        assert_eq!(asset.heir_assingment[0].heir_principal, principal(2));
+        assert_eq!(asset.heir_assingment[0].account_identifier, None);
+        assert_eq!(asset.transfers, None);
+        assert_eq!(asset.payout_from, None);
+
+        round_trips(asset.clone());
+        round_trips(AssetVersion {
+            version: 2,
+            asset,
+            replaced_at: 9,
+        });
+    }
+
+    #[test]
+    fn unversioned_audit_event() {
+        #[derive(CandidType)]
+        struct AuditEventF {
+            timestamp: u64,
+            event_type: EventTypeF,
+            blame: Principal,
+            details: String,
+        }
+        let event: AuditEvent = try_decode(&unversioned(&AuditEventF {
+            timestamp: 3,
+            event_type: EventTypeF::Heartbeat,
+            blame: principal(1),
+            details: "beat".to_string(),
+        }))
+        .unwrap();
+        assert_eq!(event.timestamp, 3);
+        assert_eq!(event.blame, principal(1));
+        assert_eq!(
+            event.event_type,
+            EventType::Legacy {
+                kind: EventKind::Heartbeat,
+                details: "beat".to_string(),
+            }
+        );
+        assert_eq!(event.prev_hash, None);
+
+        round_trips(event.clone());
+        round_trips(AuditEvent {
+            event_type: EventType::Heartbeat,
+            prev_hash: Some(vec![7; 32]),
+            ..event
+        });
+    }
+
+    #[test]
+    fn types_without_legacy_layouts_round_trip() {
+        round_trips(ReleaseJob {
+            owner: principal(1),
+            asset_id: 7,
+            heir: principal(2),
+            ledger_canister: principal(9),
+            share: 500,
+            fee: Some(10),
+            state: JobState::Succeeded {
+                block_index: Nat::from(3u64),
+            },
+            attempts: 1,
+            next_attempt_at: 11,
+            created_at_time: 12,
+            outcome_unknown: true,
+            from_subaccount: Some([4; 32]),
+            to_account_identifier: None,
+            token_ids: Some(vec![Nat::from(1u64)]),
+            grants_control: false,
+            calls_back: true,
+            bitcoin_address: Some("bc1q".to_string()),
+            bitcoin_tx: Some(vec![1, 2]),
+            bitcoin_extra_txs: Some(vec![vec![3]]),
+            evm_transfer: None,
+            payout_from: Some(principal(3)),
+        });
+        round_trips(AuditStreamStats {
+            archived_events: 3,
+            dropped_events: 1,
+            ..Default::default()
+        });
+        round_trips(ArchiveInfo {
+            canister_id: principal(4),
+            installed: true,
+            first_event_id: Some(1),
+            last_event_id: Some(9),
+            events: 9,
+            created_at: 2,
+            aliases_pushed: Some(1),
+        });
+        round_trips(OwnerAlias {
+            previous: principal(5),
+            owner: None,
+        });
+        round_trips(ArchiveConfig {
+            wasm: vec![0, 97, 115, 109],
+            controllers: vec![principal(5)],
+        });
+        round_trips(Letter {
+            owner: principal(1),
+            heir: principal(2),
+            subject: "s".to_string(),
+            body: "b".to_string(),
+            written_at: 3,
+            read_at: None,
+        });
+    }
+
+    #[test]
+    fn unknown_encodings_are_rejected() {
+        let profile = UserProfile {
+            first_name: "a".to_string(),
+            last_name: "b".to_string(),
+            created_at: 1,
+        };
+        let mut too_new = encode(&profile);
+        too_new[MAGIC.len()] = UserProfile::VERSION + 1;
+        assert!(matches!(
+            try_decode::<UserProfile>(&too_new),
+            Err(DecodeError::TooNew { version }) if version == UserProfile::VERSION + 1
+        ));
+
+        assert!(matches!(
+            try_decode::<UserProfile>(b"garbage"),
+            Err(DecodeError::Unrecognised)
+        ));
+
+        let mut truncated = encode(&profile);
+        truncated.truncate(truncated.len() - 1);
+        assert!(matches!(
+            try_decode::<UserProfile>(&truncated),
+            Err(DecodeError::Candid(_))
+        ));
+    }
+}
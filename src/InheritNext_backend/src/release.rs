@@ -0,0 +1,1119 @@
+use std::{cell::RefCell, collections::BTreeMap, time::Duration};
+
+use candid::{Nat, Principal};
+use ic_cdk::{
+    call::{Call, CallErrorExt},
+    management_canister::{
+        canister_status, update_settings, CanisterSettings, CanisterStatusArgs, UpdateSettingsArgs,
+    },
+};
+use icrc_ledger_types::{
+    icrc1::{
+        account::{Account, Subaccount},
+        transfer::{Memo, TransferArg, TransferError},
+    },
+    icrc2::transfer_from::{TransferFromArgs, TransferFromError},
+};
+
+use crate::{
+    bitcoin, escrow, evm,
+    helpers::{
+        compute_heir_shares, compute_wide_heir_shares, log_event, now, remainder_policy,
+        CALLBACK_SHARE_SCALE, CLAIM_COOLDOWN, MAX_CANISTER_CONTROLLERS, MAX_RELEASE_ATTEMPTS,
+        MAX_RELEASE_JOBS_PER_TICK, RELEASE_JOB_INTERVAL_SECS, RELEASE_RETRY_BASE_DELAY,
+        RELEASE_RETRY_MAX_DELAY,
+    },
+    storage::{
+        self, get_asset, get_release_job, get_vault, insert_asset, insert_release_job,
+        release_job_exists,
+    },
+    types::{
+        AccountIdentifier, ApiError, Asset, AssetType, EventType, EvmTransfer, HeirTransfer,
+        IcpTimeStamp, IcpTransferArgs, IcpTransferError, InheritNextReleaseArgs,
+        InheritNextReleaseError, JobState, NftTransferFromArg, NftTransferFromError, ReleaseJob,
+        ReleaseJobKey, RemainderPolicy, Tokens, TransferStatus, Vault, VaultStatus,
+    },
+    vault,
+};
+
+thread_local! {
+    static LAST_CLAIMED: RefCell<BTreeMap<ReleaseJobKey, u64>> =
+        const { RefCell::new(BTreeMap::new()) };
+}
+
+pub enum AttemptError {
+    // The ledger never executed the call, safe to retry as is
+    Clean(String),
+    // The call may have been executed, only the ledger's dedup makes a retry safe
+    Unknown(String),
+}
+
+// Timers do not survive upgrades, so this has to be called from both init and post_upgrade
+pub fn start_release_timer() {
+    ic_cdk_timers::set_timer_interval_serial(
+        Duration::from_secs(RELEASE_JOB_INTERVAL_SECS),
+        async || process_release_jobs().await,
+    );
+}
+
+// Called from post_upgrade. A job still InFlight never saw its callback, so we don't
+// know whether the ledger executed it. Released vaults are re-enqueued in case they
+// were released before their jobs got written.
+pub fn recover_release_jobs() {
+    for mut job in storage::list_in_flight_release_jobs() {
+        job.state = JobState::FailedRetryable {
+            reason: "Interrupted before the ledger replied".to_string(),
+        };
+        job.outcome_unknown = true;
+        job.next_attempt_at = now();
+        insert_release_job(job);
+    }
+
+    for owner in storage::list_vault_owners() {
+        if get_vault(&owner).is_some_and(|vault| vault.status == VaultStatus::Released) {
+            enqueue_vault_release(&owner);
+        }
+    }
+}
+
+pub fn enqueue_vault_release(owner: &Principal) {
+    let Some(vault) = get_vault(owner) else {
+        return;
+    };
+    for asset in storage::list_user_assets(owner) {
+        enqueue_asset_release(&asset, &vault);
+    }
+
+    // Don't wait for the next tick to start paying out
+    ic_cdk_timers::set_timer(Duration::ZERO, process_release_jobs());
+}
+
+fn enqueue_asset_release(asset: &Asset, vault: &Vault) {
+    let cur_time = now();
+
+    let (ledger_canister, amount, from_subaccount) = match &asset.asset_type {
+        AssetType::ICRC2Token {
+            ledger_canister,
+            amount,
+        } => (*ledger_canister, *amount, None),
+        AssetType::ICRC1Escrow {
+            ledger_canister,
+            amount,
+        }
+        | AssetType::ICPLedger {
+            ledger_canister,
+            amount,
+        } => (
+            *ledger_canister,
+            *amount,
+            Some(escrow::vault_subaccount(vault)),
+        ),
+        AssetType::ICRC7Nft {
+            collection,
+            token_ids,
+        } => return enqueue_nft_release(asset, vault, *collection, token_ids),
+        AssetType::CanisterControl { canister_id, .. } => {
+            return enqueue_control_release(asset, *canister_id)
+        }
+        AssetType::Callback { canister, .. } => (*canister, CALLBACK_SHARE_SCALE, None),
+        // Nothing moves, heirs fetch their decryption key from `secret` once released
+        AssetType::EncryptedSecret { .. } => return,
+        AssetType::BitcoinAddress { .. } => return enqueue_bitcoin_release(asset),
+        AssetType::EvmAddress { .. } => return enqueue_evm_release(asset, vault),
+    };
+    let legacy_icp = matches!(asset.asset_type, AssetType::ICPLedger { .. });
+    let calls_back = matches!(asset.asset_type, AssetType::Callback { .. });
+
+    // The same heir listed twice still gets a single job for the combined share
+    let mut shares: BTreeMap<Principal, u64> = BTreeMap::new();
+    for (heir, share) in
+        compute_heir_shares(amount, &asset.heir_assingment, &remainder_policy(vault))
+    {
+        *shares.entry(heir).or_default() += share;
+    }
+
+    for (heir, share) in shares {
+        let key = ReleaseJobKey {
+            asset_id: asset.id,
+            heir,
+        };
+        if release_job_exists(&key) {
+            continue;
+        }
+
+        // Fixed here so retries of an unconfirmed attempt always target the same account
+        let to_account_identifier = legacy_icp.then(|| {
+            let assignment = asset
+                .heir_assingment
+                .iter()
+                .find(|h| h.heir_principal == heir);
+            vault::heir_account_identifier(&heir, assignment)
+        });
+
+        insert_release_job(ReleaseJob {
+            owner: asset.owner,
+            asset_id: asset.id,
+            heir,
+            ledger_canister,
+            share,
+            // Callbacks move no tokens, so there is no fee to take from the share
+            fee: calls_back.then_some(0),
+            state: JobState::Queued,
+            attempts: 0,
+            next_attempt_at: cur_time,
+            created_at_time: cur_time,
+            outcome_unknown: false,
+            from_subaccount,
+            to_account_identifier,
+            token_ids: None,
+            grants_control: false,
+            calls_back,
+            bitcoin_address: None,
+            bitcoin_tx: None,
+            bitcoin_extra_txs: None,
+            evm_transfer: None,
+            payout_from: asset.payout_from,
+        });
+    }
+}
+
+// One job per heir moves all of their tokens. Tokens nobody was given only leave the
+// owner under a residual heir policy.
+fn enqueue_nft_release(asset: &Asset, vault: &Vault, collection: Principal, token_ids: &[Nat]) {
+    let cur_time = now();
+
+    let mut allocation: BTreeMap<Principal, Vec<Nat>> = BTreeMap::new();
+    for heir in &asset.heir_assingment {
+        allocation
+            .entry(heir.heir_principal)
+            .or_default()
+            .extend(heir.token_ids.clone().unwrap_or_default());
+    }
+    if let RemainderPolicy::ToHeir { heir } = remainder_policy(vault) {
+        let unassigned: Vec<Nat> = token_ids
+            .iter()
+            .filter(|t| !allocation.values().any(|tokens| tokens.contains(t)))
+            .cloned()
+            .collect();
+        allocation.entry(heir).or_default().extend(unassigned);
+    }
+
+    for (heir, tokens) in allocation {
+        let key = ReleaseJobKey {
+            asset_id: asset.id,
+            heir,
+        };
+        if tokens.is_empty() || release_job_exists(&key) {
+            continue;
+        }
+
+        insert_release_job(ReleaseJob {
+            owner: asset.owner,
+            asset_id: asset.id,
+            heir,
+            ledger_canister: collection,
+            share: tokens.len() as u64,
+            // ICRC-37 transfers carry no fee
+            fee: Some(0),
+            state: JobState::Queued,
+            attempts: 0,
+            next_attempt_at: cur_time,
+            created_at_time: cur_time,
+            outcome_unknown: false,
+            from_subaccount: None,
+            to_account_identifier: None,
+            token_ids: Some(tokens),
+            grants_control: false,
+            calls_back: false,
+            bitcoin_address: None,
+            bitcoin_tx: None,
+            bitcoin_extra_txs: None,
+            evm_transfer: None,
+            payout_from: asset.payout_from,
+        });
+    }
+}
+
+fn enqueue_control_release(asset: &Asset, canister_id: Principal) {
+    let cur_time = now();
+
+    for heir in &asset.heir_assingment {
+        let key = ReleaseJobKey {
+            asset_id: asset.id,
+            heir: heir.heir_principal,
+        };
+        if release_job_exists(&key) {
+            continue;
+        }
+
+        insert_release_job(ReleaseJob {
+            owner: asset.owner,
+            asset_id: asset.id,
+            heir: heir.heir_principal,
+            ledger_canister: canister_id,
+            share: 1,
+            fee: Some(0),
+            state: JobState::Queued,
+            attempts: 0,
+            next_attempt_at: cur_time,
+            created_at_time: cur_time,
+            outcome_unknown: false,
+            from_subaccount: None,
+            to_account_identifier: None,
+            token_ids: None,
+            grants_control: true,
+            calls_back: false,
+            bitcoin_address: None,
+            bitcoin_tx: None,
+            bitcoin_extra_txs: None,
+            evm_transfer: None,
+            payout_from: asset.payout_from,
+        });
+    }
+}
+
+// One job per heir so each can follow their own payout, though the first one to run builds
+// the transactions for all of them. `share` holds the percentage in basis points until
+// those transactions fix it in satoshis.
+fn enqueue_bitcoin_release(asset: &Asset) {
+    let cur_time = now();
+
+    for heir in &asset.heir_assingment {
+        let key = ReleaseJobKey {
+            asset_id: asset.id,
+            heir: heir.heir_principal,
+        };
+        if release_job_exists(&key) {
+            continue;
+        }
+
+        insert_release_job(ReleaseJob {
+            owner: asset.owner,
+            asset_id: asset.id,
+            heir: heir.heir_principal,
+            ledger_canister: bitcoin::bitcoin_canister(),
+            share: heir.percentage as u64 * 100,
+            fee: Some(0),
+            state: JobState::Queued,
+            attempts: 0,
+            next_attempt_at: cur_time,
+            created_at_time: cur_time,
+            outcome_unknown: false,
+            from_subaccount: None,
+            to_account_identifier: None,
+            token_ids: None,
+            grants_control: false,
+            calls_back: false,
+            bitcoin_address: heir.account_identifier.clone(),
+            bitcoin_tx: None,
+            bitcoin_extra_txs: None,
+            evm_transfer: None,
+            payout_from: None,
+        });
+    }
+}
+
+// Nonces are handed out in heir order after the owner's withdrawals, skipping heirs with
+// nothing to receive, so the jobs come out the same however often this runs. Native ETH
+// pays the gas of each transfer out of that heir's share.
+fn enqueue_evm_release(asset: &Asset, vault: &Vault) {
+    let AssetType::EvmAddress {
+        token,
+        amount,
+        next_nonce,
+        ..
+    } = &asset.asset_type
+    else {
+        return;
+    };
+    let cur_time = now();
+    let policy = remainder_policy(vault);
+    let gas_cost = match token {
+        None => evm::max_gas_cost(&asset.asset_type),
+        Some(_) => 0,
+    };
+
+    let mut values: BTreeMap<Principal, u128> = BTreeMap::new();
+    for (heir, value) in compute_wide_heir_shares(*amount, &asset.heir_assingment, &policy) {
+        *values.entry(heir).or_default() += value;
+    }
+    let mut basis_points: BTreeMap<Principal, u64> = BTreeMap::new();
+    for (heir, share) in compute_heir_shares(CALLBACK_SHARE_SCALE, &asset.heir_assingment, &policy)
+    {
+        *basis_points.entry(heir).or_default() += share;
+    }
+
+    let mut nonce = *next_nonce;
+    for (heir, value) in values {
+        // A residual heir who isn't also assigned has given no address, their part stays
+        let Some(to) = asset
+            .heir_assingment
+            .iter()
+            .find(|h| h.heir_principal == heir)
+            .and_then(|h| h.account_identifier.clone())
+        else {
+            continue;
+        };
+        let value = value.saturating_sub(gas_cost);
+        if value == 0 {
+            continue;
+        }
+
+        let key = ReleaseJobKey {
+            asset_id: asset.id,
+            heir,
+        };
+        if !release_job_exists(&key) {
+            insert_release_job(ReleaseJob {
+                owner: asset.owner,
+                asset_id: asset.id,
+                heir,
+                ledger_canister: Principal::management_canister(),
+                share: basis_points.get(&heir).copied().unwrap_or_default(),
+                fee: Some(0),
+                state: JobState::Queued,
+                attempts: 0,
+                next_attempt_at: cur_time,
+                created_at_time: cur_time,
+                outcome_unknown: false,
+                from_subaccount: None,
+                to_account_identifier: None,
+                token_ids: None,
+                grants_control: false,
+                calls_back: false,
+                bitcoin_address: None,
+                bitcoin_tx: None,
+                bitcoin_extra_txs: None,
+                evm_transfer: Some(EvmTransfer {
+                    to,
+                    nonce,
+                    value,
+                    signed_tx: None,
+                    tx_hash: None,
+                }),
+                payout_from: None,
+            });
+        }
+        nonce += 1;
+    }
+}
+
+// Pull-style payout: the heir runs their own job right away instead of waiting for the
+// timer. A permanently failed job gets a fresh set of attempts unless an earlier attempt
+// may already have paid.
+pub async fn claim_share(heir: &Principal, asset_id: u64) -> Result<ReleaseJob, ApiError> {
+    let asset = get_asset(asset_id).ok_or(ApiError::AssetNotFound)?;
+    let vault = get_vault(&asset.owner).ok_or(ApiError::VaultNotFound)?;
+    let policy = remainder_policy(&vault);
+
+    let is_heir = asset
+        .heir_assingment
+        .iter()
+        .any(|h| h.heir_principal == *heir)
+        || policy == RemainderPolicy::ToHeir { heir: *heir };
+    if !is_heir {
+        return Err(ApiError::Unauthorized);
+    }
+
+    if vault.status != VaultStatus::Released {
+        return Err(ApiError::VaultNotReleased);
+    }
+    if matches!(asset.asset_type, AssetType::EncryptedSecret { .. }) {
+        return Err(ApiError::validation(
+            "asset_id",
+            "secrets are opened with get_secret_decryption_key",
+        ));
+    }
+
+    enqueue_asset_release(&asset, &vault);
+
+    let key = ReleaseJobKey {
+        asset_id,
+        heir: *heir,
+    };
+    // No job means the heir's share came to nothing or has nowhere to go
+    let mut job = get_release_job(&key).ok_or(ApiError::NothingToClaim)?;
+
+    match &job.state {
+        JobState::Succeeded { .. } => return Ok(job),
+        JobState::InFlight => return Err(ApiError::PayoutInProgress),
+        JobState::FailedPermanent { reason } if job.outcome_unknown => {
+            return Err(ApiError::PayoutNeedsReconciliation {
+                reason: reason.clone(),
+            });
+        }
+        _ => {}
+    }
+
+    reserve_claim(key, now())?;
+
+    if matches!(job.state, JobState::FailedPermanent { .. }) {
+        job.state = JobState::Queued;
+        job.attempts = 0;
+        insert_release_job(job);
+        log_event(
+            EventType::PayoutClaimed {
+                asset_id,
+                heir: *heir,
+            },
+            &asset.owner,
+        );
+    }
+
+    run_release_job(key).await;
+
+    get_release_job(&key).ok_or(ApiError::NothingToClaim)
+}
+
+// Each claim makes a payout attempt, so an heir gets at most one per job per cooldown
+fn reserve_claim(key: ReleaseJobKey, cur_time: u64) -> Result<(), ApiError> {
+    LAST_CLAIMED.with(|l| {
+        let mut last_claimed = l.borrow_mut();
+        if let Some(last) = last_claimed.get(&key) {
+            let ready_at = last.saturating_add(CLAIM_COOLDOWN);
+            if cur_time < ready_at {
+                return Err(ApiError::TooManyRequests {
+                    retry_after: ready_at,
+                });
+            }
+        }
+        last_claimed.insert(key, cur_time);
+        Ok(())
+    })
+}
+
+pub async fn process_release_jobs() {
+    for key in storage::list_due_release_jobs(now(), MAX_RELEASE_JOBS_PER_TICK) {
+        run_release_job(key).await;
+    }
+}
+
+async fn run_release_job(key: ReleaseJobKey) {
+    let Some(mut job) = get_release_job(&key) else {
+        return;
+    };
+
+    // Another run may have picked this job up while we were awaiting
+    if !matches!(
+        job.state,
+        JobState::Queued | JobState::FailedRetryable { .. }
+    ) {
+        return;
+    }
+
+    // Persisted before the first await so nothing else can start the same payout
+    job.state = JobState::InFlight;
+    job.attempts += 1;
+    insert_release_job(job.clone());
+
+    let fee = match job.fee {
+        Some(fee) => fee,
+        None => match job_fee(&job).await {
+            Ok(fee) => fee,
+            Err(reason) => return schedule_retry(job, reason),
+        },
+    };
+    job.fee = Some(fee);
+
+    // The ledger fee is taken out of the heir's share, so the owner is never debited more
+    // than the `amount` they approved when adding the asset. A Bitcoin share can be 0 when
+    // it was too small for an output, and an EVM share is only in basis points.
+    if job.share <= fee && job.bitcoin_tx.is_none() && job.evm_transfer.is_none() {
+        let reason = format!("Share {} does not cover ledger fee {}", job.share, fee);
+        return finish_job(job, JobState::FailedPermanent { reason });
+    }
+
+    let amount = job.share - fee;
+    match transfer_share(&mut job, amount, fee).await {
+        Ok(Ok(block_index)) => finish_job(job, JobState::Succeeded { block_index }),
+        Ok(Err(TransferFromError::Duplicate { duplicate_of })) => finish_job(
+            job,
+            JobState::Succeeded {
+                block_index: duplicate_of,
+            },
+        ),
+        Ok(Err(TransferFromError::BadFee { expected_fee })) => {
+            // A different fee makes a different transaction the ledger can't dedupe against
+            // an earlier attempt that may have landed
+            if job.outcome_unknown {
+                let reason = format!("Ledger fee changed to {} after an unconfirmed attempt, needs manual reconciliation", expected_fee);
+                return finish_job(job, JobState::FailedPermanent { reason });
+            }
+            job.fee = u64::try_from(expected_fee.0).ok();
+            schedule_retry(job, "Ledger fee changed".to_string())
+        }
+        Ok(Err(TransferFromError::TooOld)) => {
+            if job.outcome_unknown {
+                let reason = "Transaction window expired after an unconfirmed attempt, needs manual reconciliation".to_string();
+                return finish_job(job, JobState::FailedPermanent { reason });
+            }
+            // Every earlier attempt was rejected outright, so a fresh timestamp can't double pay
+            job.created_at_time = now();
+            schedule_retry(job, "Transaction too old".to_string())
+        }
+        Ok(Err(
+            e @ (TransferFromError::BadBurn { .. } | TransferFromError::GenericError { .. }),
+        )) => finish_job(
+            job,
+            JobState::FailedPermanent {
+                reason: e.to_string(),
+            },
+        ),
+        Ok(Err(e)) => schedule_retry(job, e.to_string()),
+        Err(AttemptError::Clean(reason)) => schedule_retry(job, reason),
+        Err(AttemptError::Unknown(reason)) => {
+            job.outcome_unknown = true;
+            schedule_retry(job, reason)
+        }
+    }
+}
+
+// Exponential backoff, capped
+fn retry_delay(attempts: u32) -> u64 {
+    RELEASE_RETRY_BASE_DELAY
+        .saturating_mul(1u64 << attempts.min(32))
+        .min(RELEASE_RETRY_MAX_DELAY)
+}
+
+fn schedule_retry(mut job: ReleaseJob, reason: String) {
+    if job.attempts >= MAX_RELEASE_ATTEMPTS {
+        let reason = format!("Gave up after {} attempts: {}", job.attempts, reason);
+        return finish_job(job, JobState::FailedPermanent { reason });
+    }
+
+    job.next_attempt_at = now().saturating_add(retry_delay(job.attempts));
+    job.state = JobState::FailedRetryable { reason };
+    insert_release_job(job);
+}
+
+// Records the final outcome on the job and on the asset the heir can see
+fn finish_job(mut job: ReleaseJob, state: JobState) {
+    let fee = job.fee.unwrap_or_default();
+
+    let (status, event_type) = match &state {
+        JobState::Succeeded { block_index } => (
+            TransferStatus::Completed {
+                block_index: block_index.clone(),
+            },
+            EventType::PayoutCompleted {
+                asset_id: job.asset_id,
+                heir: job.heir,
+                ledger_canister: job.ledger_canister,
+                amount: job.share.saturating_sub(fee),
+                fee,
+                block_index: block_index.clone(),
+            },
+        ),
+        JobState::FailedPermanent { reason } => (
+            TransferStatus::Failed {
+                reason: reason.clone(),
+            },
+            EventType::PayoutFailed {
+                asset_id: job.asset_id,
+                heir: job.heir,
+                ledger_canister: job.ledger_canister,
+                reason: reason.clone(),
+            },
+        ),
+        _ => return,
+    };
+
+    job.state = state;
+    insert_release_job(job.clone());
+
+    if let Some(mut asset) = get_asset(job.asset_id) {
+        asset
+            .transfers
+            .get_or_insert_with(Vec::new)
+            .push(HeirTransfer {
+                heir_principal: job.heir,
+                amount: job.share,
+                fee,
+                status,
+                timestamp: now(),
+            });
+        insert_asset(asset);
+    }
+
+    log_event(event_type, &job.owner);
+}
+
+pub fn list_release_jobs(owner: &Principal) -> Vec<ReleaseJob> {
+    storage::list_owner_release_jobs(owner)
+}
+
+async fn job_fee(job: &ReleaseJob) -> Result<u64, String> {
+    if job.to_account_identifier.is_some() {
+        vault::icp_transfer_fee(&job.ledger_canister).await
+    } else {
+        fetch_icrc1_fee(&job.ledger_canister).await
+    }
+}
+
+pub async fn fetch_icrc1_fee(ledger_canister: &Principal) -> Result<u64, String> {
+    let fee: Nat = Call::unbounded_wait(*ledger_canister, "icrc1_fee")
+        .await
+        .map_err(|e| format!("icrc1_fee call failed: {:?}", e))?
+        .candid()
+        .map_err(|e| format!("Failed to decode icrc1_fee: {:?}", e))?;
+
+    u64::try_from(fee.0).map_err(|_| "Ledger fee does not fit in u64".to_string())
+}
+
+// Escrow jobs pay from the canister's own subaccount. Their errors are folded into
+// `TransferFromError`, which has a variant for every `TransferError`, so both kinds of
+// job share the handling in `run_release_job`.
+async fn transfer_share(
+    job: &mut ReleaseJob,
+    amount: u64,
+    fee: u64,
+) -> Result<Result<Nat, TransferFromError>, AttemptError> {
+    if job.grants_control {
+        return grant_control(job).await;
+    }
+    if job.calls_back {
+        return call_back(job).await;
+    }
+    if job.bitcoin_address.is_some() {
+        return bitcoin::send_share(job).await;
+    }
+    if job.evm_transfer.is_some() {
+        return evm::sign_share(job).await;
+    }
+    if job.token_ids.is_some() {
+        return transfer_nfts(job).await;
+    }
+    let Some(from_subaccount) = job.from_subaccount else {
+        return icrc2_transfer_from(job, amount, fee).await;
+    };
+    if let Some(to) = job.to_account_identifier {
+        return legacy_icp_transfer(job, from_subaccount, to, amount, fee).await;
+    }
+
+    let args = TransferArg {
+        from_subaccount: Some(from_subaccount),
+        to: Account {
+            owner: job.heir,
+            subaccount: None,
+        },
+        fee: Some(Nat::from(fee)),
+        created_at_time: Some(job.created_at_time),
+        memo: Some(Memo::from(job.asset_id)),
+        amount: Nat::from(amount),
+    };
+
+    let result = icrc1_transfer(&job.ledger_canister, args).await?;
+    Ok(result.map_err(|e| match e {
+        TransferError::BadFee { expected_fee } => TransferFromError::BadFee { expected_fee },
+        TransferError::BadBurn { min_burn_amount } => {
+            TransferFromError::BadBurn { min_burn_amount }
+        }
+        TransferError::InsufficientFunds { balance } => {
+            TransferFromError::InsufficientFunds { balance }
+        }
+        TransferError::TooOld => TransferFromError::TooOld,
+        TransferError::CreatedInFuture { ledger_time } => {
+            TransferFromError::CreatedInFuture { ledger_time }
+        }
+        TransferError::TemporarilyUnavailable => TransferFromError::TemporarilyUnavailable,
+        TransferError::Duplicate { duplicate_of } => TransferFromError::Duplicate { duplicate_of },
+        TransferError::GenericError {
+            error_code,
+            message,
+        } => TransferFromError::GenericError {
+            error_code,
+            message,
+        },
+    }))
+}
+
+// Adds every heir of the asset in one `update_settings`, so the jobs of different heirs
+// all write the same controller set and can't undo each other. This canister stays a
+// controller so those later jobs can still read the status. Both calls are idempotent,
+// which makes any failure safe to retry.
+async fn grant_control(job: &ReleaseJob) -> Result<Result<Nat, TransferFromError>, AttemptError> {
+    let permanent = |message: String| {
+        Ok(Err(TransferFromError::GenericError {
+            error_code: Nat::from(0u64),
+            message,
+        }))
+    };
+    let retryable = |method: &str, e: ic_cdk::call::Error| {
+        AttemptError::Clean(format!("{} call failed: {:?}", method, e))
+    };
+
+    let Some(asset) = get_asset(job.asset_id) else {
+        return permanent("Asset no longer exists".to_string());
+    };
+    let AssetType::CanisterControl {
+        canister_id,
+        remove_owner,
+    } = asset.asset_type
+    else {
+        return permanent("Asset no longer controls a canister".to_string());
+    };
+
+    let status = canister_status(&CanisterStatusArgs { canister_id })
+        .await
+        .map_err(|e| retryable("canister_status", e))?;
+    let current = status.settings.controllers;
+
+    let mut controllers = current.clone();
+    for heir in &asset.heir_assingment {
+        if !controllers.contains(&heir.heir_principal) {
+            controllers.push(heir.heir_principal);
+        }
+    }
+    if remove_owner {
+        controllers.retain(|c| *c != job.payer());
+    }
+
+    let added: Vec<Principal> = controllers
+        .iter()
+        .filter(|c| !current.contains(c))
+        .copied()
+        .collect();
+    let removed: Vec<Principal> = current
+        .iter()
+        .filter(|c| !controllers.contains(c))
+        .copied()
+        .collect();
+    if added.is_empty() && removed.is_empty() {
+        return Ok(Ok(Nat::from(0u64)));
+    }
+    if controllers.len() > MAX_CANISTER_CONTROLLERS {
+        return permanent(format!(
+            "{} controllers would exceed the limit of {}",
+            controllers.len(),
+            MAX_CANISTER_CONTROLLERS
+        ));
+    }
+
+    update_settings(&UpdateSettingsArgs {
+        canister_id,
+        settings: CanisterSettings {
+            controllers: Some(controllers),
+            ..Default::default()
+        },
+    })
+    .await
+    .map_err(|e| retryable("update_settings", e))?;
+
+    log_event(
+        EventType::ControllersChanged {
+            asset_id: job.asset_id,
+            canister_id,
+            added,
+            removed,
+        },
+        &job.owner,
+    );
+    Ok(Ok(Nat::from(0u64)))
+}
+
+// Hands the heir their share of a third-party position. The call is bounded so a canister
+// that never answers can't stall the other payouts. A retry repeats the same asset_id and
+// heir, which is what callback.did asks implementers to deduplicate on.
+async fn call_back(job: &ReleaseJob) -> Result<Result<Nat, TransferFromError>, AttemptError> {
+    let Some(asset) = get_asset(job.asset_id) else {
+        return Ok(Err(TransferFromError::GenericError {
+            error_code: Nat::from(0u64),
+            message: "Asset no longer exists".to_string(),
+        }));
+    };
+    let AssetType::Callback {
+        canister,
+        method,
+        payload,
+    } = asset.asset_type
+    else {
+        return Ok(Err(TransferFromError::GenericError {
+            error_code: Nat::from(0u64),
+            message: "Asset is no longer a callback asset".to_string(),
+        }));
+    };
+
+    let args = InheritNextReleaseArgs {
+        owner: job.payer(),
+        heir: job.heir,
+        asset_id: job.asset_id,
+        share: job.share,
+        payload,
+    };
+    let response = Call::bounded_wait(canister, &method)
+        .with_arg(args)
+        .await
+        .map_err(|e| {
+            let reason = format!("{} call failed: {:?}", method, e);
+            if e.is_clean_reject() {
+                AttemptError::Clean(reason)
+            } else {
+                AttemptError::Unknown(reason)
+            }
+        })?;
+    let result: Result<Nat, InheritNextReleaseError> = response
+        .candid()
+        .map_err(|e| AttemptError::Unknown(format!("Failed to decode {}: {:?}", method, e)))?;
+
+    match result {
+        Ok(reference) => Ok(Ok(reference)),
+        // The canister declined without doing anything, so no dedup is needed to retry
+        Err(InheritNextReleaseError::Retry { reason }) => Err(AttemptError::Clean(reason)),
+        Err(InheritNextReleaseError::Rejected { reason }) => {
+            Ok(Err(TransferFromError::GenericError {
+                error_code: Nat::from(0u64),
+                message: reason,
+            }))
+        }
+    }
+}
+
+// ICRC-37 answers per token, so tokens that went through are dropped from the job and a
+// retry only resends the rest. A duplicate counts as sent. Returns the last block index.
+async fn transfer_nfts(
+    job: &mut ReleaseJob,
+) -> Result<Result<Nat, TransferFromError>, AttemptError> {
+    let remaining = job.token_ids.clone().unwrap_or_default();
+    let args: Vec<NftTransferFromArg> = remaining
+        .iter()
+        .map(|token_id| NftTransferFromArg {
+            spender_subaccount: None,
+            from: Account {
+                owner: job.payer(),
+                subaccount: None,
+            },
+            to: Account {
+                owner: job.heir,
+                subaccount: None,
+            },
+            token_id: token_id.clone(),
+            memo: Some(job.asset_id.to_be_bytes().to_vec()),
+            created_at_time: Some(job.created_at_time),
+        })
+        .collect();
+
+    let response = Call::unbounded_wait(job.ledger_canister, "icrc37_transfer_from")
+        .with_arg(args)
+        .await
+        .map_err(|e| {
+            let reason = format!("icrc37_transfer_from call failed: {:?}", e);
+            if e.is_clean_reject() {
+                AttemptError::Clean(reason)
+            } else {
+                AttemptError::Unknown(reason)
+            }
+        })?;
+    let results: Vec<Option<Result<Nat, NftTransferFromError>>> =
+        response.candid().map_err(|e| {
+            AttemptError::Unknown(format!("Failed to decode icrc37_transfer_from: {:?}", e))
+        })?;
+
+    let mut pending = Vec::new();
+    let mut last_block = Nat::from(0u64);
+    let mut first_error = None;
+    for (i, token_id) in remaining.into_iter().enumerate() {
+        match results.get(i).cloned().flatten() {
+            Some(Ok(block_index))
+            | Some(Err(NftTransferFromError::Duplicate {
+                duplicate_of: block_index,
+            })) => last_block = block_index,
+            Some(Err(e)) => {
+                first_error.get_or_insert(e);
+                pending.push(token_id);
+            }
+            // The collection didn't get to this token, it's retried as is
+            None => pending.push(token_id),
+        }
+    }
+    let all_sent = pending.is_empty();
+    job.token_ids = Some(pending);
+
+    Ok(match first_error {
+        Some(NftTransferFromError::TooOld) => Err(TransferFromError::TooOld),
+        Some(NftTransferFromError::CreatedInFuture { ledger_time }) => {
+            Err(TransferFromError::CreatedInFuture { ledger_time })
+        }
+        Some(
+            NftTransferFromError::GenericError {
+                error_code,
+                message,
+            }
+            | NftTransferFromError::GenericBatchError {
+                error_code,
+                message,
+            },
+        ) => Err(TransferFromError::GenericError {
+            error_code,
+            message,
+        }),
+        // Revoked approvals, moved or missing tokens won't fix themselves on a retry
+        Some(e) => Err(TransferFromError::GenericError {
+            error_code: Nat::from(0u64),
+            message: format!("{:?}", e),
+        }),
+        None if !all_sent => Err(TransferFromError::TemporarilyUnavailable),
+        None => Ok(last_block),
+    })
+}
+
+// Same folding for the legacy ICP ledger, whose errors map onto their ICRC counterparts
+async fn legacy_icp_transfer(
+    job: &ReleaseJob,
+    from_subaccount: Subaccount,
+    to: AccountIdentifier,
+    amount: u64,
+    fee: u64,
+) -> Result<Result<Nat, TransferFromError>, AttemptError> {
+    let args = IcpTransferArgs {
+        memo: job.asset_id,
+        amount: Tokens { e8s: amount },
+        fee: Tokens { e8s: fee },
+        from_subaccount: Some(from_subaccount),
+        to,
+        created_at_time: Some(IcpTimeStamp {
+            timestamp_nanos: job.created_at_time,
+        }),
+    };
+
+    let result = icp_transfer(&job.ledger_canister, args).await?;
+    Ok(result.map(Nat::from).map_err(|e| match e {
+        IcpTransferError::BadFee { expected_fee } => TransferFromError::BadFee {
+            expected_fee: Nat::from(expected_fee.e8s),
+        },
+        IcpTransferError::InsufficientFunds { balance } => TransferFromError::InsufficientFunds {
+            balance: Nat::from(balance.e8s),
+        },
+        IcpTransferError::TxTooOld { .. } => TransferFromError::TooOld,
+        // The legacy error doesn't say what time the ledger has, only that we're ahead of it
+        IcpTransferError::TxCreatedInFuture => {
+            TransferFromError::CreatedInFuture { ledger_time: now() }
+        }
+        IcpTransferError::TxDuplicate { duplicate_of } => TransferFromError::Duplicate {
+            duplicate_of: Nat::from(duplicate_of),
+        },
+    }))
+}
+
+pub async fn icp_transfer(
+    ledger_canister: &Principal,
+    args: IcpTransferArgs,
+) -> Result<Result<u64, IcpTransferError>, AttemptError> {
+    let response = Call::unbounded_wait(*ledger_canister, "transfer")
+        .with_arg(args)
+        .await
+        .map_err(|e| {
+            let reason = format!("transfer call failed: {:?}", e);
+            if e.is_clean_reject() {
+                AttemptError::Clean(reason)
+            } else {
+                AttemptError::Unknown(reason)
+            }
+        })?;
+
+    response
+        .candid()
+        .map_err(|e| AttemptError::Unknown(format!("Failed to decode transfer: {:?}", e)))
+}
+
+pub async fn icrc1_transfer(
+    ledger_canister: &Principal,
+    args: TransferArg,
+) -> Result<Result<Nat, TransferError>, AttemptError> {
+    let response = Call::unbounded_wait(*ledger_canister, "icrc1_transfer")
+        .with_arg(args)
+        .await
+        .map_err(|e| {
+            let reason = format!("icrc1_transfer call failed: {:?}", e);
+            if e.is_clean_reject() {
+                AttemptError::Clean(reason)
+            } else {
+                AttemptError::Unknown(reason)
+            }
+        })?;
+
+    response
+        .candid()
+        .map_err(|e| AttemptError::Unknown(format!("Failed to decode icrc1_transfer: {:?}", e)))
+}
+
+async fn icrc2_transfer_from(
+    job: &ReleaseJob,
+    amount: u64,
+    fee: u64,
+) -> Result<Result<Nat, TransferFromError>, AttemptError> {
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account {
+            owner: job.payer(),
+            subaccount: None,
+        },
+        to: Account {
+            owner: job.heir,
+            subaccount: None,
+        },
+        amount: Nat::from(amount),
+        fee: Some(Nat::from(fee)),
+        memo: Some(Memo::from(job.asset_id)),
+        created_at_time: Some(job.created_at_time),
+    };
+
+    let response = Call::unbounded_wait(job.ledger_canister, "icrc2_transfer_from")
+        .with_arg(args)
+        .await
+        .map_err(|e| {
+            let reason = format!("icrc2_transfer_from call failed: {:?}", e);
+            if e.is_clean_reject() {
+                AttemptError::Clean(reason)
+            } else {
+                AttemptError::Unknown(reason)
+            }
+        })?;
+
+    response.candid().map_err(|e| {
+        AttemptError::Unknown(format!("Failed to decode icrc2_transfer_from: {:?}", e))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delay_doubles_per_attempt() {
+        assert_eq!(retry_delay(0), RELEASE_RETRY_BASE_DELAY);
+        assert_eq!(retry_delay(1), RELEASE_RETRY_BASE_DELAY * 2);
+        assert_eq!(retry_delay(2), RELEASE_RETRY_BASE_DELAY * 4);
+    }
+
+    #[test]
+    fn retry_delay_is_capped() {
+        // One minute doubled eight times is still under six hours, nine times is over
+        assert_eq!(retry_delay(8), RELEASE_RETRY_BASE_DELAY * 256);
+        assert_eq!(retry_delay(9), RELEASE_RETRY_MAX_DELAY);
+        assert_eq!(retry_delay(40), RELEASE_RETRY_MAX_DELAY);
+        assert_eq!(retry_delay(u32::MAX), RELEASE_RETRY_MAX_DELAY);
+    }
+
+    #[test]
+    fn retry_delay_never_decreases() {
+        for attempts in 0..64 {
+            assert!(retry_delay(attempts + 1) >= retry_delay(attempts));
+        }
+    }
+
+    #[test]
+    fn claims_are_limited_per_job() {
+        let heir = Principal::from_slice(&[1]);
+        let key = ReleaseJobKey { asset_id: 7, heir };
+        let other = ReleaseJobKey { asset_id: 8, heir };
+
+        assert!(reserve_claim(key, 1_000).is_ok());
+        assert!(matches!(
+            reserve_claim(key, 1_000 + CLAIM_COOLDOWN - 1),
+            Err(ApiError::TooManyRequests { retry_after }) if retry_after == 1_000 + CLAIM_COOLDOWN
+        ));
+        // Another asset of the same heir has its own cooldown
+        assert!(reserve_claim(other, 1_000).is_ok());
+        assert!(reserve_claim(key, 1_000 + CLAIM_COOLDOWN).is_ok());
+    }
+}
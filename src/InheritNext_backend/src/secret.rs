@@ -0,0 +1,173 @@
+use std::{cell::RefCell, collections::BTreeMap};
+
+use candid::Principal;
+use ic_cdk::management_canister::{
+    vetkd_derive_key, vetkd_public_key, VetKDCurve, VetKDDeriveKeyArgs, VetKDKeyId,
+    VetKDPublicKeyArgs,
+};
+
+use crate::{
+    escrow,
+    helpers::{check_is_anonymous, log_event, now, SECRET_KEY_COOLDOWN, VETKD_KEY_NAME},
+    storage::{get_asset, get_vault},
+    types::{
+        ApiError, AssetType, EventType, SecretDecryptionKey, SecretEncryptionKey, Vault,
+        VaultStatus,
+    },
+};
+
+const SECRET_CONTEXT: &[u8] = b"inheritnext-secret";
+// Compressed BLS12-381 G1 point
+const TRANSPORT_KEY_LENGTH: usize = 48;
+
+thread_local! {
+    // Derivations cost cycles, so each heir gets at most one per asset per cooldown
+    static LAST_DERIVED: RefCell<BTreeMap<(u64, Principal), u64>> =
+        const { RefCell::new(BTreeMap::new()) };
+}
+
+fn key_id() -> VetKDKeyId {
+    VetKDKeyId {
+        curve: VetKDCurve::Bls12_381_G2,
+        name: VETKD_KEY_NAME.to_string(),
+    }
+}
+
+// The vault's escrow subaccount doubles as its stable id, it survives recovery
+fn derivation_id(vault: &Vault, heir: &Principal) -> Vec<u8> {
+    let mut id = escrow::vault_subaccount(vault).to_vec();
+    id.extend_from_slice(heir.as_slice());
+    id
+}
+
+async fn public_key() -> Result<Vec<u8>, ApiError> {
+    let args = VetKDPublicKeyArgs {
+        canister_id: None,
+        context: SECRET_CONTEXT.to_vec(),
+        key_id: key_id(),
+    };
+    vetkd_public_key(&args)
+        .await
+        .map(|r| r.public_key)
+        .map_err(|e| ApiError::KeyDerivationFailed {
+            reason: format!("vetkd_public_key failed: {:?}", e),
+        })
+}
+
+// The owner seals a secret to `heir` with IBE against these, without any key leaving
+// the subnet
+pub async fn get_secret_encryption_key(
+    owner: &Principal,
+    heir: &Principal,
+) -> Result<SecretEncryptionKey, ApiError> {
+    if check_is_anonymous(heir) || heir == owner {
+        return Err(ApiError::validation(
+            "heir",
+            "must be someone other than the owner",
+        ));
+    }
+
+    let vault = get_vault(owner).ok_or(ApiError::VaultNotFound)?;
+    if vault.status == VaultStatus::Released {
+        return Err(ApiError::VaultReleased);
+    }
+    escrow::pin_vault_subaccount(owner)?;
+    let vault = get_vault(owner).ok_or(ApiError::VaultNotFound)?;
+
+    Ok(SecretEncryptionKey {
+        derivation_id: derivation_id(&vault, heir),
+        public_key: public_key().await?,
+    })
+}
+
+// Only once the vault is released, and only for an heir the secret was sealed to
+pub async fn get_secret_decryption_key(
+    heir: &Principal,
+    asset_id: u64,
+    transport_public_key: Vec<u8>,
+) -> Result<SecretDecryptionKey, ApiError> {
+    if transport_public_key.len() != TRANSPORT_KEY_LENGTH {
+        return Err(ApiError::validation(
+            "transport_public_key",
+            format!("must be {} bytes", TRANSPORT_KEY_LENGTH),
+        ));
+    }
+
+    let asset = get_asset(asset_id).ok_or(ApiError::AssetNotFound)?;
+    let AssetType::EncryptedSecret { ciphertexts } = &asset.asset_type else {
+        return Err(ApiError::validation("asset_id", "not an encrypted secret"));
+    };
+    let is_heir = asset
+        .heir_assingment
+        .iter()
+        .any(|h| h.heir_principal == *heir)
+        && ciphertexts.iter().any(|c| c.heir == *heir);
+    if !is_heir {
+        return Err(ApiError::Unauthorized);
+    }
+
+    let vault = get_vault(&asset.owner).ok_or(ApiError::VaultNotFound)?;
+    if vault.status != VaultStatus::Released {
+        return Err(ApiError::VaultNotReleased);
+    }
+
+    let cur_time = now();
+    let key = (asset_id, *heir);
+    let last = LAST_DERIVED.with(|l| l.borrow().get(&key).copied());
+    if let Some(last) = last {
+        let ready_at = last.saturating_add(SECRET_KEY_COOLDOWN);
+        if cur_time < ready_at {
+            return Err(ApiError::TooManyRequests {
+                retry_after: ready_at,
+            });
+        }
+    }
+    // Taken before the call so concurrent requests can't derive twice, and handed back if
+    // the heir ends up without a key
+    LAST_DERIVED.with(|l| l.borrow_mut().insert(key, cur_time));
+
+    let derived = derive_key(&vault, heir, transport_public_key).await;
+    let Ok(derived) = derived else {
+        LAST_DERIVED.with(|l| match last {
+            Some(last) => l.borrow_mut().insert(key, last),
+            None => l.borrow_mut().remove(&key),
+        });
+        return derived;
+    };
+
+    log_event(
+        EventType::SecretKeyDerived {
+            asset_id,
+            heir: *heir,
+        },
+        &asset.owner,
+    );
+
+    Ok(derived)
+}
+
+async fn derive_key(
+    vault: &Vault,
+    heir: &Principal,
+    transport_public_key: Vec<u8>,
+) -> Result<SecretDecryptionKey, ApiError> {
+    let derivation_id = derivation_id(vault, heir);
+    let args = VetKDDeriveKeyArgs {
+        input: derivation_id.clone(),
+        context: SECRET_CONTEXT.to_vec(),
+        transport_public_key,
+        key_id: key_id(),
+    };
+    let encrypted_key = vetkd_derive_key(&args)
+        .await
+        .map_err(|e| ApiError::KeyDerivationFailed {
+            reason: format!("vetkd_derive_key failed: {:?}", e),
+        })?
+        .encrypted_key;
+
+    Ok(SecretDecryptionKey {
+        encrypted_key,
+        public_key: public_key().await?,
+        derivation_id,
+    })
+}
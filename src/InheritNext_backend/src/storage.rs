@@ -4,11 +4,20 @@ use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
     DefaultMemoryImpl, StableBTreeMap, StableCell,
 };
-use std::cell::RefCell;
+use std::{cell::RefCell, collections::BTreeSet, ops::Bound};
 
 use crate::{
-    helpers::MAX_AUDIT_EVENT,
-    types::{Asset, AssetId, AuditEvent, EventId, StablePrincipal, UserProfile, Vault},
+    audit::{chain_hash, GENESIS_HASH},
+    helpers::{
+        is_compactable_event, MAX_ARCHIVE_QUEUE_LEN, MAX_CRITICAL_EVENTS_PER_VAULT,
+        MAX_HEARTBEATS_PER_VAULT,
+    },
+    types::{
+        ApiError, ArchiveConfig, ArchiveInfo, Asset, AssetId, AssetVersion, AssetVersionKey,
+        AuditEvent, AuditStreamStats, Document, DocumentChunkKey, EventId, JobState, Letter,
+        LetterKey, OwnerAlias, OwnerAssetKey, OwnerDocumentKey, ReleaseJob, ReleaseJobKey,
+        StablePrincipal, StreamEventKey, UserProfile, Vault,
+    },
 };
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
@@ -42,7 +51,10 @@ thread_local! {
         )
     );
 
-
+    static RELEASE_JOBS: RefCell<StableBTreeMap<ReleaseJobKey, ReleaseJob, Memory>> =
+    RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))))
+    );
 
     static NEXT_EVENT_ID: RefCell<StableCell<u64, Memory>> =
     RefCell::new(
@@ -54,6 +66,128 @@ thread_local! {
         StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))), 0)
     );
 
+    static ASSET_HISTORY: RefCell<StableBTreeMap<AssetVersionKey, AssetVersion, Memory>> =
+    RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))))
+    );
+
+    static ASSETS_BY_OWNER: RefCell<StableBTreeMap<OwnerAssetKey, (), Memory>> =
+    RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))))
+    );
+
+    // Every event still held in AUDIT_LOG, per stream
+    static AUDIT_STREAMS: RefCell<StableBTreeMap<StreamEventKey, (), Memory>> =
+    RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))))
+    );
+
+    // Retained compactable events, so the oldest one is found without a scan
+    static AUDIT_COMPACTABLE: RefCell<StableBTreeMap<StreamEventKey, (), Memory>> =
+    RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))))
+    );
+
+    static AUDIT_STREAM_STATS: RefCell<StableBTreeMap<StablePrincipal, AuditStreamStats, Memory>> =
+    RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))))
+    );
+
+    // Chain hash of every event still held locally, pruned along with the event once it
+    // has been archived or dropped
+    static AUDIT_HASHES: RefCell<StableBTreeMap<EventId, [u8; 32], Memory>> =
+    RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))))
+    );
+
+    // Retained critical events, the counterpart of AUDIT_COMPACTABLE
+    static AUDIT_CRITICAL: RefCell<StableBTreeMap<StreamEventKey, (), Memory>> =
+    RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))))
+    );
+
+    // Evicted events still in AUDIT_LOG, waiting to be shipped to an archive
+    static ARCHIVE_QUEUE: RefCell<StableBTreeMap<EventId, (), Memory>> =
+    RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))))
+    );
+
+    static ARCHIVES: RefCell<StableBTreeMap<u64, ArchiveInfo, Memory>> =
+    RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))))
+    );
+
+    static ARCHIVE_CONFIG: RefCell<StableCell<ArchiveConfig, Memory>> =
+    RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))),
+            ArchiveConfig::default(),
+        )
+    );
+
+    static DOCUMENTS: RefCell<StableBTreeMap<u64, Document, Memory>> =
+    RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))))
+    );
+
+    // Raw document bytes, kept in their own memory apart from the metadata
+    static DOCUMENT_CHUNKS: RefCell<StableBTreeMap<DocumentChunkKey, Vec<u8>, Memory>> =
+    RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))))
+    );
+
+    static DOCUMENTS_BY_OWNER: RefCell<StableBTreeMap<OwnerDocumentKey, (), Memory>> =
+    RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))))
+    );
+
+    static NEXT_DOCUMENT_ID: RefCell<StableCell<u64, Memory>> =
+    RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))), 0)
+    );
+
+    static LETTERS: RefCell<StableBTreeMap<LetterKey, Letter, Memory>> =
+    RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))))
+    );
+
+    // Same key layout as ASSETS_BY_OWNER, but keyed by each heir named on the asset
+    static ASSETS_BY_HEIR: RefCell<StableBTreeMap<OwnerAssetKey, (), Memory>> =
+    RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))))
+    );
+
+    // Where a previous owner's audit stream went when their vault was recovered
+    static AUDIT_STREAM_MOVES: RefCell<StableBTreeMap<StablePrincipal, StablePrincipal, Memory>> =
+    RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))))
+    );
+
+    // Every change to AUDIT_STREAM_MOVES in order, replayed to each archive so owners can
+    // read what was archived under a previous owner's principal
+    static OWNER_ALIASES: RefCell<StableBTreeMap<u64, OwnerAlias, Memory>> =
+    RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))))
+    );
+
+    // Same key layout as DOCUMENTS_BY_OWNER, but keyed by each heir with access
+    static DOCUMENTS_BY_HEIR: RefCell<StableBTreeMap<OwnerDocumentKey, (), Memory>> =
+    RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25))))
+    );
+
+    // LETTERS keys with owner and heir swapped, so an heir's letters are one range
+    static LETTERS_BY_HEIR: RefCell<StableBTreeMap<LetterKey, (), Memory>> =
+    RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))))
+    );
+
+    // Queued compactable events, the only ones dropped when the queue is full
+    static ARCHIVE_QUEUE_COMPACTABLE: RefCell<StableBTreeMap<EventId, (), Memory>> =
+    RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27))))
+    );
+
 }
 
 fn return_stable_prin(p: &Principal) -> StablePrincipal {
@@ -64,12 +198,12 @@ pub fn is_user_registered(visitor: &Principal) -> bool {
     USERS.with(|users| users.borrow().contains_key(&return_stable_prin(visitor)))
 }
 
-pub fn create_user(owner: &Principal, profile: UserProfile) -> Result<(), String> {
+pub fn create_user(owner: &Principal, profile: UserProfile) -> Result<(), ApiError> {
     USERS.with(|users| {
         let mut users = users.borrow_mut();
 
         if users.contains_key(&return_stable_prin(owner)) {
-            return Err("User Already registered".to_string());
+            return Err(ApiError::AlreadyRegistered);
         }
 
         users.insert(return_stable_prin(owner), profile);
@@ -96,7 +230,236 @@ pub fn insert_vault(owner: &Principal, vault: Vault) {
     });
 }
 
-pub fn log_event(event: AuditEvent) -> EventId {
+pub fn list_vault_owners() -> Vec<Principal> {
+    VAULTS.with(|vaults| vaults.borrow().keys().map(|key| key.0).collect())
+}
+
+// Moves the profile, the vault, every asset, document and letter from `old_owner` to
+// `new_owner`
+pub fn transfer_vault_ownership(
+    old_owner: &Principal,
+    new_owner: &Principal,
+) -> Result<(), ApiError> {
+    if vault_exists(new_owner) {
+        return Err(ApiError::VaultAlreadyExists);
+    }
+
+    let mut vault = VAULTS
+        .with(|vaults| vaults.borrow_mut().remove(&return_stable_prin(old_owner)))
+        .ok_or(ApiError::VaultNotFound)?;
+    vault.owner = *new_owner;
+    insert_vault(new_owner, vault);
+
+    // A new owner who registered already keeps their own names, but the registration date
+    // stays the earlier one
+    USERS.with(|users| {
+        let mut users = users.borrow_mut();
+        if let Some(old) = users.remove(&return_stable_prin(old_owner)) {
+            let merged = match users.get(&return_stable_prin(new_owner)) {
+                Some(new) => UserProfile {
+                    first_name: new.first_name,
+                    last_name: new.last_name,
+                    created_at: new.created_at.min(old.created_at),
+                },
+                None => old,
+            };
+            users.insert(return_stable_prin(new_owner), merged);
+        }
+    });
+
+    // Allowances, approvals and controller entries were all given by the old owner's
+    // principal, so payouts keep coming from there
+    for mut asset in list_user_assets(old_owner) {
+        asset.payout_from.get_or_insert(*old_owner);
+        asset.owner = *new_owner;
+        insert_asset(asset);
+    }
+
+    for mut document in list_user_documents(old_owner) {
+        document.owner = *new_owner;
+        insert_document(document);
+    }
+
+    for mut letter in list_user_letters(old_owner) {
+        remove_letter(old_owner, &letter.heir);
+        letter.owner = *new_owner;
+        insert_letter(letter);
+    }
+
+    move_audit_stream(old_owner, new_owner);
+
+    Ok(())
+}
+
+fn stream_key(owner: &Principal, event_id: EventId) -> StreamEventKey {
+    StreamEventKey {
+        owner: *owner,
+        event_id: event_id.0,
+    }
+}
+
+fn stream_range(owner: &Principal) -> std::ops::RangeInclusive<StreamEventKey> {
+    stream_key(owner, EventId(0))..=stream_key(owner, EventId(u64::MAX))
+}
+
+pub fn get_audit_stream_stats(owner: &Principal) -> AuditStreamStats {
+    AUDIT_STREAM_STATS
+        .with(|stats| stats.borrow().get(&return_stable_prin(owner)))
+        .unwrap_or_default()
+}
+
+fn insert_audit_stream_stats(owner: &Principal, stats: AuditStreamStats) {
+    AUDIT_STREAM_STATS.with(|s| s.borrow_mut().insert(return_stable_prin(owner), stats));
+}
+
+// The stream an event blamed on `principal` belongs to. Recovering a vault hands the old
+// owner's stream to the new one, so this follows every recovery since and a vault keeps
+// its whole history, including anything the old principal does afterwards.
+pub fn audit_stream(principal: &Principal) -> Principal {
+    let mut stream = *principal;
+    while let Some(next) =
+        AUDIT_STREAM_MOVES.with(|moves| moves.borrow().get(&return_stable_prin(&stream)))
+    {
+        stream = next.0;
+    }
+    stream
+}
+
+// Previous owners whose events were archived under their own principal before their stream
+// was handed to `stream`
+pub fn previous_stream_owners(stream: &Principal) -> Vec<Principal> {
+    let moved: Vec<Principal> =
+        AUDIT_STREAM_MOVES.with(|moves| moves.borrow().keys().map(|previous| previous.0).collect());
+    moved
+        .into_iter()
+        .filter(|previous| audit_stream(previous) == *stream)
+        .collect()
+}
+
+fn move_audit_stream(old_owner: &Principal, new_owner: &Principal) {
+    let withdrawn = AUDIT_STREAM_MOVES.with(|moves| {
+        let mut moves = moves.borrow_mut();
+        // The new owner has a vault again, so whatever they log is theirs
+        let withdrawn = moves.remove(&return_stable_prin(new_owner)).is_some();
+        moves.insert(return_stable_prin(old_owner), return_stable_prin(new_owner));
+        withdrawn
+    });
+    if withdrawn {
+        push_owner_alias(OwnerAlias {
+            previous: *new_owner,
+            owner: None,
+        });
+    }
+    push_owner_alias(OwnerAlias {
+        previous: *old_owner,
+        owner: Some(*new_owner),
+    });
+
+    for index in [&AUDIT_STREAMS, &AUDIT_COMPACTABLE, &AUDIT_CRITICAL] {
+        index.with(|index| {
+            let mut index = index.borrow_mut();
+            let keys: Vec<StreamEventKey> = index.keys_range(stream_range(old_owner)).collect();
+            for key in keys {
+                index.remove(&key);
+                index.insert(stream_key(new_owner, EventId(key.event_id)), ());
+            }
+        });
+    }
+
+    let Some(moved) =
+        AUDIT_STREAM_STATS.with(|stats| stats.borrow_mut().remove(&return_stable_prin(old_owner)))
+    else {
+        return;
+    };
+    let mut stats = get_audit_stream_stats(new_owner);
+    stats.retained_events += moved.retained_events;
+    stats.retained_heartbeats += moved.retained_heartbeats;
+    stats.evicted_events += moved.evicted_events;
+    stats.compacted_heartbeats += moved.compacted_heartbeats;
+    stats.first_compacted_heartbeat = [
+        stats.first_compacted_heartbeat,
+        moved.first_compacted_heartbeat,
+    ]
+    .into_iter()
+    .flatten()
+    .min();
+    stats.last_compacted_heartbeat = stats
+        .last_compacted_heartbeat
+        .max(moved.last_compacted_heartbeat);
+    stats.archived_events += moved.archived_events;
+    stats.dropped_events += moved.dropped_events;
+    stats.queued_events += moved.queued_events;
+    enforce_stream_retention(new_owner, &mut stats);
+    insert_audit_stream_stats(new_owner, stats);
+    enforce_archive_queue_cap();
+}
+
+fn index_audit_event(
+    id: EventId,
+    stream: &Principal,
+    event: &AuditEvent,
+    stats: &mut AuditStreamStats,
+) {
+    let key = stream_key(stream, id);
+    AUDIT_STREAMS.with(|index| index.borrow_mut().insert(key, ()));
+    if is_compactable_event(&event.event_type) {
+        AUDIT_COMPACTABLE.with(|index| index.borrow_mut().insert(key, ()));
+        stats.retained_heartbeats += 1;
+    } else {
+        AUDIT_CRITICAL.with(|index| index.borrow_mut().insert(key, ()));
+        stats.retained_events += 1;
+    }
+}
+
+// The event stays readable locally until an archive has acknowledged it
+fn queue_for_archive(key: StreamEventKey, stats: &mut AuditStreamStats) {
+    let id = EventId(key.event_id);
+    if AUDIT_COMPACTABLE.with(|index| index.borrow_mut().remove(&key).is_some()) {
+        ARCHIVE_QUEUE_COMPACTABLE.with(|queue| queue.borrow_mut().insert(id, ()));
+    }
+    AUDIT_CRITICAL.with(|index| index.borrow_mut().remove(&key));
+    ARCHIVE_QUEUE.with(|queue| queue.borrow_mut().insert(id, ()));
+    stats.queued_events += 1;
+}
+
+fn oldest_in_stream(
+    index: &'static std::thread::LocalKey<RefCell<StableBTreeMap<StreamEventKey, (), Memory>>>,
+    owner: &Principal,
+) -> Option<StreamEventKey> {
+    index.with(|index| index.borrow().keys_range(stream_range(owner)).next())
+}
+
+// Retention is decided per stream, so one busy vault can only ever push out its own
+// history. Compactable events beyond their window are also folded into counters; critical
+// events are only evicted once the vault itself exceeds its own cap. Either way they go to
+// the archive queue rather than being deleted.
+fn enforce_stream_retention(owner: &Principal, stats: &mut AuditStreamStats) {
+    while stats.retained_heartbeats > MAX_HEARTBEATS_PER_VAULT {
+        let Some(key) = oldest_in_stream(&AUDIT_COMPACTABLE, owner) else {
+            break;
+        };
+        stats.retained_heartbeats -= 1;
+        queue_for_archive(key, stats);
+        if let Some(event) = get_audit_event(EventId(key.event_id)) {
+            stats.compacted_heartbeats += 1;
+            stats
+                .first_compacted_heartbeat
+                .get_or_insert(event.timestamp);
+            stats.last_compacted_heartbeat = Some(event.timestamp);
+        }
+    }
+
+    while stats.retained_events > MAX_CRITICAL_EVENTS_PER_VAULT {
+        let Some(key) = oldest_in_stream(&AUDIT_CRITICAL, owner) else {
+            break;
+        };
+        stats.retained_events -= 1;
+        stats.evicted_events += 1;
+        queue_for_archive(key, stats);
+    }
+}
+
+pub fn log_event(mut event: AuditEvent) -> EventId {
     let event_id = NEXT_EVENT_ID.with(|id| {
         let mut cell = id.borrow_mut();
         let current = *cell.get();
@@ -104,31 +467,332 @@ pub fn log_event(event: AuditEvent) -> EventId {
         EventId(current)
     });
 
-    AUDIT_LOG.with(|log| {
+    let prev = audit_chain_head().map_or(GENESIS_HASH, |(_, hash)| hash);
+    event.prev_hash = Some(prev.to_vec());
+    let hash = chain_hash(&prev, event_id, &event);
+    AUDIT_HASHES.with(|hashes| hashes.borrow_mut().insert(event_id, hash));
+
+    let stream = audit_stream(&event.blame);
+    let mut stats = get_audit_stream_stats(&stream);
+    index_audit_event(event_id, &stream, &event, &mut stats);
+    AUDIT_LOG.with(|log| log.borrow_mut().insert(event_id, event));
+
+    enforce_stream_retention(&stream, &mut stats);
+    insert_audit_stream_stats(&stream, stats);
+    enforce_archive_queue_cap();
+    event_id
+}
+
+// Removes an archived or dropped event with everything kept for it. The head's hash stays,
+// as the next event is chained to it.
+fn prune_audit_event(id: EventId) -> Option<AuditEvent> {
+    ARCHIVE_QUEUE.with(|queue| queue.borrow_mut().remove(&id));
+    ARCHIVE_QUEUE_COMPACTABLE.with(|queue| queue.borrow_mut().remove(&id));
+    let event = AUDIT_LOG.with(|log| log.borrow_mut().remove(&id))?;
+    let stream = audit_stream(&event.blame);
+    AUDIT_STREAMS.with(|index| index.borrow_mut().remove(&stream_key(&stream, id)));
+    if audit_chain_head().is_some_and(|(head, _)| head != id) {
+        AUDIT_HASHES.with(|hashes| hashes.borrow_mut().remove(&id));
+    }
+    Some(event)
+}
+
+fn enforce_archive_queue_cap() {
+    trim_archive_queue(MAX_ARCHIVE_QUEUE_LEN);
+}
+
+// Without an archive to ship to the queue would grow forever, so past `max_len` the oldest
+// queued heartbeats are dropped and counted on their stream. Critical events are never
+// dropped; they wait for an archive however long the queue gets.
+pub fn trim_archive_queue(max_len: u64) {
+    while archive_queue_len() > max_len {
+        let Some(id) = ARCHIVE_QUEUE_COMPACTABLE
+            .with(|queue| queue.borrow().first_key_value().map(|(k, _)| k))
+        else {
+            break;
+        };
+        let Some(event) = prune_audit_event(id) else {
+            continue;
+        };
+        let stream = audit_stream(&event.blame);
+        let mut stats = get_audit_stream_stats(&stream);
+        stats.queued_events = stats.queued_events.saturating_sub(1);
+        stats.dropped_events += 1;
+        insert_audit_stream_stats(&stream, stats);
+    }
+}
+
+pub fn archive_queue_len() -> u64 {
+    ARCHIVE_QUEUE.with(|queue| queue.borrow().len())
+}
+
+pub fn get_audit_event(id: EventId) -> Option<AuditEvent> {
+    AUDIT_LOG.with(|log| log.borrow().get(&id))
+}
+
+// Lets tests tamper with a logged event behind the chain's back
+#[cfg(test)]
+pub fn overwrite_audit_event(id: EventId, event: AuditEvent) {
+    AUDIT_LOG.with(|log| log.borrow_mut().insert(id, event));
+}
+
+pub fn get_audit_hash(id: EventId) -> Option<[u8; 32]> {
+    AUDIT_HASHES.with(|hashes| hashes.borrow().get(&id))
+}
+
+// Hash of the closest chained event before `id`
+pub fn audit_hash_before(id: EventId) -> Option<[u8; 32]> {
+    AUDIT_HASHES.with(|hashes| {
+        hashes
+            .borrow()
+            .range(..id)
+            .next_back()
+            .map(|entry| entry.value())
+    })
+}
+
+pub fn audit_chain_head() -> Option<(EventId, [u8; 32])> {
+    AUDIT_HASHES.with(|hashes| hashes.borrow().last_key_value())
+}
+
+// Chains the events logged before hashing existed, oldest first. Events already pruned
+// back then are simply not part of the chain.
+pub fn migrate_audit_chain() {
+    if AUDIT_HASHES.with(|hashes| !hashes.borrow().is_empty()) {
+        return;
+    }
+
+    let events: Vec<(EventId, AuditEvent)> = AUDIT_LOG.with(|log| {
+        log.borrow()
+            .iter()
+            .map(|entry| (*entry.key(), entry.value()))
+            .collect()
+    });
+
+    let mut prev = GENESIS_HASH;
+    for (id, mut event) in events {
+        event.prev_hash = Some(prev.to_vec());
+        let hash = chain_hash(&prev, id, &event);
+        AUDIT_HASHES.with(|hashes| hashes.borrow_mut().insert(id, hash));
+        AUDIT_LOG.with(|log| log.borrow_mut().insert(id, event));
+        prev = hash;
+    }
+}
+
+// Builds the stream indexes for events logged before they existed. Every event in the log
+// is either retained or queued for the archive, so any mismatch means they are incomplete
+// and they are rebuilt from the log.
+pub fn migrate_audit_streams() {
+    let log_len = AUDIT_LOG.with(|log| log.borrow().len());
+    let streams_len = AUDIT_STREAMS.with(|index| index.borrow().len());
+    let classified_len = AUDIT_CRITICAL.with(|index| index.borrow().len())
+        + AUDIT_COMPACTABLE.with(|index| index.borrow().len())
+        + ARCHIVE_QUEUE.with(|queue| queue.borrow().len());
+    if log_len == streams_len && log_len == classified_len {
+        return;
+    }
+
+    AUDIT_STREAMS.with(|index| index.borrow_mut().clear_new());
+    AUDIT_COMPACTABLE.with(|index| index.borrow_mut().clear_new());
+    AUDIT_CRITICAL.with(|index| index.borrow_mut().clear_new());
+
+    let mut streams: std::collections::BTreeMap<Principal, AuditStreamStats> =
+        std::collections::BTreeMap::new();
+    let events: Vec<(EventId, AuditEvent)> = AUDIT_LOG.with(|log| {
+        log.borrow()
+            .iter()
+            .map(|entry| (*entry.key(), entry.value()))
+            .collect()
+    });
+    for (id, event) in events {
+        let stream = audit_stream(&event.blame);
+        if ARCHIVE_QUEUE.with(|queue| queue.borrow().contains_key(&id)) {
+            AUDIT_STREAMS.with(|index| index.borrow_mut().insert(stream_key(&stream, id), ()));
+            continue;
+        }
+        let stats = streams.entry(stream).or_insert_with(|| {
+            // Keep compaction history already recorded, recount what is retained
+            let mut stats = get_audit_stream_stats(&stream);
+            stats.retained_events = 0;
+            stats.retained_heartbeats = 0;
+            stats
+        });
+        index_audit_event(id, &stream, &event, stats);
+    }
+
+    for (owner, mut stats) in streams {
+        enforce_stream_retention(&owner, &mut stats);
+        insert_audit_stream_stats(&owner, stats);
+    }
+    enforce_archive_queue_cap();
+}
+
+pub fn list_archive_queue(limit: usize) -> Vec<(EventId, AuditEvent)> {
+    let ids: Vec<EventId> = ARCHIVE_QUEUE.with(|queue| queue.borrow().keys().take(limit).collect());
+    ids.into_iter()
+        .filter_map(|id| get_audit_event(id).map(|event| (id, event)))
+        .collect()
+}
+
+// Called once `archive_index` has acknowledged `ids`, which are then dropped locally
+pub fn complete_archive_batch(archive_index: u64, ids: &[EventId]) {
+    let mut archived: std::collections::BTreeMap<Principal, u64> =
+        std::collections::BTreeMap::new();
+
+    for id in ids {
+        let Some(event) = prune_audit_event(*id) else {
+            continue;
+        };
+        *archived.entry(audit_stream(&event.blame)).or_default() += 1;
+    }
+
+    for (owner, count) in archived {
+        let mut stats = get_audit_stream_stats(&owner);
+        stats.queued_events = stats.queued_events.saturating_sub(count);
+        stats.archived_events += count;
+        insert_audit_stream_stats(&owner, stats);
+    }
+
+    if let Some(mut info) = get_archive(archive_index) {
+        for id in ids {
+            info.first_event_id = Some(info.first_event_id.map_or(id.0, |first| first.min(id.0)));
+            info.last_event_id = Some(info.last_event_id.map_or(id.0, |last| last.max(id.0)));
+        }
+        info.events += ids.len() as u64;
+        insert_archive(archive_index, info);
+    }
+}
+
+fn push_owner_alias(alias: OwnerAlias) {
+    OWNER_ALIASES.with(|log| {
         let mut log = log.borrow_mut();
-        log.insert(event_id, event);
+        let seq = log.last_key_value().map_or(0, |(seq, _)| seq + 1);
+        log.insert(seq, alias);
+    });
+}
+
+// The aliases an archive that has applied `from` of them still needs, oldest first
+pub fn list_owner_aliases(from: u64) -> Vec<OwnerAlias> {
+    OWNER_ALIASES.with(|log| {
+        log.borrow()
+            .range(from..)
+            .map(|entry| entry.value())
+            .collect()
+    })
+}
+
+pub fn get_archive(index: u64) -> Option<ArchiveInfo> {
+    ARCHIVES.with(|archives| archives.borrow().get(&index))
+}
+
+pub fn latest_archive() -> Option<(u64, ArchiveInfo)> {
+    ARCHIVES.with(|archives| archives.borrow().last_key_value())
+}
+
+pub fn insert_archive(index: u64, info: ArchiveInfo) {
+    ARCHIVES.with(|archives| archives.borrow_mut().insert(index, info));
+}
+
+pub fn list_archives() -> Vec<ArchiveInfo> {
+    ARCHIVES.with(|archives| {
+        archives
+            .borrow()
+            .iter()
+            .map(|entry| entry.value())
+            .collect()
+    })
+}
+
+pub fn get_archive_config() -> ArchiveConfig {
+    ARCHIVE_CONFIG.with(|config| config.borrow().get().clone())
+}
+
+pub fn set_archive_config(config: ArchiveConfig) {
+    ARCHIVE_CONFIG.with(|cell| {
+        cell.borrow_mut().set(config);
+    });
+}
 
-        if log.len() > MAX_AUDIT_EVENT {
-            let oldest_id = log.iter().next().map(|entry| *entry.key());
-            if let Some(id) = oldest_id {
-                log.remove(&id);
+// Walks the log from just after `cursor` (or just before it when `newest_first`) until
+// `visit` returns false. With a `stream` only that principal's events are walked.
+pub fn scan_audit_log<F>(
+    stream: Option<&Principal>,
+    cursor: Option<EventId>,
+    newest_first: bool,
+    mut visit: F,
+) where
+    F: FnMut(EventId, AuditEvent) -> bool,
+{
+    let range = match (cursor, newest_first) {
+        (None, _) => (Bound::Unbounded, Bound::Unbounded),
+        (Some(id), false) => (Bound::Excluded(id), Bound::Unbounded),
+        (Some(id), true) => (Bound::Unbounded, Bound::Excluded(id)),
+    };
+
+    let Some(owner) = stream else {
+        return AUDIT_LOG.with(|log| {
+            let log = log.borrow();
+            let mut iter = log.range(range);
+            loop {
+                let entry = if newest_first {
+                    iter.next_back()
+                } else {
+                    iter.next()
+                };
+                let Some(entry) = entry else {
+                    break;
+                };
+                if !visit(*entry.key(), entry.value()) {
+                    break;
+                }
             }
-        }
+        });
+    };
+
+    let start = match range.0 {
+        Bound::Excluded(id) => Bound::Excluded(stream_key(owner, id)),
+        _ => Bound::Included(stream_key(owner, EventId(0))),
+    };
+    let end = match range.1 {
+        Bound::Excluded(id) => Bound::Excluded(stream_key(owner, id)),
+        _ => Bound::Included(stream_key(owner, EventId(u64::MAX))),
+    };
+
+    AUDIT_STREAMS.with(|index| {
+        let index = index.borrow();
+        let mut keys = index.keys_range((start, end));
+        AUDIT_LOG.with(|log| {
+            let log = log.borrow();
+            loop {
+                let key = if newest_first {
+                    keys.next_back()
+                } else {
+                    keys.next()
+                };
+                let Some(key) = key else {
+                    break;
+                };
+                let id = EventId(key.event_id);
+                let Some(event) = log.get(&id) else {
+                    continue;
+                };
+                if !visit(id, event) {
+                    break;
+                }
+            }
+        });
     });
-    event_id
 }
 
-pub fn update_vault<F, R>(blame: &Principal, f: F) -> Result<R, String>
+pub fn update_vault<F, R>(blame: &Principal, f: F) -> Result<R, ApiError>
 where
-    F: FnOnce(&mut Vault) -> Result<R, String>,
+    F: FnOnce(&mut Vault) -> Result<R, ApiError>,
 {
     VAULTS.with(|vaults| {
         let mut vaults = vaults.borrow_mut();
         let key = &return_stable_prin(blame);
 
-        let mut vault = vaults
-            .get(key)
-            .ok_or_else(|| "No vaults founds".to_string())?;
+        let mut vault = vaults.get(key).ok_or(ApiError::VaultNotFound)?;
 
         let res = f(&mut vault)?;
 
@@ -137,9 +801,46 @@ where
     })
 }
 
+fn owner_asset_key(owner: &Principal, asset_id: u64) -> OwnerAssetKey {
+    OwnerAssetKey {
+        owner: *owner,
+        asset_id,
+    }
+}
+
+fn heir_asset_keys(asset: &Asset) -> BTreeSet<OwnerAssetKey> {
+    asset
+        .heir_assingment
+        .iter()
+        .map(|h| owner_asset_key(&h.heir_principal, asset.id))
+        .collect()
+}
+
 pub fn insert_asset(asset: Asset) {
-    ASSETS.with(|assets| {
-        assets.borrow_mut().insert(AssetId(asset.id), asset);
+    let key = owner_asset_key(&asset.owner, asset.id);
+    let heir_keys = heir_asset_keys(&asset);
+    let previous = ASSETS.with(|assets| assets.borrow_mut().insert(AssetId(asset.id), asset));
+
+    ASSETS_BY_OWNER.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(previous) = &previous {
+            if previous.owner != key.owner {
+                index.remove(&owner_asset_key(&previous.owner, previous.id));
+            }
+        }
+        index.insert(key, ());
+    });
+
+    ASSETS_BY_HEIR.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(previous) = &previous {
+            for stale in heir_asset_keys(previous).difference(&heir_keys) {
+                index.remove(stale);
+            }
+        }
+        for key in heir_keys {
+            index.insert(key, ());
+        }
     });
 }
 
@@ -148,21 +849,138 @@ pub fn get_asset(asset_id: u64) -> Option<Asset> {
 }
 
 pub fn remove_asset(asset_id: u64) -> Option<Asset> {
-    ASSETS.with(|assets| assets.borrow_mut().remove(&AssetId(asset_id)))
+    let removed = ASSETS.with(|assets| assets.borrow_mut().remove(&AssetId(asset_id)));
+
+    if let Some(asset) = &removed {
+        ASSETS_BY_OWNER.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&owner_asset_key(&asset.owner, asset.id))
+        });
+        ASSETS_BY_HEIR.with(|index| {
+            let mut index = index.borrow_mut();
+            for key in heir_asset_keys(asset) {
+                index.remove(&key);
+            }
+        });
+    }
+    removed
 }
 
 pub fn list_user_assets(owner: &Principal) -> Vec<Asset> {
-    let mut result = Vec::new();
-    ASSETS.with(|assets| {
-        let assets = assets.borrow();
-        for item in assets.iter() {
-            let value = item.value();
-            if value.owner == *owner {
-                result.push(value);
-            }
+    let range = owner_asset_key(owner, 0)..=owner_asset_key(owner, u64::MAX);
+    let ids: Vec<u64> = ASSETS_BY_OWNER.with(|index| {
+        index
+            .borrow()
+            .keys_range(range)
+            .map(|key| key.asset_id)
+            .collect()
+    });
+
+    ids.into_iter().filter_map(get_asset).collect()
+}
+
+// Backfills the owner index for assets stored before it existed. A length mismatch means
+// the index is incomplete or stale, so it is rebuilt from scratch.
+pub fn migrate_owner_index() {
+    let assets_len = ASSETS.with(|assets| assets.borrow().len());
+    let index_len = ASSETS_BY_OWNER.with(|index| index.borrow().len());
+    if assets_len == index_len {
+        return;
+    }
+
+    ASSETS_BY_OWNER.with(|index| {
+        let mut index = index.borrow_mut();
+        let stale: Vec<OwnerAssetKey> = index.keys().collect();
+        for key in stale {
+            index.remove(&key);
         }
+
+        ASSETS.with(|assets| {
+            for entry in assets.borrow().iter() {
+                let asset = entry.value();
+                index.insert(owner_asset_key(&asset.owner, asset.id), ());
+            }
+        });
+    });
+}
+
+fn asset_history_range(asset_id: u64) -> std::ops::RangeInclusive<AssetVersionKey> {
+    AssetVersionKey {
+        asset_id,
+        version: 0,
+    }..=AssetVersionKey {
+        asset_id,
+        version: u32::MAX,
+    }
+}
+
+// Stores `previous` as the next version in the asset's history and returns its number
+pub fn archive_asset_version(previous: Asset, replaced_at: u64) -> u32 {
+    ASSET_HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        let version = history
+            .keys_range(asset_history_range(previous.id))
+            .next_back()
+            .map(|key| key.version + 1)
+            .unwrap_or(0);
+
+        let key = AssetVersionKey {
+            asset_id: previous.id,
+            version,
+        };
+        history.insert(
+            key,
+            AssetVersion {
+                version,
+                asset: previous,
+                replaced_at,
+            },
+        );
+        version
+    })
+}
+
+pub fn list_asset_history(asset_id: u64) -> Vec<AssetVersion> {
+    ASSET_HISTORY.with(|history| {
+        history
+            .borrow()
+            .range(asset_history_range(asset_id))
+            .map(|entry| entry.value())
+            .collect()
+    })
+}
+
+pub fn list_heir_assets(heir: &Principal) -> Vec<Asset> {
+    let range = owner_asset_key(heir, 0)..=owner_asset_key(heir, u64::MAX);
+    let ids: Vec<u64> = ASSETS_BY_HEIR.with(|index| {
+        index
+            .borrow()
+            .keys_range(range)
+            .map(|key| key.asset_id)
+            .collect()
+    });
+
+    ids.into_iter().filter_map(get_asset).collect()
+}
+
+// Backfills the heir index for assets stored before it existed. Assets can name any number
+// of heirs, so there is no length to compare and it is rebuilt whenever it is empty.
+pub fn migrate_heir_index() {
+    if !ASSETS_BY_HEIR.with(|index| index.borrow().is_empty()) {
+        return;
+    }
+
+    ASSETS_BY_HEIR.with(|index| {
+        let mut index = index.borrow_mut();
+        ASSETS.with(|assets| {
+            for entry in assets.borrow().iter() {
+                for key in heir_asset_keys(&entry.value()) {
+                    index.insert(key, ());
+                }
+            }
+        });
     });
-    result
 }
 
 // Global Counter to prevent assetid to being same
@@ -175,3 +993,280 @@ pub fn next_asset_id() -> u64 {
         current
     })
 }
+
+pub fn release_job_exists(key: &ReleaseJobKey) -> bool {
+    RELEASE_JOBS.with(|jobs| jobs.borrow().contains_key(key))
+}
+
+pub fn get_release_job(key: &ReleaseJobKey) -> Option<ReleaseJob> {
+    RELEASE_JOBS.with(|jobs| jobs.borrow().get(key))
+}
+
+pub fn insert_release_job(job: ReleaseJob) {
+    let key = ReleaseJobKey {
+        asset_id: job.asset_id,
+        heir: job.heir,
+    };
+    RELEASE_JOBS.with(|jobs| {
+        jobs.borrow_mut().insert(key, job);
+    });
+}
+
+pub fn list_owner_release_jobs(owner: &Principal) -> Vec<ReleaseJob> {
+    RELEASE_JOBS.with(|jobs| {
+        jobs.borrow()
+            .iter()
+            .map(|entry| entry.value())
+            .filter(|job| job.owner == *owner)
+            .collect()
+    })
+}
+
+pub fn list_due_release_jobs(cur_time: u64, limit: usize) -> Vec<ReleaseJobKey> {
+    RELEASE_JOBS.with(|jobs| {
+        jobs.borrow()
+            .iter()
+            .filter(|entry| {
+                let job = entry.value();
+                matches!(
+                    job.state,
+                    JobState::Queued | JobState::FailedRetryable { .. }
+                ) && job.next_attempt_at <= cur_time
+            })
+            .map(|entry| *entry.key())
+            .take(limit)
+            .collect()
+    })
+}
+
+pub fn list_in_flight_release_jobs() -> Vec<ReleaseJob> {
+    RELEASE_JOBS.with(|jobs| {
+        jobs.borrow()
+            .iter()
+            .map(|entry| entry.value())
+            .filter(|job| job.state == JobState::InFlight)
+            .collect()
+    })
+}
+
+fn owner_document_key(owner: &Principal, document_id: u64) -> OwnerDocumentKey {
+    OwnerDocumentKey {
+        owner: *owner,
+        document_id,
+    }
+}
+
+fn document_chunk_range(document_id: u64) -> std::ops::RangeInclusive<DocumentChunkKey> {
+    DocumentChunkKey {
+        document_id,
+        index: 0,
+    }..=DocumentChunkKey {
+        document_id,
+        index: u32::MAX,
+    }
+}
+
+pub fn next_document_id() -> u64 {
+    NEXT_DOCUMENT_ID.with(|id| {
+        let mut cell = id.borrow_mut();
+        let current = *cell.get();
+        let _ = cell.set(current + 1);
+        current
+    })
+}
+
+fn heir_document_keys(document: &Document) -> BTreeSet<OwnerDocumentKey> {
+    document
+        .access
+        .iter()
+        .map(|heir| owner_document_key(heir, document.id))
+        .collect()
+}
+
+pub fn insert_document(document: Document) {
+    let key = owner_document_key(&document.owner, document.id);
+    let heir_keys = heir_document_keys(&document);
+    let previous = DOCUMENTS.with(|documents| documents.borrow_mut().insert(document.id, document));
+
+    DOCUMENTS_BY_OWNER.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(previous) = &previous {
+            if previous.owner != key.owner {
+                index.remove(&owner_document_key(&previous.owner, previous.id));
+            }
+        }
+        index.insert(key, ());
+    });
+
+    DOCUMENTS_BY_HEIR.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(previous) = &previous {
+            for stale in heir_document_keys(previous).difference(&heir_keys) {
+                index.remove(stale);
+            }
+        }
+        for key in heir_keys {
+            index.insert(key, ());
+        }
+    });
+}
+
+pub fn get_document(document_id: u64) -> Option<Document> {
+    DOCUMENTS.with(|documents| documents.borrow().get(&document_id))
+}
+
+// Drops the metadata together with every chunk
+pub fn remove_document(document_id: u64) -> Option<Document> {
+    let removed = DOCUMENTS.with(|documents| documents.borrow_mut().remove(&document_id))?;
+
+    DOCUMENTS_BY_OWNER.with(|index| {
+        index
+            .borrow_mut()
+            .remove(&owner_document_key(&removed.owner, removed.id))
+    });
+    DOCUMENTS_BY_HEIR.with(|index| {
+        let mut index = index.borrow_mut();
+        for key in heir_document_keys(&removed) {
+            index.remove(&key);
+        }
+    });
+    DOCUMENT_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        let keys: Vec<DocumentChunkKey> = chunks
+            .keys_range(document_chunk_range(document_id))
+            .collect();
+        for key in keys {
+            chunks.remove(&key);
+        }
+    });
+    Some(removed)
+}
+
+pub fn list_user_documents(owner: &Principal) -> Vec<Document> {
+    let range = owner_document_key(owner, 0)..=owner_document_key(owner, u64::MAX);
+    let ids: Vec<u64> = DOCUMENTS_BY_OWNER.with(|index| {
+        index
+            .borrow()
+            .keys_range(range)
+            .map(|key| key.document_id)
+            .collect()
+    });
+
+    ids.into_iter().filter_map(get_document).collect()
+}
+
+pub fn list_heir_documents(heir: &Principal) -> Vec<Document> {
+    let range = owner_document_key(heir, 0)..=owner_document_key(heir, u64::MAX);
+    let ids: Vec<u64> = DOCUMENTS_BY_HEIR.with(|index| {
+        index
+            .borrow()
+            .keys_range(range)
+            .map(|key| key.document_id)
+            .collect()
+    });
+
+    ids.into_iter().filter_map(get_document).collect()
+}
+
+pub fn insert_document_chunk(document_id: u64, index: u32, bytes: Vec<u8>) {
+    DOCUMENT_CHUNKS.with(|chunks| {
+        chunks
+            .borrow_mut()
+            .insert(DocumentChunkKey { document_id, index }, bytes)
+    });
+}
+
+pub fn get_document_chunk(document_id: u64, index: u32) -> Option<Vec<u8>> {
+    DOCUMENT_CHUNKS.with(|chunks| {
+        chunks
+            .borrow()
+            .get(&DocumentChunkKey { document_id, index })
+    })
+}
+
+pub fn list_document_chunk_indexes(document_id: u64) -> Vec<u32> {
+    DOCUMENT_CHUNKS.with(|chunks| {
+        chunks
+            .borrow()
+            .keys_range(document_chunk_range(document_id))
+            .map(|key| key.index)
+            .collect()
+    })
+}
+
+// Every key under `owner`, which in LETTERS_BY_HEIR means every letter to that heir
+fn letter_range(owner: &Principal) -> std::ops::RangeInclusive<LetterKey> {
+    // Keys are length-prefixed and principals are at most 29 bytes, so these two bound
+    // every heir of the owner
+    LetterKey {
+        owner: *owner,
+        heir: Principal::management_canister(),
+    }..=LetterKey {
+        owner: *owner,
+        heir: Principal::from_slice(&[0xFF; 29]),
+    }
+}
+
+fn heir_letter_key(owner: &Principal, heir: &Principal) -> LetterKey {
+    LetterKey {
+        owner: *heir,
+        heir: *owner,
+    }
+}
+
+pub fn insert_letter(letter: Letter) {
+    let key = LetterKey {
+        owner: letter.owner,
+        heir: letter.heir,
+    };
+    LETTERS_BY_HEIR.with(|index| {
+        index
+            .borrow_mut()
+            .insert(heir_letter_key(&letter.owner, &letter.heir), ())
+    });
+    LETTERS.with(|letters| letters.borrow_mut().insert(key, letter));
+}
+
+pub fn get_letter(owner: &Principal, heir: &Principal) -> Option<Letter> {
+    LETTERS.with(|letters| {
+        letters.borrow().get(&LetterKey {
+            owner: *owner,
+            heir: *heir,
+        })
+    })
+}
+
+pub fn remove_letter(owner: &Principal, heir: &Principal) -> Option<Letter> {
+    LETTERS_BY_HEIR.with(|index| index.borrow_mut().remove(&heir_letter_key(owner, heir)));
+    LETTERS.with(|letters| {
+        letters.borrow_mut().remove(&LetterKey {
+            owner: *owner,
+            heir: *heir,
+        })
+    })
+}
+
+pub fn list_user_letters(owner: &Principal) -> Vec<Letter> {
+    LETTERS.with(|letters| {
+        letters
+            .borrow()
+            .range(letter_range(owner))
+            .map(|entry| entry.value())
+            .collect()
+    })
+}
+
+pub fn list_heir_letters(heir: &Principal) -> Vec<Letter> {
+    let owners: Vec<Principal> = LETTERS_BY_HEIR.with(|index| {
+        index
+            .borrow()
+            .keys_range(letter_range(heir))
+            .map(|key| key.heir)
+            .collect()
+    });
+
+    owners
+        .into_iter()
+        .filter_map(|owner| get_letter(&owner, heir))
+        .collect()
+}
@@ -1,9 +1,12 @@
 use std::borrow::Cow;
 
-use candid::{CandidType, Principal};
+use candid::{CandidType, Nat, Principal};
 use ic_stable_structures::{storable::Bound, Storable};
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
 use serde::{Deserialize, Serialize};
 
+use crate::migrations;
+
 #[derive(
     Clone, Copy, PartialEq, PartialOrd, Eq, Ord, Hash, Debug, CandidType, Serialize, Deserialize,
 )]
@@ -28,6 +31,54 @@ impl Storable for StablePrincipal {
     };
 }
 
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub enum ApiError {
+    AnonymousNotAllowed,
+    NotRegistered,
+    AlreadyRegistered,
+    VaultNotFound,
+    VaultAlreadyExists,
+    VaultReleased,
+    VaultNotReleased,
+    VaultNotPending,
+    VaultNotActive,
+    AssetNotFound,
+    Unauthorized,
+    ConcurrentModification,
+    RecoveryNotConfigured,
+    RecoveryRequestNotFound,
+    RecoveryRequestOpen,
+    RecoveryRequestExpired,
+    AlreadyApproved,
+    ThresholdNotMet { approvals: u32, threshold: u32 },
+    PayoutInProgress,
+    NothingToClaim,
+    PayoutNeedsReconciliation { reason: String },
+    EscrowBusy,
+    ArchiveUnavailable { reason: String },
+    InsufficientAllowance { required: Nat, current: Nat },
+    NftNotOwned { token_ids: Vec<Nat> },
+    NftNotApproved { token_ids: Vec<Nat> },
+    CallbackRejected { reason: String },
+    KeyDerivationFailed { reason: String },
+    TooManyRequests { retry_after: u64 },
+    DocumentNotFound,
+    StorageQuotaExceeded { used: u64, quota: u64 },
+    LetterNotFound,
+    BitcoinCallFailed { reason: String },
+    LedgerCallFailed { ledger: Principal, reason: String },
+    ValidationFailed { field: String, reason: String },
+}
+
+impl ApiError {
+    pub fn validation(field: impl Into<String>, reason: impl Into<String>) -> Self {
+        ApiError::ValidationFailed {
+            field: field.into(),
+            reason: reason.into(),
+        }
+    }
+}
+
 #[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
 pub struct UserProfile {
     pub first_name: String,
@@ -37,15 +88,15 @@ pub struct UserProfile {
 
 impl Storable for UserProfile {
     fn to_bytes(&self) -> Cow<'_, [u8]> {
-        Cow::Owned(candid::encode_one(self).unwrap())
+        Cow::Owned(migrations::encode(self))
     }
 
     fn into_bytes(self) -> Vec<u8> {
-        candid::encode_one(self).unwrap()
+        migrations::encode(&self)
     }
 
     fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
-        candid::decode_one(&bytes).unwrap()
+        migrations::decode(&bytes)
     }
 
     const BOUND: Bound = Bound::Unbounded;
@@ -73,6 +124,46 @@ pub struct RecoveryConfig {
     pub threshold: u32,
 }
 
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub enum HeirVisibility {
+    // Heirs learn nothing until the vault is released
+    Hidden,
+    // Heirs can see they are named and the vault status, but not the assets
+    ExistenceOnly,
+    // Heirs can see the assets they are named on and their share
+    Full,
+}
+
+// What happens to the part of an asset not covered by heir percentages
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub enum RemainderPolicy {
+    // Percentages must sum to exactly 100
+    RequireFull,
+    // The unassigned part is never transferred
+    KeepWithOwner,
+    // The unassigned part is split between heirs in proportion to their percentages
+    SplitProportionally,
+    // The unassigned part goes to one residual heir
+    ToHeir { heir: Principal },
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub enum RecoveryAction {
+    // Owner lost their Internet Identity, move the vault to a new principal
+    TransferOwnership { new_owner: Principal },
+    // Owner is alive but missed heartbeats, put the vault back to Active
+    CancelPending,
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct RecoveryRequest {
+    pub action: RecoveryAction,
+    pub initiated_by: Principal,
+    pub approvals: Vec<Principal>,
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
 #[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
 pub struct Vault {
     pub owner: Principal,
@@ -81,36 +172,299 @@ pub struct Vault {
     pub dms: DeadManSwitch,
     pub recovery_config: Option<RecoveryConfig>,
     pub next_asset_id: u64,
+    pub recovery_request: Option<RecoveryRequest>,
+    pub heir_visibility: Option<HeirVisibility>,
+    pub remainder_policy: Option<RemainderPolicy>,
+    // Fixed the first time an escrow asset is touched or a secret is sealed, and kept
+    // through recovery, so deposited funds never have to move and sealed secrets stay
+    // decryptable
+    pub escrow_subaccount: Option<Subaccount>,
 }
 
 impl Storable for Vault {
     fn to_bytes(&self) -> Cow<'_, [u8]> {
-        Cow::Owned(candid::encode_one(self).unwrap())
+        Cow::Owned(migrations::encode(self))
     }
 
     fn into_bytes(self) -> Vec<u8> {
-        candid::encode_one(self).unwrap()
+        migrations::encode(&self)
     }
 
     fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
-        candid::decode_one(&bytes).unwrap()
+        migrations::decode(&bytes)
     }
 
     const BOUND: Bound = Bound::Unbounded;
 }
 
-#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
-pub enum EventType {
+// What an event is about, without its payload. Used for filtering and retention.
+#[derive(Clone, Copy, Serialize, Deserialize, CandidType, PartialEq, Eq, Debug)]
+pub enum EventKind {
     VaultCreated,
+    DmsConfigured,
     AssetCreated,
     AssetUpdated,
     AssetDeleted,
+    AllowanceVerified,
+    NftApprovalVerified,
+    ControlVerified,
+    CallbackVerified,
+    SecretKeyDerived,
+    DocumentUploaded,
+    DocumentRemoved,
+    DocumentAccessChanged,
+    LetterWritten,
+    LetterDeleted,
+    LetterRead,
+    BitcoinAddressDerived,
+    BitcoinTransactionSigned,
+    EvmAddressDerived,
+    EvmTransactionSigned,
+    EvmWithdrawalSigned,
     HeirAdded,
     HeirRemoved,
+    HeirChanged,
     Heartbeat,
     SwitchPending,
     VaultReleased,
+    PayoutClaimed,
+    PayoutCompleted,
+    PayoutFailed,
+    RecoveryConfigured,
     RecoveryInitiated,
+    RecoveryApproved,
+    RecoveryCancelled,
+    RecoveryExecuted,
+    VaultRecovered,
+    EscrowDeposited,
+    EscrowWithdrawn,
+    ControllersChanged,
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub enum EventType {
+    VaultCreated,
+    // Intervals in nanoseconds
+    DmsConfigured {
+        old_heartbeat_interval: u64,
+        new_heartbeat_interval: u64,
+        old_grace_period: u64,
+        new_grace_period: u64,
+    },
+    AssetCreated {
+        asset_id: u64,
+        name: String,
+    },
+    AssetUpdated {
+        asset_id: u64,
+        archived_version: u32,
+        changes: Vec<String>,
+    },
+    AssetDeleted {
+        asset_id: u64,
+        name: String,
+    },
+    AllowanceVerified {
+        ledger_canister: Principal,
+        required: u64,
+        allowance: Nat,
+    },
+    NftApprovalVerified {
+        collection: Principal,
+        token_ids: Vec<Nat>,
+    },
+    ControlVerified {
+        canister_id: Principal,
+        controllers: Vec<Principal>,
+    },
+    CallbackVerified {
+        canister: Principal,
+        method: String,
+    },
+    SecretKeyDerived {
+        asset_id: u64,
+        heir: Principal,
+    },
+    DocumentUploaded {
+        document_id: u64,
+        name: String,
+        size: u64,
+        sha256: Vec<u8>,
+    },
+    DocumentRemoved {
+        document_id: u64,
+    },
+    DocumentAccessChanged {
+        document_id: u64,
+        access: Vec<Principal>,
+    },
+    LetterWritten {
+        heir: Principal,
+    },
+    LetterDeleted {
+        heir: Principal,
+    },
+    LetterRead {
+        heir: Principal,
+    },
+    BitcoinAddressDerived {
+        asset_id: u64,
+        address: String,
+    },
+    BitcoinTransactionSigned {
+        asset_id: u64,
+        txid: String,
+        inputs: u32,
+        fee: u64,
+    },
+    EvmAddressDerived {
+        asset_id: u64,
+        address: String,
+    },
+    EvmTransactionSigned {
+        asset_id: u64,
+        heir: Principal,
+        nonce: u64,
+        tx_hash: String,
+    },
+    EvmWithdrawalSigned {
+        asset_id: u64,
+        to: String,
+        value: u128,
+        nonce: u64,
+        tx_hash: String,
+    },
+    HeirAdded {
+        asset_id: u64,
+        heir: Principal,
+        percentage: u8,
+    },
+    HeirRemoved {
+        asset_id: u64,
+        heir: Principal,
+        percentage: u8,
+    },
+    HeirChanged {
+        asset_id: u64,
+        heir: Principal,
+        old_percentage: u8,
+        new_percentage: u8,
+    },
+    Heartbeat,
+    SwitchPending {
+        last_heartbeat: u64,
+    },
+    VaultReleased {
+        pending_since: u64,
+    },
+    PayoutClaimed {
+        asset_id: u64,
+        heir: Principal,
+    },
+    PayoutCompleted {
+        asset_id: u64,
+        heir: Principal,
+        ledger_canister: Principal,
+        amount: u64,
+        fee: u64,
+        block_index: Nat,
+    },
+    PayoutFailed {
+        asset_id: u64,
+        heir: Principal,
+        ledger_canister: Principal,
+        reason: String,
+    },
+    RecoveryConfigured {
+        recovery_principals: Vec<Principal>,
+        threshold: u32,
+    },
+    RecoveryInitiated {
+        action: RecoveryAction,
+        initiated_by: Principal,
+    },
+    RecoveryApproved {
+        approver: Principal,
+        approvals: u32,
+    },
+    RecoveryCancelled,
+    RecoveryExecuted {
+        action: RecoveryAction,
+        executed_by: Principal,
+    },
+    VaultRecovered {
+        previous_owner: Principal,
+    },
+    EscrowDeposited {
+        asset_id: u64,
+        ledger_canister: Principal,
+        amount: u64,
+    },
+    EscrowWithdrawn {
+        asset_id: u64,
+        ledger_canister: Principal,
+        amount: u64,
+        fee: u64,
+        block_index: Nat,
+    },
+    ControllersChanged {
+        asset_id: u64,
+        canister_id: Principal,
+        added: Vec<Principal>,
+        removed: Vec<Principal>,
+    },
+    // Logged before payloads existed, only the free-text description survives
+    Legacy {
+        kind: EventKind,
+        details: String,
+    },
+}
+
+impl EventType {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            EventType::VaultCreated => EventKind::VaultCreated,
+            EventType::DmsConfigured { .. } => EventKind::DmsConfigured,
+            EventType::AssetCreated { .. } => EventKind::AssetCreated,
+            EventType::AssetUpdated { .. } => EventKind::AssetUpdated,
+            EventType::AssetDeleted { .. } => EventKind::AssetDeleted,
+            EventType::AllowanceVerified { .. } => EventKind::AllowanceVerified,
+            EventType::NftApprovalVerified { .. } => EventKind::NftApprovalVerified,
+            EventType::ControlVerified { .. } => EventKind::ControlVerified,
+            EventType::CallbackVerified { .. } => EventKind::CallbackVerified,
+            EventType::SecretKeyDerived { .. } => EventKind::SecretKeyDerived,
+            EventType::DocumentUploaded { .. } => EventKind::DocumentUploaded,
+            EventType::DocumentRemoved { .. } => EventKind::DocumentRemoved,
+            EventType::DocumentAccessChanged { .. } => EventKind::DocumentAccessChanged,
+            EventType::LetterWritten { .. } => EventKind::LetterWritten,
+            EventType::LetterDeleted { .. } => EventKind::LetterDeleted,
+            EventType::LetterRead { .. } => EventKind::LetterRead,
+            EventType::BitcoinAddressDerived { .. } => EventKind::BitcoinAddressDerived,
+            EventType::BitcoinTransactionSigned { .. } => EventKind::BitcoinTransactionSigned,
+            EventType::EvmAddressDerived { .. } => EventKind::EvmAddressDerived,
+            EventType::EvmTransactionSigned { .. } => EventKind::EvmTransactionSigned,
+            EventType::EvmWithdrawalSigned { .. } => EventKind::EvmWithdrawalSigned,
+            EventType::HeirAdded { .. } => EventKind::HeirAdded,
+            EventType::HeirRemoved { .. } => EventKind::HeirRemoved,
+            EventType::HeirChanged { .. } => EventKind::HeirChanged,
+            EventType::Heartbeat => EventKind::Heartbeat,
+            EventType::SwitchPending { .. } => EventKind::SwitchPending,
+            EventType::VaultReleased { .. } => EventKind::VaultReleased,
+            EventType::PayoutClaimed { .. } => EventKind::PayoutClaimed,
+            EventType::PayoutCompleted { .. } => EventKind::PayoutCompleted,
+            EventType::PayoutFailed { .. } => EventKind::PayoutFailed,
+            EventType::RecoveryConfigured { .. } => EventKind::RecoveryConfigured,
+            EventType::RecoveryInitiated { .. } => EventKind::RecoveryInitiated,
+            EventType::RecoveryApproved { .. } => EventKind::RecoveryApproved,
+            EventType::RecoveryCancelled => EventKind::RecoveryCancelled,
+            EventType::RecoveryExecuted { .. } => EventKind::RecoveryExecuted,
+            EventType::VaultRecovered { .. } => EventKind::VaultRecovered,
+            EventType::EscrowDeposited { .. } => EventKind::EscrowDeposited,
+            EventType::EscrowWithdrawn { .. } => EventKind::EscrowWithdrawn,
+            EventType::ControllersChanged { .. } => EventKind::ControllersChanged,
+            EventType::Legacy { kind, .. } => *kind,
+        }
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
@@ -118,25 +472,205 @@ pub struct AuditEvent {
     pub timestamp: u64,
     pub event_type: EventType,
     pub blame: Principal,
-    pub details: String,
+    // Chain hash of the event logged just before this one. None only for events that were
+    // pruned before the chain existed.
+    pub prev_hash: Option<Vec<u8>>,
 }
 
 impl Storable for AuditEvent {
     fn to_bytes(&self) -> Cow<'_, [u8]> {
-        Cow::Owned(candid::encode_one(self).unwrap())
+        Cow::Owned(migrations::encode(self))
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        migrations::encode(&self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        migrations::decode(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct AuditQuery {
+    // Exclusive: the page starts after this id (before it when `newest_first`)
+    pub cursor: Option<u64>,
+    pub limit: Option<u32>,
+    pub event_types: Option<Vec<EventKind>>,
+    pub from_time: Option<u64>,
+    pub to_time: Option<u64>,
+    pub newest_first: Option<bool>,
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct AuditEntry {
+    pub id: u64,
+    pub event: AuditEvent,
+}
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Debug)]
+pub struct AuditPage {
+    pub entries: Vec<AuditEntry>,
+    // None once the log is exhausted. May be set even when `entries` is short, because a
+    // single call only scans a bounded number of events.
+    pub next_cursor: Option<u64>,
+    // Archive canisters that may hold older events matching the query, to be called with
+    // the given args
+    pub archived: Vec<ArchivedEventsCallback>,
+}
+
+// Mirrors the interface of the InheritNext_archive canister
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct ArchiveInit {
+    pub backend: Principal,
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct ArchivedEvent {
+    pub id: u64,
+    pub owner: Principal,
+    pub timestamp: u64,
+    // Candid-encoded `AuditEvent`
+    pub event: Vec<u8>,
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct GetArchivedEventsArgs {
+    pub owner: Option<Principal>,
+    pub cursor: Option<u64>,
+    pub limit: Option<u32>,
+    pub newest_first: Option<bool>,
+    pub from_time: Option<u64>,
+    pub to_time: Option<u64>,
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct ArchivedEventsPage {
+    pub events: Vec<ArchivedEvent>,
+    pub next_cursor: Option<u64>,
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub enum ArchiveError {
+    Unauthorized,
+}
+
+// Archived events filed under `previous` belong to `owner`'s stream since a recovery. None
+// withdraws the alias once `previous` has a vault of their own again.
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct OwnerAlias {
+    pub previous: Principal,
+    pub owner: Option<Principal>,
+}
+
+impl Storable for OwnerAlias {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(migrations::encode(self))
     }
 
     fn into_bytes(self) -> Vec<u8> {
-        candid::encode_one(self).unwrap()
+        migrations::encode(&self)
     }
 
     fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
-        candid::decode_one(&bytes).unwrap()
+        migrations::decode(&bytes)
     }
 
     const BOUND: Bound = Bound::Unbounded;
 }
 
+pub type ArchivedEventsResult = Result<ArchivedEventsPage, ArchiveError>;
+
+candid::define_function!(pub GetArchivedEventsFn : (GetArchivedEventsArgs) -> (ArchivedEventsResult) query);
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Debug)]
+pub struct ArchivedEventsCallback {
+    pub callback: GetArchivedEventsFn,
+    pub args: GetArchivedEventsArgs,
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct ArchiveInfo {
+    pub canister_id: Principal,
+    pub installed: bool,
+    pub first_event_id: Option<u64>,
+    pub last_event_id: Option<u64>,
+    pub events: u64,
+    pub created_at: u64,
+    // How much of the owner alias log the archive has applied
+    pub aliases_pushed: Option<u64>,
+}
+
+impl Storable for ArchiveInfo {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(migrations::encode(self))
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        migrations::encode(&self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        migrations::decode(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Wasm installed into every new archive canister, uploaded by a controller
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug, Default)]
+pub struct ArchiveConfig {
+    pub wasm: Vec<u8>,
+    // Added next to the backend as controllers of new archives
+    pub controllers: Vec<Principal>,
+}
+
+impl Storable for ArchiveConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(migrations::encode(self))
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        migrations::encode(&self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        migrations::decode(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct AuditChainHead {
+    pub event_id: Option<u64>,
+    pub hash: Vec<u8>,
+    // Subnet certificate over `hash`, only available to non-replicated queries
+    pub certificate: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct AuditChainBreak {
+    pub event_id: u64,
+    pub reason: String,
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct AuditChainReport {
+    pub from: u64,
+    pub to: u64,
+    pub checked: u64,
+    // Events removed by retention whose hashes are still kept, so the chain links across them.
+    // Events shipped to an archive lose their hash too, and the link across them is only
+    // checked against the archive.
+    pub pruned: u64,
+    pub breaks: Vec<AuditChainBreak>,
+    // Set when the range reaches the newest event: whether it ends at the certified head
+    pub head_matches: Option<bool>,
+}
+
 #[derive(
     Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, CandidType, Serialize, Deserialize, Debug,
 )]
@@ -163,6 +697,79 @@ impl Storable for EventId {
     };
 }
 
+// Per-vault audit stream index entry. Events belong to the stream of the principal they
+// blame, which for every vault event is the vault owner.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct StreamEventKey {
+    pub owner: Principal,
+    pub event_id: u64,
+}
+
+impl Storable for StreamEventKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned((*self).into_bytes())
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        let owner = self.owner.as_slice();
+        let mut bytes = Vec::with_capacity(1 + owner.len() + 8);
+        bytes.push(owner.len() as u8);
+        bytes.extend_from_slice(owner);
+        bytes.extend_from_slice(&self.event_id.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let len = bytes[0] as usize;
+        let mut arr = [0u8; 8];
+        arr.copy_from_slice(&bytes[1 + len..1 + len + 8]);
+        StreamEventKey {
+            owner: Principal::from_slice(&bytes[1..1 + len]),
+            event_id: u64::from_be_bytes(arr),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 38,
+        is_fixed_size: false,
+    };
+}
+
+// Retention bookkeeping for one audit stream. Events beyond the retained window are queued
+// for the archive canister; heartbeats are counted separately in the `compacted_*` fields.
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug, Default)]
+pub struct AuditStreamStats {
+    pub retained_events: u64,
+    pub retained_heartbeats: u64,
+    pub evicted_events: u64,
+    pub compacted_heartbeats: u64,
+    pub first_compacted_heartbeat: Option<u64>,
+    pub last_compacted_heartbeat: Option<u64>,
+    // Evicted events already handed over to an archive canister
+    pub archived_events: u64,
+    // Evicted events still waiting in the archive queue
+    pub queued_events: u64,
+    // Compacted heartbeats deleted without reaching an archive because the queue was full.
+    // Critical events are never dropped.
+    pub dropped_events: u64,
+}
+
+impl Storable for AuditStreamStats {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(migrations::encode(self))
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        migrations::encode(&self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        migrations::decode(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
 #[derive(
     Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, CandidType, Serialize, Deserialize, Debug,
 )]
@@ -189,18 +796,157 @@ impl Storable for AssetId {
     };
 }
 
+// Secondary index entry so an owner's assets can be found without scanning every asset
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct OwnerAssetKey {
+    pub owner: Principal,
+    pub asset_id: u64,
+}
+
+impl Storable for OwnerAssetKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned((*self).into_bytes())
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        let owner = self.owner.as_slice();
+        let mut bytes = Vec::with_capacity(1 + owner.len() + 8);
+        bytes.push(owner.len() as u8);
+        bytes.extend_from_slice(owner);
+        bytes.extend_from_slice(&self.asset_id.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let len = bytes[0] as usize;
+        let mut arr = [0u8; 8];
+        arr.copy_from_slice(&bytes[1 + len..1 + len + 8]);
+        OwnerAssetKey {
+            owner: Principal::from_slice(&bytes[1..1 + len]),
+            asset_id: u64::from_be_bytes(arr),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 38,
+        is_fixed_size: false,
+    };
+}
+
 #[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
 pub enum AssetType {
     ICRC2Token {
         ledger_canister: Principal,
         amount: u64,
     },
+    // Tokens held by this canister in the vault's escrow subaccount. `amount` is the
+    // credited balance and only moves through deposits, withdrawals and payouts.
+    ICRC1Escrow {
+        ledger_canister: Principal,
+        amount: u64,
+    },
+    // ICP held in the same escrow subaccount, but moved with the ledger's legacy
+    // `transfer`/`account_balance` interface so heirs can be paid to raw account identifiers
+    ICPLedger {
+        ledger_canister: Principal,
+        amount: u64,
+    },
+    // Whole tokens of an ICRC-7 collection, moved with ICRC-37 `transfer_from`. Heirs are
+    // given tokens through `HeirAssignment.token_ids` instead of percentages.
+    ICRC7Nft {
+        collection: Principal,
+        token_ids: Vec<Nat>,
+    },
+    // Control of a canister this canister is already a controller of. On release every heir
+    // is added as a controller, and the owner dropped if `remove_owner` is set. Heirs carry
+    // no percentage.
+    CanisterControl {
+        canister_id: Principal,
+        remove_owner: bool,
+    },
+    // A position held in a third-party canister that implements the interface in
+    // callback.did. `inheritnext_verify` confirms it when added, and on release `method` is
+    // called once per heir with their share and the opaque `payload`.
+    Callback {
+        canister: Principal,
+        method: String,
+        payload: Vec<u8>,
+    },
+    // Seed phrases, passwords, instructions. Sealed in the browser to each heir's vetKD
+    // identity, so this canister only ever holds ciphertext.
+    EncryptedSecret {
+        ciphertexts: Vec<SealedSecret>,
+    },
+    // A P2WPKH address of this canister's threshold ECDSA key, derived per owner and asset.
+    // Both fields are filled in by the backend, whatever the owner sends.
+    BitcoinAddress {
+        address: String,
+        derivation_path: Vec<Vec<u8>>,
+    },
+    // An EVM address of the same threshold ECDSA key. Nothing here is read from the chain:
+    // `amount` is what the owner says the address holds, in wei or ERC-20 base units, and
+    // the fee caps are used as is when the payouts are signed. `token` is the ERC-20
+    // contract, or None for native ETH. An ERC-20 address also needs ETH for gas.
+    // `address`, `derivation_path` and `next_nonce` are kept by the backend.
+    EvmAddress {
+        address: String,
+        derivation_path: Vec<Vec<u8>>,
+        next_nonce: u64,
+        chain_id: u64,
+        token: Option<String>,
+        amount: u128,
+        max_fee_per_gas: u128,
+        max_priority_fee_per_gas: u128,
+    },
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct SealedSecret {
+    pub heir: Principal,
+    pub ciphertext: Vec<u8>,
+}
+
+// What the owner needs to seal a secret to one heir with IBE
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct SecretEncryptionKey {
+    pub public_key: Vec<u8>,
+    pub derivation_id: Vec<u8>,
+}
+
+// The heir's vetKey, encrypted under the transport key they sent. `public_key` and
+// `derivation_id` let them verify it after decrypting.
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct SecretDecryptionKey {
+    pub encrypted_key: Vec<u8>,
+    pub public_key: Vec<u8>,
+    pub derivation_id: Vec<u8>,
 }
 
 #[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
 pub struct HeirAssignment {
     pub heir_principal: Principal,
     pub percentage: u8,
+    // Where the heir is paid when a principal can't be: a hex account identifier for
+    // ICPLedger assets (optional), a Bitcoin address for BitcoinAddress assets and an EVM
+    // address for EvmAddress assets (required)
+    pub account_identifier: Option<String>,
+    // ICRC7Nft assets only: the tokens this heir inherits. `percentage` must be 0.
+    pub token_ids: Option<Vec<Nat>>,
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub enum TransferStatus {
+    Completed { block_index: Nat },
+    Failed { reason: String },
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct HeirTransfer {
+    pub heir_principal: Principal,
+    pub amount: u64,
+    pub fee: u64,
+    pub status: TransferStatus,
+    pub timestamp: u64,
 }
 
 #[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
@@ -212,20 +958,549 @@ pub struct Asset {
     pub description: String,
     pub created_at: u64,
     pub heir_assingment: Vec<HeirAssignment>,
+    // opt so assets stored before distribution existed still decode
+    pub transfers: Option<Vec<HeirTransfer>>,
+    // The account the allowance, approval or controller entry was given by, pinned to the
+    // previous owner on an ownership transfer. None means `owner`.
+    pub payout_from: Option<Principal>,
 }
 
 impl Storable for Asset {
     fn to_bytes(&self) -> Cow<'_, [u8]> {
-        Cow::Owned(candid::encode_one(self).expect("Failed to encode Asset"))
+        Cow::Owned(migrations::encode(self))
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        migrations::encode(&self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        migrations::decode(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct HeirAssetView {
+    pub asset_id: u64,
+    pub name: String,
+    pub description: String,
+    pub asset_type: AssetType,
+    pub percentage: u8,
+    pub token_ids: Vec<Nat>,
+    pub transfers: Vec<HeirTransfer>,
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct InheritanceView {
+    pub owner: Principal,
+    pub status: VaultStatus,
+    pub assets: Vec<HeirAssetView>,
+}
+
+// crc32 of the hash ++ sha224(0x0A "account-id" ++ principal ++ subaccount)
+pub type AccountIdentifier = [u8; 32];
+
+// Mirrors the legacy ICP ledger interface
+#[derive(Clone, Copy, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct Tokens {
+    pub e8s: u64,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct IcpTimeStamp {
+    pub timestamp_nanos: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct IcpTransferArgs {
+    pub memo: u64,
+    pub amount: Tokens,
+    pub fee: Tokens,
+    pub from_subaccount: Option<Subaccount>,
+    pub to: AccountIdentifier,
+    pub created_at_time: Option<IcpTimeStamp>,
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub enum IcpTransferError {
+    BadFee { expected_fee: Tokens },
+    InsufficientFunds { balance: Tokens },
+    TxTooOld { allowed_window_nanos: u64 },
+    TxCreatedInFuture,
+    TxDuplicate { duplicate_of: u64 },
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct AccountBalanceArgs {
+    pub account: AccountIdentifier,
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct TransferFeeArg {}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct TransferFee {
+    pub transfer_fee: Tokens,
+}
+
+// Mirrors the parts of the ICRC-7 and ICRC-37 interfaces we call
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct IsApprovedArg {
+    pub spender: Account,
+    pub from_subaccount: Option<Subaccount>,
+    pub token_id: Nat,
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct NftTransferFromArg {
+    pub spender_subaccount: Option<Subaccount>,
+    pub from: Account,
+    pub to: Account,
+    pub token_id: Nat,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub enum NftTransferFromError {
+    InvalidRecipient,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+// Mirrors callback.did, the interface third-party canisters implement for Callback assets
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct InheritNextVerifyArgs {
+    pub owner: Principal,
+    pub method: String,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct InheritNextReleaseArgs {
+    pub owner: Principal,
+    pub heir: Principal,
+    pub asset_id: u64,
+    pub share: u64,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub enum InheritNextReleaseError {
+    Retry { reason: String },
+    Rejected { reason: String },
+}
+
+// Where the owner sends tokens for escrowed assets. ICRC ledgers take `account`, legacy ICP
+// tooling the hex `account_identifier` of the same account.
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct EscrowAccount {
+    pub account: Account,
+    pub account_identifier: String,
+}
+
+// One payout of one asset to one heir. Keying jobs by (asset, heir) means enqueueing
+// the same release twice can never create a second payout
+#[derive(
+    Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, CandidType, Serialize, Deserialize, Debug,
+)]
+pub struct ReleaseJobKey {
+    pub asset_id: u64,
+    pub heir: Principal,
+}
+
+impl Storable for ReleaseJobKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned((*self).into_bytes())
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = self.asset_id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(self.heir.as_slice());
+        bytes
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let mut arr = [0u8; 8];
+        arr.copy_from_slice(&bytes[..8]);
+        ReleaseJobKey {
+            asset_id: u64::from_be_bytes(arr),
+            heir: Principal::from_slice(&bytes[8..]),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 37,
+        is_fixed_size: false,
+    };
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub enum JobState {
+    Queued,
+    InFlight,
+    Succeeded { block_index: Nat },
+    FailedRetryable { reason: String },
+    FailedPermanent { reason: String },
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct ReleaseJob {
+    pub owner: Principal,
+    pub asset_id: u64,
+    pub heir: Principal,
+    pub ledger_canister: Principal,
+    pub share: u64,
+    pub fee: Option<u64>,
+    pub state: JobState,
+    pub attempts: u32,
+    pub next_attempt_at: u64,
+    // Fixed per job so the ledger deduplicates retries instead of paying twice
+    pub created_at_time: u64,
+    // Set once an attempt may have reached the ledger without us seeing the result
+    pub outcome_unknown: bool,
+    // Escrow payouts are sent from this subaccount of the canister, the rest are pulled
+    // from the owner with ICRC-2
+    pub from_subaccount: Option<Subaccount>,
+    // Set for ICPLedger payouts, which go through the legacy `transfer` to this account
+    pub to_account_identifier: Option<AccountIdentifier>,
+    // Set for ICRC7Nft payouts: the tokens still to transfer. `share` is the number of
+    // tokens the heir gets in total.
+    pub token_ids: Option<Vec<Nat>>,
+    // Set for CanisterControl payouts: nothing is transferred, the heirs are made
+    // controllers of `ledger_canister` instead
+    pub grants_control: bool,
+    // Set for Callback payouts: `ledger_canister` is called to hand over the position and
+    // `share` is in basis points
+    pub calls_back: bool,
+    // Set for BitcoinAddress payouts: the heir's address, fixed when the job is created
+    pub bitcoin_address: Option<String>,
+    // The signed transaction paying every heir of the asset. Kept so a retry broadcasts
+    // the same transaction instead of building a second one.
+    pub bitcoin_tx: Option<Vec<u8>>,
+    // The rest of the payout when the address held more UTXOs than fit in one transaction
+    pub bitcoin_extra_txs: Option<Vec<Vec<u8>>>,
+    // Set for EvmAddress payouts, in which case `share` is in basis points
+    pub evm_transfer: Option<EvmTransfer>,
+    // Copied from the asset: the account tokens are pulled from instead of `owner`
+    pub payout_from: Option<Principal>,
+}
+
+impl ReleaseJob {
+    pub fn payer(&self) -> Principal {
+        self.payout_from.unwrap_or(self.owner)
+    }
+}
+
+// Everything needed to sign one EVM payout, fixed when the job is created so every
+// attempt signs the same transfer with the same nonce
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct EvmTransfer {
+    pub to: String,
+    pub nonce: u64,
+    // Wei or token base units the heir receives, gas already taken out for native ETH
+    pub value: u128,
+    // The signed raw transaction, ready for anyone to broadcast
+    pub signed_tx: Option<Vec<u8>>,
+    pub tx_hash: Option<String>,
+}
+
+impl Storable for ReleaseJob {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(migrations::encode(self))
     }
 
     fn into_bytes(self) -> Vec<u8> {
-        candid::encode_one(self).expect("Failed to encode Asset")
+        migrations::encode(&self)
     }
 
     fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
-        candid::decode_one(&bytes).expect("Failed to decode Asset - storage corruption detected")
+        migrations::decode(&bytes)
     }
 
     const BOUND: Bound = Bound::Unbounded;
 }
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct AssetUpdate {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub asset_type: Option<AssetType>,
+    pub heir_assingment: Option<Vec<HeirAssignment>>,
+}
+
+#[derive(
+    Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, CandidType, Serialize, Deserialize, Debug,
+)]
+pub struct AssetVersionKey {
+    pub asset_id: u64,
+    pub version: u32,
+}
+
+impl Storable for AssetVersionKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned((*self).into_bytes())
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = self.asset_id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.version.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let mut id = [0u8; 8];
+        id.copy_from_slice(&bytes[..8]);
+        let mut version = [0u8; 4];
+        version.copy_from_slice(&bytes[8..12]);
+        AssetVersionKey {
+            asset_id: u64::from_be_bytes(id),
+            version: u32::from_be_bytes(version),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 12,
+        is_fixed_size: true,
+    };
+}
+
+// A snapshot of an asset as it was before an update replaced it
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct AssetVersion {
+    pub version: u32,
+    pub asset: Asset,
+    pub replaced_at: u64,
+}
+
+impl Storable for AssetVersion {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(migrations::encode(self))
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        migrations::encode(&self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        migrations::decode(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, CandidType, PartialEq, Eq, Debug)]
+pub enum DocumentLink {
+    Vault,
+    Asset { asset_id: u64 },
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, CandidType, PartialEq, Eq, Debug)]
+pub enum DocumentStatus {
+    Uploading,
+    Committed,
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct DocumentUpload {
+    pub name: String,
+    pub mime_type: String,
+    pub size: u64,
+    pub link: DocumentLink,
+    pub access: Vec<Principal>,
+}
+
+// A will, deed scan or video letter. The bytes live in DOCUMENT_CHUNKS; every chunk
+// but the last is exactly DOCUMENT_CHUNK_SIZE long.
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct Document {
+    pub id: u64,
+    pub owner: Principal,
+    pub name: String,
+    pub mime_type: String,
+    pub size: u64,
+    pub chunk_count: u32,
+    pub link: DocumentLink,
+    // Heirs who may download it once the vault is released
+    pub access: Vec<Principal>,
+    pub status: DocumentStatus,
+    // Set on commit
+    pub sha256: Option<Vec<u8>>,
+    pub created_at: u64,
+}
+
+impl Storable for Document {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(migrations::encode(self))
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        migrations::encode(&self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        migrations::decode(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct StorageUsage {
+    // Includes the declared size of uploads not committed yet
+    pub used: u64,
+    pub quota: u64,
+    pub documents: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct DocumentChunkKey {
+    pub document_id: u64,
+    pub index: u32,
+}
+
+impl Storable for DocumentChunkKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned((*self).into_bytes())
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = self.document_id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let mut id = [0u8; 8];
+        id.copy_from_slice(&bytes[..8]);
+        let mut index = [0u8; 4];
+        index.copy_from_slice(&bytes[8..12]);
+        DocumentChunkKey {
+            document_id: u64::from_be_bytes(id),
+            index: u32::from_be_bytes(index),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 12,
+        is_fixed_size: true,
+    };
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct OwnerDocumentKey {
+    pub owner: Principal,
+    pub document_id: u64,
+}
+
+impl Storable for OwnerDocumentKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned((*self).into_bytes())
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        let owner = self.owner.as_slice();
+        let mut bytes = Vec::with_capacity(1 + owner.len() + 8);
+        bytes.push(owner.len() as u8);
+        bytes.extend_from_slice(owner);
+        bytes.extend_from_slice(&self.document_id.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let len = bytes[0] as usize;
+        let mut arr = [0u8; 8];
+        arr.copy_from_slice(&bytes[1 + len..1 + len + 8]);
+        OwnerDocumentKey {
+            owner: Principal::from_slice(&bytes[1..1 + len]),
+            document_id: u64::from_be_bytes(arr),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 38,
+        is_fixed_size: false,
+    };
+}
+
+// A final message from the owner to one heir, sealed until the vault is released
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct Letter {
+    pub owner: Principal,
+    pub heir: Principal,
+    pub subject: String,
+    pub body: String,
+    pub written_at: u64,
+    // First time the heir opened it
+    pub read_at: Option<u64>,
+}
+
+impl Storable for Letter {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(migrations::encode(self))
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        migrations::encode(&self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        migrations::decode(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// What an heir sees of a letter before opening it
+#[derive(Clone, Serialize, Deserialize, CandidType, PartialEq, Debug)]
+pub struct LetterNotice {
+    pub owner: Principal,
+    pub written_at: u64,
+    pub read_at: Option<u64>,
+}
+
+// One letter per owner and heir
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct LetterKey {
+    pub owner: Principal,
+    pub heir: Principal,
+}
+
+impl Storable for LetterKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned((*self).into_bytes())
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        let owner = self.owner.as_slice();
+        let heir = self.heir.as_slice();
+        let mut bytes = Vec::with_capacity(2 + owner.len() + heir.len());
+        bytes.push(owner.len() as u8);
+        bytes.extend_from_slice(owner);
+        bytes.push(heir.len() as u8);
+        bytes.extend_from_slice(heir);
+        bytes
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let owner_len = bytes[0] as usize;
+        let heir_len = bytes[1 + owner_len] as usize;
+        let heir_start = 2 + owner_len;
+        LetterKey {
+            owner: Principal::from_slice(&bytes[1..1 + owner_len]),
+            heir: Principal::from_slice(&bytes[heir_start..heir_start + heir_len]),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 60,
+        is_fixed_size: false,
+    };
+}
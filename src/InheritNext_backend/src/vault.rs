@@ -1,22 +1,34 @@
-use candid::Principal;
-use ic_cdk::call::Call;
+use std::time::Duration;
+
+use candid::{Nat, Principal};
+use ic_cdk::{
+    call::Call,
+    management_canister::{canister_status, CanisterStatusArgs},
+};
 use icrc_ledger_types::{
-    icrc1::account::Account,
+    icrc1::account::{Account, Subaccount, DEFAULT_SUBACCOUNT},
     icrc2::allowance::{Allowance, AllowanceArgs},
 };
+use sha2::{Digest, Sha224};
 
 use crate::{
     helpers::{
-        is_vault_released, log_event, now, DEFAULT_GRACE_PERIOD, DEFAULT_HEARTBEAT_INTERVAL,
-        NANOS_PER_DAY,
+        check_is_anonymous, is_vault_released, log_event, now, validate_heir_assignments,
+        validate_remainder_policy, DEFAULT_GRACE_PERIOD, DEFAULT_HEARTBEAT_INTERVAL,
+        MAX_RECOVERY_PRINCIPALS, NANOS_PER_DAY, RECOVERY_REQUEST_TTL, SWITCH_CHECK_INTERVAL_SECS,
+    },
+    release,
+    storage::{self, get_vault, insert_vault, update_vault, vault_exists},
+    types::{
+        AccountBalanceArgs, AccountIdentifier, ApiError, DeadManSwitch, EventType, HeirAssignment,
+        HeirVisibility, InheritNextVerifyArgs, IsApprovedArg, RecoveryAction, RecoveryConfig,
+        RecoveryRequest, RemainderPolicy, Tokens, TransferFee, TransferFeeArg, Vault, VaultStatus,
     },
-    storage::{self, insert_vault, update_vault, vault_exists},
-    types::{DeadManSwitch, EventType, Vault, VaultStatus},
 };
 
-pub fn create_new_vault(caller: &Principal) -> Result<(), String> {
+pub fn create_new_vault(caller: &Principal) -> Result<(), ApiError> {
     if vault_exists(caller) {
-        return Err("Vault Already Exists".to_string());
+        return Err(ApiError::VaultAlreadyExists);
     }
 
     let cur_time = now();
@@ -34,6 +46,10 @@ pub fn create_new_vault(caller: &Principal) -> Result<(), String> {
             },
             recovery_config: None,
             next_asset_id: 0,
+            recovery_request: None,
+            heir_visibility: None,
+            remainder_policy: None,
+            escrow_subaccount: None,
         },
     );
 
@@ -44,28 +60,88 @@ pub fn configure_switch(
     caller: &Principal,
     heartbeat_intervals_d: u32,
     grace_period_d: u32,
-) -> Result<(), String> {
-    if heartbeat_intervals_d == 0 || grace_period_d == 0 {
-        return Err("intervals must be greater thna zero".to_string());
+) -> Result<(), ApiError> {
+    if heartbeat_intervals_d == 0 {
+        return Err(ApiError::validation(
+            "heartbeat_interval_d",
+            "must be greater than zero",
+        ));
+    }
+    if grace_period_d == 0 {
+        return Err(ApiError::validation(
+            "grace_period_d",
+            "must be greater than zero",
+        ));
     }
 
-    storage::update_vault(caller, |vault| {
+    let new_heartbeat_interval = (heartbeat_intervals_d as u64) * NANOS_PER_DAY;
+    let new_grace_period = (grace_period_d as u64) * NANOS_PER_DAY;
+
+    let (old_heartbeat_interval, old_grace_period) = storage::update_vault(caller, |vault| {
         if vault.status == VaultStatus::Released {
-            return Err("Cannot modify released vault".to_string());
+            return Err(ApiError::VaultReleased);
         }
 
-        vault.dms.heartbeat_interval = (heartbeat_intervals_d as u64) * NANOS_PER_DAY;
-        vault.dms.grace_period = (grace_period_d as u64) * NANOS_PER_DAY;
+        let old = (vault.dms.heartbeat_interval, vault.dms.grace_period);
+        vault.dms.heartbeat_interval = new_heartbeat_interval;
+        vault.dms.grace_period = new_grace_period;
+
+        Ok(old)
+    })?;
 
+    log_event(
+        EventType::DmsConfigured {
+            old_heartbeat_interval,
+            new_heartbeat_interval,
+            old_grace_period,
+            new_grace_period,
+        },
+        caller,
+    );
+    Ok(())
+}
+
+pub fn set_heir_visibility(caller: &Principal, visibility: HeirVisibility) -> Result<(), ApiError> {
+    update_vault(caller, |vault| {
+        if is_vault_released(vault) {
+            return Err(ApiError::VaultReleased);
+        }
+
+        vault.heir_visibility = Some(visibility);
+        Ok(())
+    })
+}
+
+pub fn set_remainder_policy(caller: &Principal, policy: RemainderPolicy) -> Result<(), ApiError> {
+    validate_remainder_policy(caller, &policy)?;
+
+    // Assets added under a looser policy must still make sense under the new one
+    for asset in storage::list_user_assets(caller) {
+        validate_heir_assignments(caller, &asset.asset_type, &asset.heir_assingment, &policy)
+            .map_err(|e| match e {
+                ApiError::ValidationFailed { field, reason } => ApiError::validation(
+                    "remainder_policy",
+                    format!("asset {} conflicts: {}: {}", asset.id, field, reason),
+                ),
+                other => other,
+            })?;
+    }
+
+    update_vault(caller, |vault| {
+        if is_vault_released(vault) {
+            return Err(ApiError::VaultReleased);
+        }
+
+        vault.remainder_policy = Some(policy);
         Ok(())
     })
 }
 
-pub fn send_heartbeat(caller: &Principal) -> Result<(), String> {
+pub fn send_heartbeat(caller: &Principal) -> Result<(), ApiError> {
     // println!("heartbeat SEND ===========================");
     update_vault(caller, |vault| {
         if is_vault_released(vault) {
-            return Err("Vault Already Released".to_string());
+            return Err(ApiError::VaultReleased);
         }
         let cur_time = now();
         vault.dms.last_heartbeat = cur_time;
@@ -76,11 +152,341 @@ pub fn send_heartbeat(caller: &Principal) -> Result<(), String> {
     })
 }
 
+pub fn configure_recovery(
+    caller: &Principal,
+    recovery_principals: Vec<Principal>,
+    threshold: u32,
+) -> Result<(), ApiError> {
+    if recovery_principals.is_empty() {
+        return Err(ApiError::validation(
+            "recovery_principals",
+            "at least one recovery principal is required",
+        ));
+    }
+    if recovery_principals.len() > MAX_RECOVERY_PRINCIPALS {
+        return Err(ApiError::validation(
+            "recovery_principals",
+            format!(
+                "too many recovery principals (max {})",
+                MAX_RECOVERY_PRINCIPALS
+            ),
+        ));
+    }
+    if threshold == 0 || threshold as usize > recovery_principals.len() {
+        return Err(ApiError::validation(
+            "threshold",
+            "must be between 1 and the number of recovery principals",
+        ));
+    }
+    for (i, p) in recovery_principals.iter().enumerate() {
+        let field = format!("recovery_principals[{}]", i);
+        if check_is_anonymous(p) {
+            return Err(ApiError::validation(
+                field,
+                "anonymous principal cannot be a recovery principal",
+            ));
+        }
+        if p == caller {
+            return Err(ApiError::validation(
+                field,
+                "owner cannot be their own recovery principal",
+            ));
+        }
+        if recovery_principals[..i].contains(p) {
+            return Err(ApiError::validation(
+                field,
+                format!("duplicate recovery principal {}", p.to_text()),
+            ));
+        }
+    }
+
+    update_vault(caller, |vault| {
+        if is_vault_released(vault) {
+            return Err(ApiError::VaultReleased);
+        }
+
+        vault.recovery_config = Some(RecoveryConfig {
+            recovery_principals,
+            threshold,
+        });
+        // Approvals given under the old config no longer mean anything
+        vault.recovery_request = None;
+
+        Ok(())
+    })
+}
+
+fn ensure_recovery_principal(vault: &Vault, caller: &Principal) -> Result<u32, ApiError> {
+    let config = vault
+        .recovery_config
+        .as_ref()
+        .ok_or(ApiError::RecoveryNotConfigured)?;
+
+    if !config.recovery_principals.contains(caller) {
+        return Err(ApiError::Unauthorized);
+    }
+
+    Ok(config.threshold)
+}
+
+fn ensure_action_allowed(vault: &Vault, action: &RecoveryAction) -> Result<(), ApiError> {
+    match action {
+        RecoveryAction::TransferOwnership { new_owner } => {
+            if is_vault_released(vault) {
+                return Err(ApiError::VaultReleased);
+            }
+            if check_is_anonymous(new_owner) || *new_owner == vault.owner {
+                return Err(ApiError::validation(
+                    "new_owner",
+                    "must be a different, non-anonymous principal",
+                ));
+            }
+        }
+        RecoveryAction::CancelPending => {
+            if vault.status != VaultStatus::Pending {
+                return Err(ApiError::VaultNotPending);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Has to run outside `update_vault`, which keeps the vault map borrowed
+fn ensure_new_owner_free(action: &RecoveryAction) -> Result<(), ApiError> {
+    if let RecoveryAction::TransferOwnership { new_owner } = action {
+        if vault_exists(new_owner) {
+            return Err(ApiError::VaultAlreadyExists);
+        }
+    }
+    Ok(())
+}
+
+pub fn initiate_recovery(
+    caller: &Principal,
+    owner: &Principal,
+    action: RecoveryAction,
+) -> Result<(), ApiError> {
+    ensure_new_owner_free(&action)?;
+
+    update_vault(owner, |vault| {
+        ensure_recovery_principal(vault, caller)?;
+        ensure_action_allowed(vault, &action)?;
+
+        let cur_time = now();
+        if let Some(request) = &vault.recovery_request {
+            if request.expires_at > cur_time {
+                return Err(ApiError::RecoveryRequestOpen);
+            }
+        }
+
+        vault.recovery_request = Some(RecoveryRequest {
+            action: action.clone(),
+            initiated_by: *caller,
+            approvals: vec![*caller],
+            created_at: cur_time,
+            expires_at: cur_time + RECOVERY_REQUEST_TTL,
+        });
+
+        Ok(())
+    })?;
+
+    log_event(
+        EventType::RecoveryInitiated {
+            action,
+            initiated_by: *caller,
+        },
+        owner,
+    );
+    Ok(())
+}
+
+pub fn approve_recovery(caller: &Principal, owner: &Principal) -> Result<(), ApiError> {
+    let approvals = update_vault(owner, |vault| {
+        ensure_recovery_principal(vault, caller)?;
+
+        let request = vault
+            .recovery_request
+            .as_mut()
+            .ok_or(ApiError::RecoveryRequestNotFound)?;
+        if request.expires_at <= now() {
+            return Err(ApiError::RecoveryRequestExpired);
+        }
+        if request.approvals.contains(caller) {
+            return Err(ApiError::AlreadyApproved);
+        }
+
+        request.approvals.push(*caller);
+        Ok(request.approvals.len())
+    })?;
+
+    log_event(
+        EventType::RecoveryApproved {
+            approver: *caller,
+            approvals: approvals as u32,
+        },
+        owner,
+    );
+    Ok(())
+}
+
+// The owner is clearly not lost if they can call this, so they can always veto
+pub fn cancel_recovery(caller: &Principal) -> Result<(), ApiError> {
+    update_vault(caller, |vault| {
+        if vault.recovery_request.take().is_none() {
+            return Err(ApiError::RecoveryRequestNotFound);
+        }
+        Ok(())
+    })?;
+
+    log_event(EventType::RecoveryCancelled, caller);
+    Ok(())
+}
+
+pub fn execute_recovery(caller: &Principal, owner: &Principal) -> Result<(), ApiError> {
+    if let Some(request) = get_vault(owner).and_then(|vault| vault.recovery_request) {
+        ensure_new_owner_free(&request.action)?;
+    }
+
+    let action = update_vault(owner, |vault| {
+        let threshold = ensure_recovery_principal(vault, caller)?;
+
+        let request = vault
+            .recovery_request
+            .take()
+            .ok_or(ApiError::RecoveryRequestNotFound)?;
+        if request.expires_at <= now() {
+            return Err(ApiError::RecoveryRequestExpired);
+        }
+        if (request.approvals.len() as u32) < threshold {
+            return Err(ApiError::ThresholdNotMet {
+                approvals: request.approvals.len() as u32,
+                threshold,
+            });
+        }
+        ensure_action_allowed(vault, &request.action)?;
+
+        if request.action == RecoveryAction::CancelPending {
+            vault.status = VaultStatus::Active;
+            vault.dms.last_heartbeat = now();
+            vault.dms.pending_since = None;
+        }
+
+        Ok(request.action)
+    })?;
+
+    if let RecoveryAction::TransferOwnership { new_owner } = &action {
+        storage::transfer_vault_ownership(owner, new_owner)?;
+    }
+
+    let new_owner = match &action {
+        RecoveryAction::TransferOwnership { new_owner } => Some(*new_owner),
+        RecoveryAction::CancelPending => None,
+    };
+    log_event(
+        EventType::RecoveryExecuted {
+            action,
+            executed_by: *caller,
+        },
+        owner,
+    );
+    if let Some(new_owner) = new_owner {
+        log_event(
+            EventType::VaultRecovered {
+                previous_owner: *owner,
+            },
+            &new_owner,
+        );
+    }
+    Ok(())
+}
+
+// Timers do not survive upgrades, so this has to be called from both init and post_upgrade
+pub fn start_switch_timer() {
+    ic_cdk_timers::set_timer_interval_serial(
+        Duration::from_secs(SWITCH_CHECK_INTERVAL_SECS),
+        async || process_switches(),
+    );
+}
+
+pub fn process_switches() {
+    let cur_time = now();
+    let mut released = Vec::new();
+
+    for owner in storage::list_vault_owners() {
+        let Some(mut vault) = get_vault(&owner) else {
+            continue;
+        };
+
+        let Some(new_status) = advance_switch(&mut vault, cur_time) else {
+            continue;
+        };
+
+        let dms = vault.dms.clone();
+        insert_vault(&owner, vault);
+
+        match new_status {
+            VaultStatus::Pending => log_event(
+                EventType::SwitchPending {
+                    last_heartbeat: dms.last_heartbeat,
+                },
+                &owner,
+            ),
+            VaultStatus::Released => {
+                log_event(
+                    EventType::VaultReleased {
+                        pending_since: dms.pending_since.unwrap_or(dms.last_heartbeat),
+                    },
+                    &owner,
+                );
+                released.push(owner);
+            }
+            _ => {}
+        }
+    }
+
+    for owner in released {
+        release::enqueue_vault_release(&owner);
+    }
+}
+
+// Moves the vault at most one step per sweep so the owner always gets the full grace period
+// after going Pending, even if the canister was not ticking for a while
+fn advance_switch(vault: &mut Vault, cur_time: u64) -> Option<VaultStatus> {
+    match vault.status {
+        VaultStatus::Active => {
+            let deadline = vault
+                .dms
+                .last_heartbeat
+                .saturating_add(vault.dms.heartbeat_interval);
+            if cur_time < deadline {
+                return None;
+            }
+            vault.status = VaultStatus::Pending;
+            vault.dms.pending_since = Some(cur_time);
+            Some(VaultStatus::Pending)
+        }
+        VaultStatus::Pending => {
+            let pending_since = vault.dms.pending_since.unwrap_or_else(|| {
+                vault
+                    .dms
+                    .last_heartbeat
+                    .saturating_add(vault.dms.heartbeat_interval)
+            });
+            if cur_time < pending_since.saturating_add(vault.dms.grace_period) {
+                return None;
+            }
+            vault.status = VaultStatus::Released;
+            Some(VaultStatus::Released)
+        }
+        VaultStatus::NotCreated | VaultStatus::Released => None,
+    }
+}
+
 pub async fn verify_icrc2_allowance(
     caller: &Principal,
     ledger_canister: &Principal,
     amount: u64,
-) -> Result<(), String> {
+) -> Result<(), ApiError> {
     let backend_canister = ic_cdk::api::canister_self();
     let allowance_args = AllowanceArgs {
         account: Account {
@@ -93,48 +499,350 @@ pub async fn verify_icrc2_allowance(
         },
     };
 
-    let response = Call::unbounded_wait(*ledger_canister, "icrc2_allowance")
+    let ledger_error = |reason: String| ApiError::LedgerCallFailed {
+        ledger: *ledger_canister,
+        reason,
+    };
+
+    let (allowance,): (Allowance,) = Call::unbounded_wait(*ledger_canister, "icrc2_allowance")
         .with_arg(allowance_args)
-        .await;
-
-    let check_result: Result<(Allowance,), _> = match response {
-        Ok(resp) => resp.candid_tuple().map_err(|e| {
-            (
-                ic_cdk::call::RejectCode::CanisterError,
-                format!("Failed to decode response: {:?}", e),
-            )
-        }),
-        Err(e) => Err((
-            ic_cdk::call::RejectCode::CanisterError,
-            format!("Call failed: {:?}", e),
-        )),
+        .await
+        .map_err(|e| ledger_error(format!("Call failed: {:?}", e)))?
+        .candid_tuple()
+        .map_err(|e| ledger_error(format!("Failed to decode response: {:?}", e)))?;
+
+    let req = candid::Nat::from(amount);
+    if allowance.allowance < req {
+        return Err(ApiError::InsufficientAllowance {
+            required: req,
+            current: allowance.allowance,
+        });
+    }
+
+    log_event(
+        EventType::AllowanceVerified {
+            ledger_canister: *ledger_canister,
+            required: amount,
+            allowance: allowance.allowance,
+        },
+        caller,
+    );
+    Ok(())
+}
+
+// Legacy ICP ledger accounts are addressed by a 32-byte account identifier rather than
+// an `Account`
+pub fn account_identifier(owner: &Principal, subaccount: &Subaccount) -> AccountIdentifier {
+    let mut hasher = Sha224::new();
+    hasher.update(b"\x0Aaccount-id");
+    hasher.update(owner.as_slice());
+    hasher.update(subaccount);
+    let hash: [u8; 28] = hasher.finalize().into();
+
+    let mut id = [0u8; 32];
+    id[..4].copy_from_slice(&crc32fast::hash(&hash).to_be_bytes());
+    id[4..].copy_from_slice(&hash);
+    id
+}
+
+// Accepts the 64-character hex form wallets show, and rejects anything whose checksum
+// doesn't match so a typo can't send an inheritance into the void
+pub fn parse_account_identifier(text: &str) -> Result<AccountIdentifier, String> {
+    let bytes = hex::decode(text).map_err(|e| format!("not valid hex: {}", e))?;
+    let id: AccountIdentifier = bytes
+        .try_into()
+        .map_err(|b: Vec<u8>| format!("must be 32 bytes, got {}", b.len()))?;
+
+    if id[..4] != crc32fast::hash(&id[4..]).to_be_bytes() {
+        return Err("checksum mismatch".to_string());
+    }
+    Ok(id)
+}
+
+// Where an ICPLedger payout to this heir goes: their own account identifier if the owner
+// set one, otherwise the default account of their principal
+pub fn heir_account_identifier(
+    heir: &Principal,
+    assignment: Option<&HeirAssignment>,
+) -> AccountIdentifier {
+    assignment
+        .and_then(|a| a.account_identifier.as_deref())
+        .and_then(|text| parse_account_identifier(text).ok())
+        .unwrap_or_else(|| account_identifier(heir, DEFAULT_SUBACCOUNT))
+}
+
+pub async fn icp_account_balance(
+    ledger_canister: &Principal,
+    account: AccountIdentifier,
+) -> Result<u64, ApiError> {
+    let ledger_error = |reason: String| ApiError::LedgerCallFailed {
+        ledger: *ledger_canister,
+        reason,
     };
 
-    match check_result {
-        Ok((allowance,)) => {
-            let req = candid::Nat::from(amount);
-            if allowance.allowance < req {
-                return Err(format!(
-                    "Insufficient allowance. Required: {}, Current: {}. Please approve the backend canister: dfx canister call {} icrc2_approve '(record{{spender=record{{owner=principal\\\"{}\\\";subaccount=null}};amount={}}})' ",
-                    req, allowance.allowance, ledger_canister.to_text(), backend_canister.to_text(), amount
-                ));
-            }
+    let balance: Tokens = Call::unbounded_wait(*ledger_canister, "account_balance")
+        .with_arg(AccountBalanceArgs { account })
+        .await
+        .map_err(|e| ledger_error(format!("Call failed: {:?}", e)))?
+        .candid()
+        .map_err(|e| ledger_error(format!("Failed to decode response: {:?}", e)))?;
 
-            log_event(
-                EventType::AssetUpdated,
-                caller,
-                format!(
-                    "ICRC-2 allowance verified: {} tokens approved on {}",
-                    allowance.allowance,
-                    ledger_canister.to_text()
-                ),
-            );
-            Ok(())
-        }
-        Err(e) => Err(format!(
-            "Could not verify allowance from ledger {}. Error: {:?}. Please ensure the ledger supports ICRC-2.",
-            ledger_canister.to_text(),
-            e
-        )),
+    Ok(balance.e8s)
+}
+
+pub async fn icp_transfer_fee(ledger_canister: &Principal) -> Result<u64, String> {
+    let fee: TransferFee = Call::unbounded_wait(*ledger_canister, "transfer_fee")
+        .with_arg(TransferFeeArg {})
+        .await
+        .map_err(|e| format!("transfer_fee call failed: {:?}", e))?
+        .candid()
+        .map_err(|e| format!("Failed to decode transfer_fee: {:?}", e))?;
+
+    Ok(fee.transfer_fee.e8s)
+}
+
+// Every token has to sit in the caller's default account and be approved for this
+// canister to move with ICRC-37
+pub async fn verify_icrc7_tokens(
+    caller: &Principal,
+    collection: &Principal,
+    token_ids: &[Nat],
+) -> Result<(), ApiError> {
+    let collection_error = |reason: String| ApiError::LedgerCallFailed {
+        ledger: *collection,
+        reason,
+    };
+
+    let owners: Vec<Option<Account>> = Call::unbounded_wait(*collection, "icrc7_owner_of")
+        .with_arg(token_ids.to_vec())
+        .await
+        .map_err(|e| collection_error(format!("Call failed: {:?}", e)))?
+        .candid()
+        .map_err(|e| collection_error(format!("Failed to decode response: {:?}", e)))?;
+
+    let caller_account = Account {
+        owner: *caller,
+        subaccount: None,
+    };
+    let not_owned: Vec<Nat> = token_ids
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| owners.get(*i).cloned().flatten() != Some(caller_account))
+        .map(|(_, token_id)| token_id.clone())
+        .collect();
+    if !not_owned.is_empty() {
+        return Err(ApiError::NftNotOwned {
+            token_ids: not_owned,
+        });
+    }
+
+    let approval_args: Vec<IsApprovedArg> = token_ids
+        .iter()
+        .map(|token_id| IsApprovedArg {
+            spender: Account {
+                owner: ic_cdk::api::canister_self(),
+                subaccount: None,
+            },
+            from_subaccount: None,
+            token_id: token_id.clone(),
+        })
+        .collect();
+
+    let approved: Vec<bool> = Call::unbounded_wait(*collection, "icrc37_is_approved")
+        .with_arg(approval_args)
+        .await
+        .map_err(|e| collection_error(format!("Call failed: {:?}", e)))?
+        .candid()
+        .map_err(|e| collection_error(format!("Failed to decode response: {:?}", e)))?;
+
+    let not_approved: Vec<Nat> = token_ids
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !approved.get(*i).copied().unwrap_or(false))
+        .map(|(_, token_id)| token_id.clone())
+        .collect();
+    if !not_approved.is_empty() {
+        return Err(ApiError::NftNotApproved {
+            token_ids: not_approved,
+        });
+    }
+
+    log_event(
+        EventType::NftApprovalVerified {
+            collection: *collection,
+            token_ids: token_ids.to_vec(),
+        },
+        caller,
+    );
+    Ok(())
+}
+
+// Both this canister and the owner have to control the canister already. Only a controller
+// can read its status, so a successful call proves the first.
+pub async fn verify_canister_control(
+    caller: &Principal,
+    canister_id: &Principal,
+) -> Result<(), ApiError> {
+    let status = canister_status(&CanisterStatusArgs {
+        canister_id: *canister_id,
+    })
+    .await
+    .map_err(|e| {
+        ApiError::validation(
+            "canister_id",
+            format!("this canister is not a controller: {:?}", e),
+        )
+    })?;
+
+    let controllers = status.settings.controllers;
+    if !controllers.contains(caller) {
+        return Err(ApiError::validation(
+            "canister_id",
+            "caller is not a controller",
+        ));
+    }
+
+    log_event(
+        EventType::ControlVerified {
+            canister_id: *canister_id,
+            controllers,
+        },
+        caller,
+    );
+    Ok(())
+}
+
+// Bounded so a canister that never answers can't hold the caller up
+pub async fn verify_callback(
+    caller: &Principal,
+    canister: &Principal,
+    method: &str,
+    payload: &[u8],
+) -> Result<(), ApiError> {
+    let canister_error = |reason: String| ApiError::LedgerCallFailed {
+        ledger: *canister,
+        reason,
+    };
+
+    let args = InheritNextVerifyArgs {
+        owner: *caller,
+        method: method.to_string(),
+        payload: payload.to_vec(),
+    };
+    let verdict: Result<(), String> = Call::bounded_wait(*canister, "inheritnext_verify")
+        .with_arg(args)
+        .await
+        .map_err(|e| canister_error(format!("Call failed: {:?}", e)))?
+        .candid()
+        .map_err(|e| canister_error(format!("Failed to decode response: {:?}", e)))?;
+    verdict.map_err(|reason| ApiError::CallbackRejected { reason })?;
+
+    log_event(
+        EventType::CallbackVerified {
+            canister: *canister,
+            method: method.to_string(),
+        },
+        caller,
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::DeadManSwitch;
+
+    const INTERVAL: u64 = 1_000;
+    const GRACE: u64 = 500;
+
+    fn vault(status: VaultStatus, pending_since: Option<u64>) -> Vault {
+        Vault {
+            owner: Principal::anonymous(),
+            created_at: 0,
+            status,
+            dms: DeadManSwitch {
+                last_heartbeat: 100,
+                heartbeat_interval: INTERVAL,
+                grace_period: GRACE,
+                pending_since,
+            },
+            recovery_config: None,
+            next_asset_id: 0,
+            recovery_request: None,
+            heir_visibility: None,
+            remainder_policy: None,
+            escrow_subaccount: None,
+        }
+    }
+
+    #[test]
+    fn active_vault_waits_for_heartbeat_deadline() {
+        let mut v = vault(VaultStatus::Active, None);
+        assert_eq!(advance_switch(&mut v, 100 + INTERVAL - 1), None);
+        assert_eq!(v.status, VaultStatus::Active);
+        assert_eq!(v.dms.pending_since, None);
+    }
+
+    #[test]
+    fn active_vault_goes_pending_at_deadline() {
+        let mut v = vault(VaultStatus::Active, None);
+        assert_eq!(
+            advance_switch(&mut v, 100 + INTERVAL),
+            Some(VaultStatus::Pending)
+        );
+        assert_eq!(v.status, VaultStatus::Pending);
+        assert_eq!(v.dms.pending_since, Some(100 + INTERVAL));
+    }
+
+    #[test]
+    fn overdue_vault_moves_one_step_per_sweep() {
+        let mut v = vault(VaultStatus::Active, None);
+        let late = 100 + INTERVAL + GRACE * 10;
+        assert_eq!(advance_switch(&mut v, late), Some(VaultStatus::Pending));
+        // The grace period starts when the sweep noticed, not at the missed deadline
+        assert_eq!(advance_switch(&mut v, late), None);
+        assert_eq!(
+            advance_switch(&mut v, late + GRACE),
+            Some(VaultStatus::Released)
+        );
+    }
+
+    #[test]
+    fn pending_vault_releases_after_grace_period() {
+        let mut v = vault(VaultStatus::Pending, Some(2_000));
+        assert_eq!(advance_switch(&mut v, 2_000 + GRACE - 1), None);
+        assert_eq!(v.status, VaultStatus::Pending);
+        assert_eq!(
+            advance_switch(&mut v, 2_000 + GRACE),
+            Some(VaultStatus::Released)
+        );
+        assert_eq!(v.status, VaultStatus::Released);
+    }
+
+    #[test]
+    fn pending_vault_without_timestamp_counts_from_deadline() {
+        let mut v = vault(VaultStatus::Pending, None);
+        let deadline = 100 + INTERVAL;
+        assert_eq!(advance_switch(&mut v, deadline + GRACE - 1), None);
+        assert_eq!(
+            advance_switch(&mut v, deadline + GRACE),
+            Some(VaultStatus::Released)
+        );
+    }
+
+    #[test]
+    fn released_and_missing_vaults_never_move() {
+        for status in [VaultStatus::Released, VaultStatus::NotCreated] {
+            let mut v = vault(status.clone(), None);
+            assert_eq!(advance_switch(&mut v, u64::MAX), None);
+            assert_eq!(v.status, status);
+        }
+    }
+
+    #[test]
+    fn huge_interval_does_not_overflow() {
+        let mut v = vault(VaultStatus::Active, None);
+        v.dms.heartbeat_interval = u64::MAX;
+        assert_eq!(advance_switch(&mut v, u64::MAX - 1), None);
     }
 }
@@ -32,7 +32,7 @@ import {
   VStack,
 } from "@chakra-ui/react";
 import { useEffect, useState } from "react";
-import { calculateDmsStatus } from "../icp-utils";
+import { calculateDmsStatus, describeApiError } from "../icp-utils";
 import {
   FaChevronDown,
   FaClock,
@@ -128,7 +128,7 @@ export const Dashboard = ({ profile, actor, onLogout }) => {
       if (res.Ok) {
         setVault(res.Ok);
       } else {
-        if (res.Err && res.Err.includes("No vault")) {
+        if (res.Err && "VaultNotFound" in res.Err) {
           setVault(null);
         } else {
           console.error("Failed to Load Vault", res.Err);
@@ -144,7 +144,7 @@ export const Dashboard = ({ profile, actor, onLogout }) => {
       setCreatingVault(true);
       const res = await actor.create_vault();
       if (res.Err) {
-        throw new Error(res.Err);
+        throw new Error(describeApiError(res.Err));
       }
       toast({
         title: "Vault Activated",
@@ -172,7 +172,7 @@ export const Dashboard = ({ profile, actor, onLogout }) => {
       setCheckingIn(true);
       const res = await actor.heartbeat();
       if (res.Err) {
-        throw new Error(res.Err);
+        throw new Error(describeApiError(res.Err));
       }
 
       toast({
@@ -57,3 +57,13 @@ export const calculateDmsStatus = (vault) => {
     isOverdue: diff < 0,
   };
 };
+
+// Backend errors are Candid variants, e.g. { VaultNotFound: null } or
+// { ValidationFailed: { field, reason } }
+export const describeApiError = (err) => {
+  const [kind, payload] = Object.entries(err)[0];
+  if (payload && payload.field !== undefined) {
+    return `${kind}: ${payload.field} ${payload.reason}`;
+  }
+  return kind;
+};